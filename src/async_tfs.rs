@@ -0,0 +1,285 @@
+use std::{
+    fs::File,
+    io::SeekFrom,
+    panic,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tokio::{runtime::Handle, task};
+
+use crate::{
+    BlockDevice, MountOptions, ReadDirEntry, Stat, TfsError, TfsFs, TfsFsFile, TfsResult,
+    BLOCK_SIZE,
+};
+
+// the same filesystem as TfsFs, run on tokio's blocking threads so the executor never waits on the
+// disk. between block reads and writes it's all quick in memory work, so each call goes over to a
+// blocking thread as a whole rather than block by block
+#[derive(Debug)]
+pub struct AsyncTfs<D: BlockDevice<BLOCK_SIZE> = File> {
+    tfs: Arc<Mutex<TfsFs<D>>>,
+}
+
+// everything needs to be owned to go over to another thread
+async fn run<D, T>(
+    tfs: &Arc<Mutex<TfsFs<D>>>,
+    f: impl FnOnce(&mut TfsFs<D>) -> TfsResult<T> + Send + 'static,
+) -> TfsResult<T>
+where
+    D: BlockDevice<BLOCK_SIZE> + Send + 'static,
+    T: Send + 'static,
+{
+    let tfs = Arc::clone(tfs);
+    match task::spawn_blocking(move || f(&mut tfs.lock().unwrap())).await {
+        Ok(result) => result,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
+
+impl AsyncTfs {
+    pub async fn mount(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, &MountOptions::new()).await
+    }
+
+    pub async fn mount_with(path: impl AsRef<Path>, options: &MountOptions) -> TfsResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let options = options.clone();
+        let tfs = task::spawn_blocking(move || TfsFs::mount_with(path, &options))
+            .await
+            .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))?;
+        Ok(Self {
+            tfs: Arc::new(Mutex::new(tfs)),
+        })
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE> + Send + 'static> AsyncTfs<D> {
+    pub async fn mount_on(device: D, options: &MountOptions) -> TfsResult<Self> {
+        let options = options.clone();
+        let tfs = task::spawn_blocking(move || TfsFs::mount_on(device, &options))
+            .await
+            .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))?;
+        Ok(Self {
+            tfs: Arc::new(Mutex::new(tfs)),
+        })
+    }
+
+    // dropping works too, but then the final sync happens on whichever thread drops it last
+    pub async fn unmount(self) -> TfsResult<()> {
+        run(&self.tfs, |tfs| tfs.unmount()).await
+    }
+
+    pub async fn open(&self, filename: impl AsRef<Path>) -> TfsResult<AsyncFile<D>> {
+        let filename = filename.as_ref().to_path_buf();
+        let file = run(&self.tfs, move |tfs| tfs.open(filename)).await?;
+        Ok(AsyncFile {
+            tfs: Arc::clone(&self.tfs),
+            file: Arc::new(Mutex::new(Some(file))),
+        })
+    }
+
+    pub async fn read(&self, filename: impl AsRef<Path>) -> TfsResult<Vec<u8>> {
+        let filename = filename.as_ref().to_path_buf();
+        run(&self.tfs, move |tfs| tfs.read_file(filename)).await
+    }
+
+    pub async fn write_file(&self, filename: impl AsRef<Path>, contents: &[u8]) -> TfsResult<()> {
+        let filename = filename.as_ref().to_path_buf();
+        let contents = contents.to_vec();
+        run(&self.tfs, move |tfs| tfs.write_file(filename, &contents)).await
+    }
+
+    pub async fn readdir(&self) -> TfsResult<Vec<ReadDirEntry>> {
+        run(&self.tfs, |tfs| Ok(tfs.readdir().collect())).await
+    }
+
+    pub async fn readdir_at(&self, path: impl AsRef<Path>) -> TfsResult<Vec<ReadDirEntry>> {
+        let path = path.as_ref().to_path_buf();
+        run(&self.tfs, move |tfs| Ok(tfs.readdir_at(path)?.collect())).await
+    }
+
+    pub async fn mkdir(&self, path: impl AsRef<Path>) -> TfsResult<()> {
+        let path = path.as_ref().to_path_buf();
+        run(&self.tfs, move |tfs| tfs.mkdir(path)).await
+    }
+
+    pub async fn remove(&self, filename: impl AsRef<Path>) -> TfsResult<()> {
+        let filename = filename.as_ref().to_path_buf();
+        run(&self.tfs, move |tfs| tfs.remove(filename)).await
+    }
+
+    pub async fn metadata(&self, filename: impl AsRef<Path>) -> TfsResult<Stat> {
+        let filename = filename.as_ref().to_path_buf();
+        run(&self.tfs, move |tfs| tfs.metadata(filename)).await
+    }
+
+    pub async fn sync(&self) -> TfsResult<()> {
+        run(&self.tfs, |tfs| tfs.sync()).await
+    }
+}
+
+// an open file on an AsyncTfs, which it keeps alive by itself
+#[derive(Debug)]
+pub struct AsyncFile<D: BlockDevice<BLOCK_SIZE> + Send + 'static = File> {
+    tfs: Arc<Mutex<TfsFs<D>>>,
+    // shared with the blocking thread, so a future dropped partway can't take the handle with it.
+    // only gone once the file is closed
+    file: Arc<Mutex<Option<TfsFsFile>>>,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE> + Send + 'static> AsyncFile<D> {
+    // the handle is used where it is, always after the filesystem so the locks go in one order
+    async fn run<T: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut TfsFs<D>, &mut TfsFsFile) -> TfsResult<T> + Send + 'static,
+    ) -> TfsResult<T> {
+        let file = Arc::clone(&self.file);
+        run(&self.tfs, move |tfs| {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            let file = file
+                .as_mut()
+                .ok_or_else(|| TfsError::FileNotFound(String::new()))?;
+            f(tfs, file)
+        })
+        .await
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> TfsResult<usize> {
+        let len = buf.len();
+        let (read, data) = self
+            .run(move |tfs, file| {
+                let mut data = vec![0; len];
+                let read = tfs.read(file, &mut data)?;
+                Ok((read, data))
+            })
+            .await?;
+        buf[..read].copy_from_slice(&data[..read]);
+        Ok(read)
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> TfsResult<usize> {
+        let buf = buf.to_vec();
+        self.run(move |tfs, file| tfs.write(file, &buf)).await
+    }
+
+    pub async fn seek(&mut self, pos: SeekFrom) -> TfsResult<u64> {
+        self.run(move |tfs, file| tfs.seek(file, pos)).await
+    }
+
+    pub async fn metadata(&mut self) -> TfsResult<Stat> {
+        self.run(|tfs, file| tfs.stat(file)).await
+    }
+
+    pub async fn sync(&mut self) -> TfsResult<()> {
+        self.run(|tfs, file| tfs.sync_file(file)).await
+    }
+
+    pub async fn close(self) -> TfsResult<()> {
+        let file = Arc::clone(&self.file);
+        run(&self.tfs, move |tfs| {
+            match file.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                Some(mut file) => tfs.close(&mut file),
+                None => Ok(()),
+            }
+        })
+        .await
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE> + Send + 'static> Drop for AsyncFile<D> {
+    fn drop(&mut self) {
+        // nothing gets flushed, but the file mustn't be left open and locked
+        let tfs = Arc::clone(&self.tfs);
+        let file = Arc::clone(&self.file);
+        let release = move || {
+            let tfs = tfs.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(file) = file.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                tfs.release(&file);
+            }
+        };
+        // the filesystem could be busy for a while, which an executor thread mustn't wait on
+        match Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(release)),
+            Err(_) => release(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{testing::mkfs_fresh, MemDisk, MkfsOptions, Tfs, DEFAULT_DISK_SIZE};
+
+    #[tokio::test]
+    async fn mount_works() {
+        const DISK_PATH: &str = "async-mount-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = AsyncTfs::mount(DISK_PATH).await.unwrap();
+            tfs.write_file("test.txt", b"Hello, World!").await.unwrap();
+            tfs.mkdir("dir").await.unwrap();
+            tfs.unmount().await.unwrap();
+        }
+        {
+            let tfs = AsyncTfs::mount(DISK_PATH).await.unwrap();
+            assert_eq!(tfs.read("test.txt").await.unwrap(), b"Hello, World!");
+            let names: Vec<_> = tfs
+                .readdir()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.filename)
+                .collect();
+            assert_eq!(names, ["test.txt", "dir"]);
+            tfs.unmount().await.unwrap();
+        }
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let tfs = AsyncTfs::mount_on(disk, &MountOptions::new())
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+        let mut file = tfs.open("file").await.unwrap();
+        assert_eq!(file.write(&data).await.unwrap(), data.len());
+        assert_eq!(file.metadata().await.unwrap().size, data.len() as u64);
+        file.seek(SeekFrom::Start(BLOCK_SIZE as u64)).await.unwrap();
+        let mut buf = [0; BLOCK_SIZE];
+        assert_eq!(file.read(&mut buf).await.unwrap(), BLOCK_SIZE);
+        assert_eq!(buf, data[BLOCK_SIZE..BLOCK_SIZE * 2]);
+        file.close().await.unwrap();
+        assert_eq!(tfs.read("file").await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn cancelled_call_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let tfs = AsyncTfs::mount_on(disk, &MountOptions::new())
+            .await
+            .unwrap();
+        let mut file = tfs.open("file").await.unwrap();
+        // given up on while it's still off on a blocking thread
+        tokio::select! {
+            biased;
+            _ = file.metadata() => {}
+            _ = async {} => {}
+        }
+        file.write(b"kept").await.unwrap();
+        assert_eq!(tfs.read("file").await.unwrap(), b"kept");
+        drop(file);
+        // let go of on a blocking thread too
+        while tfs.tfs.lock().unwrap().open_handles("file") != 0 {
+            task::yield_now().await;
+        }
+        tfs.remove("file").await.unwrap();
+    }
+}
@@ -1,27 +1,622 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{prelude::*, SeekFrom},
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, prelude::*, SeekFrom},
+    mem,
     path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 
+use crate::structures::crc32;
+
 #[derive(Debug, thiserror::Error)]
 pub enum DiskError {
     #[error("{0}")]
     IoError(#[from] std::io::Error),
     #[error("Disk Size is Invalid - disk size must be a multiple of {block_size}")]
     InvalidSize { block_size: usize },
+    #[error("Checksum mismatch in block {block}")]
+    ChecksumMismatch { block: usize },
+    #[error("Image is already in use")]
+    Busy,
+    #[error("Image doesn't exist")]
+    NotFound,
+    #[error("Image already exists")]
+    AlreadyExists,
+    #[error("Block {block} is past the end of the disk, which only has {max} blocks")]
+    OutOfRange { block: usize, max: usize },
+    #[error("Transaction of {blocks} blocks doesn't fit in a journal of {capacity}")]
+    TransactionTooLarge { blocks: usize, capacity: usize },
 }
 
 pub type DiskResult<T> = Result<T, DiskError>;
 
+// wherever the blocks actually live, Disk adds the caching, checksums and journal on top
+pub trait BlockDevice<const BLOCK_SIZE: usize> {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()>;
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()>;
+    fn block_count(&self) -> usize;
+    fn sync(&mut self) -> DiskResult<()>;
+
+    // a run of whole blocks from start, which devices that can should write in one go
+    fn write_blocks(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        for (i, block) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write_block(start + i, block.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
+    // hold at least blocks, with any that weren't there before zeroed
+    fn extend(&mut self, blocks: usize) -> DiskResult<()> {
+        let count = self.block_count();
+        if blocks > count {
+            self.write_blocks(count, &vec![0; (blocks - count) * BLOCK_SIZE])?;
+        }
+        Ok(())
+    }
+
+    // give back anything past blocks, for devices that can actually get smaller
+    fn truncate(&mut self, _blocks: usize) -> DiskResult<()> {
+        Ok(())
+    }
+}
+
+// positioned io, so no block depends on where the last one left the cursor
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+// seek_read and seek_write can stop short, so keep going until the whole block is through
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// anywhere else it's a seek then a read or write. only the Disk that owns the file ever touches
+// it, so nothing can move the cursor in between
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_all_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+// an image file, which grows as blocks past its end are written
+impl<const BLOCK_SIZE: usize> BlockDevice<BLOCK_SIZE> for File {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        Ok(read_exact_at(self, buf, (num * BLOCK_SIZE) as u64)?)
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        Ok(write_all_at(self, data, (num * BLOCK_SIZE) as u64)?)
+    }
+
+    fn write_blocks(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        Ok(write_all_at(self, data, (start * BLOCK_SIZE) as u64)?)
+    }
+
+    fn extend(&mut self, blocks: usize) -> DiskResult<()> {
+        let len = (blocks * BLOCK_SIZE) as u64;
+        if self.metadata()?.len() < len {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, blocks: usize) -> DiskResult<()> {
+        let len = (blocks * BLOCK_SIZE) as u64;
+        if self.metadata()?.len() > len {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.metadata()
+            .map_or(0, |metadata| metadata.len() as usize / BLOCK_SIZE)
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        Ok(self.sync_all()?)
+    }
+}
+
+// so a device can be lent out, e.g. formatted and then mounted
+impl<const BLOCK_SIZE: usize, D: BlockDevice<BLOCK_SIZE> + ?Sized> BlockDevice<BLOCK_SIZE>
+    for &mut D
+{
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        (**self).read_block(num, buf)
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        (**self).write_block(num, data)
+    }
+
+    fn write_blocks(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        (**self).write_blocks(start, data)
+    }
+
+    fn extend(&mut self, blocks: usize) -> DiskResult<()> {
+        (**self).extend(blocks)
+    }
+
+    fn truncate(&mut self, blocks: usize) -> DiskResult<()> {
+        (**self).truncate(blocks)
+    }
+
+    fn block_count(&self) -> usize {
+        (**self).block_count()
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        (**self).sync()
+    }
+}
+
+// anything that can be seeked around in, e.g. a Cursor or an image embedded in another file. the
+// refcell is only so blocks can be read through a shared reference, like with a File
+#[derive(Debug)]
+pub struct IoDevice<T> {
+    inner: RefCell<T>,
+}
+
+impl<T: Read + Write + Seek> IoDevice<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: RefCell::new(inner),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<const BLOCK_SIZE: usize, T: Read + Write + Seek> BlockDevice<BLOCK_SIZE> for IoDevice<T> {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let mut inner = self.inner.borrow_mut();
+        inner.seek(SeekFrom::Start((num * BLOCK_SIZE) as u64))?;
+        inner.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let inner = self.inner.get_mut();
+        inner.seek(SeekFrom::Start((num * BLOCK_SIZE) as u64))?;
+        inner.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        let inner = self.inner.get_mut();
+        inner.seek(SeekFrom::Start((start * BLOCK_SIZE) as u64))?;
+        inner.write_all(data)?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.inner
+            .borrow_mut()
+            .seek(SeekFrom::End(0))
+            .map_or(0, |len| len as usize / BLOCK_SIZE)
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        Ok(self.inner.get_mut().flush()?)
+    }
+}
+
+// an image borrowed from somewhere else, e.g. include_bytes!, that can only ever be read
+#[derive(Debug, Clone, Copy)]
+pub struct SliceDisk<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SliceDisk<'a> {
+    pub fn new<const BLOCK_SIZE: usize>(data: &'a [u8]) -> DiskResult<Self> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            });
+        }
+        Ok(Self { data })
+    }
+}
+
+impl<const BLOCK_SIZE: usize> BlockDevice<BLOCK_SIZE> for SliceDisk<'_> {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let block = self
+            .data
+            .get(num * BLOCK_SIZE..(num + 1) * BLOCK_SIZE)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn write_block(&mut self, _num: usize, _data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        Err(io::Error::from(io::ErrorKind::ReadOnlyFilesystem).into())
+    }
+
+    fn block_count(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        Ok(())
+    }
+}
+
+// the whole image mapped into memory, so reads and writes are just copies. the mapping is redone
+// whenever a write goes past the end of the file
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MmapDisk {
+    file: File,
+    map: memmap2::MmapMut,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapDisk {
+    // the image has to be there already, but it's grown to size if it's smaller
+    pub fn open(path: impl AsRef<Path>, size: usize) -> DiskResult<Self> {
+        let file = open_image(path, OpenOptions::new().read(true).write(true))?;
+        lock(&file, true)?;
+        if (file.metadata()?.len() as usize) < size {
+            file.set_len(size as u64)?;
+        }
+        let map = Self::map(&file)?;
+        Ok(Self { file, map })
+    }
+
+    fn map(file: &File) -> DiskResult<memmap2::MmapMut> {
+        // safe as long as nothing else changes the length of the image while it's mounted, the
+        // same thing every other backend already assumes. platforms without mmap error here
+        Ok(unsafe { memmap2::MmapMut::map_mut(file)? })
+    }
+
+    fn grow(&mut self, len: usize) -> DiskResult<()> {
+        self.map.flush()?;
+        self.file.set_len(len as u64)?;
+        self.map = Self::map(&self.file)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<const BLOCK_SIZE: usize> BlockDevice<BLOCK_SIZE> for MmapDisk {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let block = self
+            .map
+            .get(num * BLOCK_SIZE..(num + 1) * BLOCK_SIZE)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let end = (num + 1) * BLOCK_SIZE;
+        if end > self.map.len() {
+            self.grow(end)?;
+        }
+        self.map[num * BLOCK_SIZE..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn extend(&mut self, blocks: usize) -> DiskResult<()> {
+        if blocks * BLOCK_SIZE > self.map.len() {
+            self.grow(blocks * BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, blocks: usize) -> DiskResult<()> {
+        if blocks * BLOCK_SIZE < self.map.len() {
+            self.grow(blocks * BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.map.len() / BLOCK_SIZE
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        Ok(self.map.flush()?)
+    }
+}
+
+// blocks kept in memory, for filesystems that don't need to outlive the process
+#[derive(Debug, Default, Clone)]
+pub struct MemDisk<const BLOCK_SIZE: usize> {
+    blocks: Vec<[u8; BLOCK_SIZE]>,
+}
+
+impl<const BLOCK_SIZE: usize> MemDisk<BLOCK_SIZE> {
+    pub fn new() -> Self {
+        Self { blocks: Vec::new() }
+    }
+
+    // e.g. an image read in from a file
+    pub fn from_bytes(bytes: &[u8]) -> DiskResult<Self> {
+        if !bytes.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            });
+        }
+        let blocks = bytes
+            .chunks_exact(BLOCK_SIZE)
+            .map(|block| block.try_into().unwrap())
+            .collect();
+        Ok(Self { blocks })
+    }
+
+    // the image just as it would be in a file
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.blocks.concat()
+    }
+}
+
+impl<const BLOCK_SIZE: usize> BlockDevice<BLOCK_SIZE> for MemDisk<BLOCK_SIZE> {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        // the same as reading off the end of a file
+        let block = self
+            .blocks
+            .get(num)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        if num >= self.blocks.len() {
+            self.blocks.resize(num + 1, [0; BLOCK_SIZE]);
+        }
+        self.blocks[num] = *data;
+        Ok(())
+    }
+
+    fn truncate(&mut self, blocks: usize) -> DiskResult<()> {
+        self.blocks.truncate(blocks);
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BlockCache<const BLOCK_SIZE: usize> {
+    capacity: usize,
+    blocks: HashMap<usize, [u8; BLOCK_SIZE]>,
+    // least recently used block first
+    order: VecDeque<usize>,
+    hits: usize,
+    misses: usize,
+}
+
+impl<const BLOCK_SIZE: usize> BlockCache<BLOCK_SIZE> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn touch(&mut self, num: usize) {
+        if let Some(index) = self.order.iter().position(|&block| block == num) {
+            self.order.remove(index);
+        }
+        self.order.push_back(num);
+    }
+
+    fn get(&mut self, num: usize) -> Option<[u8; BLOCK_SIZE]> {
+        match self.blocks.get(&num).copied() {
+            Some(block) => {
+                self.hits += 1;
+                self.touch(num);
+                Some(block)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, num: usize, data: [u8; BLOCK_SIZE]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.blocks.contains_key(&num) && self.blocks.len() >= self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.blocks.remove(&evicted);
+            }
+        }
+        self.blocks.insert(num, data);
+        self.touch(num);
+    }
+
+    fn truncate(&mut self, blocks: usize) {
+        self.blocks.retain(|&num, _| num < blocks);
+        self.order.retain(|&num| num < blocks);
+    }
+}
+
+// a crc32 for every block, kept in a table of blocks on the disk itself
+#[derive(Debug)]
+struct Checksums {
+    start: usize,
+    len: usize,
+    sums: Vec<u32>,
+}
+
+impl Checksums {
+    const ENTRY_LEN: usize = mem::size_of::<u32>();
+
+    // the table doesn't cover itself
+    fn covers(&self, num: usize) -> bool {
+        num < self.sums.len() && !(self.start..self.start + self.len).contains(&num)
+    }
+
+    // the table block holding the entry for num, along with its contents
+    fn table_block<const BLOCK_SIZE: usize>(&self, num: usize) -> (usize, [u8; BLOCK_SIZE]) {
+        let per_block = BLOCK_SIZE / Self::ENTRY_LEN;
+        let index = num / per_block;
+        let mut data = [0; BLOCK_SIZE];
+        let sums = self.sums.iter().skip(index * per_block).take(per_block);
+        for (entry, sum) in data.chunks_exact_mut(Self::ENTRY_LEN).zip(sums) {
+            entry.copy_from_slice(&sum.to_le_bytes());
+        }
+        (self.start + index, data)
+    }
+}
+
+// a log of blocks written ahead of where they belong, so a set of writes lands all together or not
+// at all. the first block is a header listing where each of the rest of them goes
+#[derive(Debug)]
+struct Journal<const BLOCK_SIZE: usize> {
+    start: usize,
+    len: usize,
+    // writes held back until commit while a transaction is open
+    staged: Option<BTreeMap<usize, [u8; BLOCK_SIZE]>>,
+}
+
+impl<const BLOCK_SIZE: usize> Journal<BLOCK_SIZE> {
+    // committed flag, block count and a crc32 of the targets and blocks, then the targets
+    const HEADER_LEN: usize = 1 + 2 + 4;
+    const TARGET_LEN: usize = mem::size_of::<u16>();
+
+    fn capacity(&self) -> usize {
+        (self.len - 1).min((BLOCK_SIZE - Self::HEADER_LEN) / Self::TARGET_LEN)
+    }
+
+    fn header(blocks: &[(usize, [u8; BLOCK_SIZE])]) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[0] = 1;
+        header[1..3].copy_from_slice(&(blocks.len() as u16).to_le_bytes());
+        let targets =
+            &mut header[Self::HEADER_LEN..Self::HEADER_LEN + blocks.len() * Self::TARGET_LEN];
+        for ((num, _), target) in blocks
+            .iter()
+            .zip(targets.chunks_exact_mut(Self::TARGET_LEN))
+        {
+            target.copy_from_slice(&(*num as u16).to_le_bytes());
+        }
+        let mut summed = targets.to_vec();
+        for (_, data) in blocks {
+            summed.extend_from_slice(data);
+        }
+        header[3..7].copy_from_slice(&crc32(&summed).to_le_bytes());
+        header
+    }
+}
+
+// only what actually reaches the device, anything served from the cache or journal isn't counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // calls made to write to the device, a run of blocks written together only counts once
+    pub write_calls: u64,
+    pub syncs: u64,
+}
+
 #[derive(Debug)]
-pub struct Disk<const BLOCK_SIZE: usize> {
-    backing_file: File,
+pub struct Disk<const BLOCK_SIZE: usize, D: BlockDevice<BLOCK_SIZE> = File> {
+    device: D,
+    // handy for checking io patterns. it and the cache are behind locks so reads only need &self
+    stats: Mutex<DiskStats>,
+    cache: Option<Mutex<BlockCache<BLOCK_SIZE>>>,
+    // blocks written but not yet flushed when in write back mode
+    dirty: Option<BTreeMap<usize, [u8; BLOCK_SIZE]>>,
+    checksums: Option<Checksums>,
+    journal: Option<Journal<BLOCK_SIZE>>,
+    read_only: bool,
+    // anything from here on is out of range, even if the device would go along with it
+    blocks: usize,
+}
+
+// held for as long as the file stays open, so two mounts can't both cache the metadata and write
+// over each other. it's flock or LockFileEx underneath
+fn lock(file: &File, exclusive: bool) -> DiskResult<()> {
+    let result = if exclusive {
+        file.try_lock()
+    } else {
+        file.try_lock_shared()
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(DiskError::Busy),
+        // anywhere without file locks just goes without
+        Err(TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => Ok(()),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+// a missing image gets its own error rather than whatever the os calls it
+fn open_image(path: impl AsRef<Path>, options: &OpenOptions) -> DiskResult<File> {
+    options.open(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => DiskError::NotFound,
+        _ => err.into(),
+    })
 }
 
 impl<const BLOCK_SIZE: usize> Disk<BLOCK_SIZE> {
-    pub fn open(path: impl AsRef<Path>, size: usize) -> DiskResult<Disk<BLOCK_SIZE>> {
-        if size % BLOCK_SIZE != 0 {
+    // a new image of size bytes, replacing anything already at path
+    pub fn create(path: impl AsRef<Path>, size: usize) -> DiskResult<Disk<BLOCK_SIZE>> {
+        if size == 0 || !size.is_multiple_of(BLOCK_SIZE) {
             return Err(DiskError::InvalidSize {
                 block_size: BLOCK_SIZE,
             });
@@ -31,29 +626,516 @@ impl<const BLOCK_SIZE: usize> Disk<BLOCK_SIZE> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)?;
-        Ok(Disk { backing_file })
+        // only emptied once it's known nothing else has it open
+        lock(&backing_file, true)?;
+        backing_file.set_len(0)?;
+        backing_file.set_len(size as u64)?;
+        Ok(Disk::new(backing_file, false))
     }
 
-    pub fn read_block(&mut self, num: usize) -> DiskResult<[u8; BLOCK_SIZE]> {
-        let mut block = [0; BLOCK_SIZE];
-        self.backing_file
-            .seek(SeekFrom::Start((num * BLOCK_SIZE) as u64))?;
-        self.backing_file.read_exact(&mut block)?;
+    // like create, but only if there's nothing worth keeping at path already. an empty file is as
+    // good as a missing one
+    pub fn create_new(path: impl AsRef<Path>, size: usize) -> DiskResult<Disk<BLOCK_SIZE>> {
+        if size == 0 || !size.is_multiple_of(BLOCK_SIZE) {
+            return Err(DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            });
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let (backing_file, existed) = match options.clone().create_new(true).open(&path) {
+            Ok(file) => (file, false),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (options.open(&path)?, true),
+            Err(err) => return Err(err.into()),
+        };
+        lock(&backing_file, true)?;
+        if existed && backing_file.metadata()?.len() != 0 {
+            return Err(DiskError::AlreadyExists);
+        }
+        backing_file.set_len(size as u64)?;
+        Ok(Disk::new(backing_file, false))
+    }
+
+    // an image that's already there, as big as the file is
+    pub fn open_existing(path: impl AsRef<Path>) -> DiskResult<Disk<BLOCK_SIZE>> {
+        let backing_file = open_image(path, OpenOptions::new().read(true).write(true))?;
+        lock(&backing_file, true)?;
+        Ok(Disk::new(backing_file, false))
+    }
+
+    // any writes will fail, so the image is guaranteed to be left alone
+    pub fn open_read_only(path: impl AsRef<Path>) -> DiskResult<Disk<BLOCK_SIZE>> {
+        let backing_file = open_image(path, OpenOptions::new().read(true))?;
+        lock(&backing_file, false)?;
+        Ok(Disk::new(backing_file, true))
+    }
+
+    // read only without taking any lock, for looking at an image something else has open
+    pub fn open_shared(path: impl AsRef<Path>) -> DiskResult<Disk<BLOCK_SIZE>> {
+        let backing_file = open_image(path, OpenOptions::new().read(true))?;
+        Ok(Disk::new(backing_file, true))
+    }
+
+    // let go of the image lock the way a dying process would, with nothing else written
+    #[cfg(test)]
+    pub fn release_lock(&self) {
+        self.device.unlock().unwrap();
+    }
+
+    // open_existing, keeping up to cache_blocks recently used blocks in memory. writes still go
+    // straight through
+    pub fn with_cache(path: impl AsRef<Path>, cache_blocks: usize) -> DiskResult<Disk<BLOCK_SIZE>> {
+        let mut disk = Self::open_existing(path)?;
+        disk.set_cache_blocks(cache_blocks);
+        Ok(disk)
+    }
+}
+
+impl<const BLOCK_SIZE: usize, D: BlockDevice<BLOCK_SIZE>> Disk<BLOCK_SIZE, D> {
+    // read_only only decides what the journal does, it's up to the device to refuse writes
+    pub fn new(device: D, read_only: bool) -> Self {
+        Disk {
+            stats: Mutex::default(),
+            cache: None,
+            dirty: None,
+            checksums: None,
+            journal: None,
+            read_only,
+            blocks: device.block_count(),
+            device,
+        }
+    }
+
+    // replaces any existing cache, dropping what it held
+    pub fn set_cache_blocks(&mut self, cache_blocks: usize) {
+        self.cache = Some(Mutex::new(BlockCache::new(cache_blocks)));
+    }
+
+    fn cache(&self) -> Option<MutexGuard<'_, BlockCache<BLOCK_SIZE>>> {
+        let cache = self.cache.as_ref()?;
+        Some(cache.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn cache_mut(&mut self) -> Option<&mut BlockCache<BLOCK_SIZE>> {
+        let cache = self.cache.as_mut()?;
+        Some(cache.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn stats_mut(&mut self) -> &mut DiskStats {
+        self.stats.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // number of blocks that can be read and written, which starts out as however many the device
+    // holds
+    pub fn block_count(&self) -> DiskResult<usize> {
+        Ok(self.blocks)
+    }
+
+    // a filesystem can be smaller than the image it's in, or bigger than a new image is so far
+    pub fn set_block_count(&mut self, blocks: usize) {
+        self.blocks = blocks;
+    }
+
+    // more room on the end, which the device is made to actually hold
+    pub fn extend(&mut self, blocks: usize) -> DiskResult<()> {
+        self.device.extend(blocks)?;
+        self.blocks = self.blocks.max(blocks);
+        Ok(())
+    }
+
+    // the opposite of extend, anything past blocks is gone afterwards, cached or not
+    pub fn truncate(&mut self, blocks: usize) -> DiskResult<()> {
+        self.flush()?;
+        self.device.truncate(blocks)?;
+        if let Some(cache) = self.cache_mut() {
+            cache.truncate(blocks);
+        }
+        self.blocks = self.blocks.min(blocks);
+        Ok(())
+    }
+
+    fn check_range(&self, start: usize, blocks: usize) -> DiskResult<()> {
+        if start + blocks > self.blocks {
+            return Err(DiskError::OutOfRange {
+                block: start.max(self.blocks),
+                max: self.blocks,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn reads(&self) -> usize {
+        self.stats().blocks_read as usize
+    }
+
+    pub fn writes(&self) -> usize {
+        self.stats().blocks_written as usize
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        *self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn reset_stats(&mut self) {
+        *self.stats_mut() = DiskStats::default();
+    }
+
+    // in write back mode writes are held in memory until flush, turning it off flushes them
+    pub fn set_write_back(&mut self, write_back: bool) -> DiskResult<()> {
+        if write_back {
+            self.dirty.get_or_insert_with(BTreeMap::new);
+        } else {
+            self.flush()?;
+            self.dirty = None;
+        }
+        Ok(())
+    }
+
+    // write out any buffered blocks in ascending order
+    pub fn flush(&mut self) -> DiskResult<()> {
+        let Some(dirty) = self.dirty.as_mut() else {
+            return Ok(());
+        };
+        let mut blocks = mem::take(dirty).into_iter();
+        while let Some((num, data)) = blocks.next() {
+            if let Err(err) = self.write_physical(num, &data) {
+                // keep whatever didn't make it so a later flush can try again
+                let dirty = self.dirty.as_mut().unwrap();
+                dirty.insert(num, data);
+                dirty.extend(blocks);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // make sure everything written so far has actually reached the disk, not just the os
+    pub fn sync_all(&mut self) -> DiskResult<()> {
+        self.stats_mut().syncs += 1;
+        self.device.sync()
+    }
+
+    // how many table blocks it takes to checksum a disk of this many blocks
+    pub fn checksum_blocks(blocks: usize) -> usize {
+        blocks.div_ceil(BLOCK_SIZE / Checksums::ENTRY_LEN)
+    }
+
+    // start checksumming against the table at start, which has to have been set up already
+    pub fn enable_checksums(&mut self, start: usize, len: usize) -> DiskResult<()> {
+        let mut sums = Vec::with_capacity(len * BLOCK_SIZE / Checksums::ENTRY_LEN);
+        for num in start..start + len {
+            let block = self.read_block(num)?;
+            sums.extend(
+                block
+                    .chunks_exact(Checksums::ENTRY_LEN)
+                    .map(|entry| u32::from_le_bytes(entry.try_into().unwrap())),
+            );
+        }
+        self.checksums = Some(Checksums { start, len, sums });
+        Ok(())
+    }
+
+    // write out a fresh table at start for a disk that's all zeros, and start checksumming
+    pub fn format_checksums(&mut self, start: usize, len: usize) -> DiskResult<()> {
+        let zeros = crc32(&[0; BLOCK_SIZE]);
+        let checksums = Checksums {
+            start,
+            len,
+            sums: vec![zeros; len * BLOCK_SIZE / Checksums::ENTRY_LEN],
+        };
+        for index in 0..len {
+            let (num, data) = checksums.table_block(index * BLOCK_SIZE / Checksums::ENTRY_LEN);
+            self.write_unchecked(num, data)?;
+        }
+        self.checksums = Some(checksums);
+        Ok(())
+    }
+
+    // journal writes in the blocks from start, which have to be zeroed or left by an earlier
+    // journal. a transaction that was committed but never applied gets replayed, returning whether
+    // there was one. read only disks keep it in memory instead
+    pub fn enable_journal(&mut self, start: usize, len: usize) -> DiskResult<bool> {
+        let mut journal = Journal {
+            start,
+            len,
+            staged: None,
+        };
+        let header = self.read_physical(start)?;
+        let count = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let mut replayed = false;
+        if header[0] != 0 && count <= journal.capacity() {
+            let targets = &header[Journal::<BLOCK_SIZE>::HEADER_LEN..];
+            let mut blocks = Vec::with_capacity(count);
+            for (i, target) in targets
+                .chunks_exact(Journal::<BLOCK_SIZE>::TARGET_LEN)
+                .take(count)
+                .enumerate()
+            {
+                let num = u16::from_le_bytes([target[0], target[1]]) as usize;
+                blocks.push((num, self.read_physical(start + 1 + i)?));
+            }
+            // anything else is a commit that didn't finish, so none of it happened
+            if Journal::header(&blocks) == header {
+                if self.read_only {
+                    journal.staged = Some(blocks.into_iter().collect());
+                } else {
+                    self.apply(start, &blocks)?;
+                }
+                replayed = true;
+            }
+        }
+        self.journal = Some(journal);
+        Ok(replayed)
+    }
+
+    // hold writes back until commit, so they go through the journal together. does nothing
+    // without a journal
+    pub fn begin_transaction(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.staged.get_or_insert_with(BTreeMap::new);
+        }
+    }
+
+    // write everything since begin_transaction to the journal, then where it actually belongs
+    pub fn commit(&mut self) -> DiskResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let Some(journal) = self.journal.as_mut() else {
+            return Ok(());
+        };
+        let Some(staged) = journal.staged.take() else {
+            return Ok(());
+        };
+        let (start, capacity) = (journal.start, journal.capacity());
+        let blocks: Vec<_> = staged.into_iter().collect();
+        // splitting it up would make it several transactions, which a crash could land between
+        let result = if blocks.len() > capacity {
+            Err(DiskError::TransactionTooLarge {
+                blocks: blocks.len(),
+                capacity,
+            })
+        } else {
+            self.write_transaction(start, &blocks)
+        };
+        if result.is_err() {
+            // keep it staged so the next commit tries it all again
+            self.journal.as_mut().unwrap().staged = Some(blocks.into_iter().collect());
+        }
+        result
+    }
+
+    fn write_transaction(
+        &mut self,
+        start: usize,
+        blocks: &[(usize, [u8; BLOCK_SIZE])],
+    ) -> DiskResult<()> {
+        for (i, (_, data)) in blocks.iter().enumerate() {
+            self.write_physical(start + 1 + i, data)?;
+        }
+        self.sync_all()?;
+        self.write_physical(start, &Journal::header(blocks))?;
+        self.sync_all()?;
+        self.apply(start, blocks)
+    }
+
+    // write a committed transaction in place and mark the journal clean
+    fn apply(&mut self, start: usize, blocks: &[(usize, [u8; BLOCK_SIZE])]) -> DiskResult<()> {
+        for (num, data) in blocks {
+            if let Some(cache) = self.cache_mut() {
+                cache.insert(*num, *data);
+            }
+            // anything still buffered for it is older
+            if let Some(dirty) = self.dirty.as_mut() {
+                dirty.remove(num);
+            }
+            self.write_physical(*num, data)?;
+        }
+        self.sync_all()?;
+        self.write_physical(start, &[0; BLOCK_SIZE])
+    }
+
+    // copy every block as it reads right now, checksum table and all, to a new image at path
+    pub fn snapshot(&mut self, path: impl AsRef<Path>) -> DiskResult<Disk<BLOCK_SIZE>> {
+        let blocks = self.block_count()?;
+        let mut dest = Disk::create(path, blocks * BLOCK_SIZE)?;
+        for num in 0..blocks {
+            let staged = self
+                .journal
+                .as_ref()
+                .and_then(|journal| journal.staged.as_ref())
+                .and_then(|staged| staged.get(&num));
+            let dirty = self.dirty.as_ref().and_then(|dirty| dirty.get(&num));
+            let block = match staged.or(dirty) {
+                Some(block) => *block,
+                None => self.read_physical(num)?,
+            };
+            dest.write_physical(num, &block)?;
+        }
+        Ok(dest)
+    }
+
+    pub fn cache_hits(&self) -> usize {
+        self.cache().map_or(0, |cache| cache.hits)
+    }
+
+    pub fn cache_misses(&self) -> usize {
+        self.cache().map_or(0, |cache| cache.misses)
+    }
+
+    pub fn read_block(&self, num: usize) -> DiskResult<[u8; BLOCK_SIZE]> {
+        self.check_range(num, 1)?;
+        let staged = self
+            .journal
+            .as_ref()
+            .and_then(|journal| journal.staged.as_ref());
+        if let Some(block) = staged.and_then(|staged| staged.get(&num)) {
+            return Ok(*block);
+        }
+        if let Some(block) = self.dirty.as_ref().and_then(|dirty| dirty.get(&num)) {
+            return Ok(*block);
+        }
+        if let Some(block) = self.cache().and_then(|mut cache| cache.get(num)) {
+            return Ok(block);
+        }
+        let block = self.read_physical(num)?;
+        if let Some(checksums) = &self.checksums {
+            if checksums.covers(num) && crc32(&block) != checksums.sums[num] {
+                return Err(DiskError::ChecksumMismatch { block: num });
+            }
+        }
+        if let Some(mut cache) = self.cache() {
+            cache.insert(num, block);
+        }
         Ok(block)
     }
 
     pub fn write_block(&mut self, num: usize, data: [u8; BLOCK_SIZE]) -> DiskResult<()> {
-        self.backing_file
-            .seek(SeekFrom::Start((num * BLOCK_SIZE) as u64))?;
-        self.backing_file.write_all(&data)?;
+        self.check_range(num, 1)?;
+        self.write_unchecked(num, data)?;
+        let table = match self.checksums.as_mut() {
+            Some(checksums) if checksums.covers(num) => {
+                checksums.sums[num] = crc32(&data);
+                checksums.table_block(num)
+            }
+            _ => return Ok(()),
+        };
+        self.write_unchecked(table.0, table.1)
+    }
+
+    // whole blocks from start on, going to the device in one write when nothing needs to hold them
+    // back first
+    pub fn write_blocks(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            });
+        }
+        self.check_range(start, data.len() / BLOCK_SIZE)?;
+        let staged = !self.read_only
+            && self
+                .journal
+                .as_ref()
+                .is_some_and(|journal| journal.staged.is_some());
+        if staged || self.dirty.is_some() {
+            for (i, block) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+                self.write_block(start + i, block.try_into().unwrap())?;
+            }
+            return Ok(());
+        }
+        let blocks = start..start + data.len() / BLOCK_SIZE;
+        if let Some(cache) = self.cache_mut() {
+            for (num, block) in blocks.clone().zip(data.chunks_exact(BLOCK_SIZE)) {
+                cache.insert(num, block.try_into().unwrap());
+            }
+        }
+        self.write_physical_run(start, data)?;
+        let Some(checksums) = self.checksums.as_mut() else {
+            return Ok(());
+        };
+        // each table block only needs writing once, whichever of its blocks it's found through
+        let mut tables = BTreeMap::new();
+        for (num, block) in blocks.zip(data.chunks_exact(BLOCK_SIZE)) {
+            if checksums.covers(num) {
+                checksums.sums[num] = crc32(block);
+                tables.insert(num / (BLOCK_SIZE / Checksums::ENTRY_LEN), num);
+            }
+        }
+        for num in tables.into_values() {
+            let (table, data) = self.checksums.as_ref().unwrap().table_block(num);
+            self.write_unchecked(table, data)?;
+        }
         Ok(())
     }
 
+    // write without touching the checksum table
+    fn write_unchecked(&mut self, num: usize, data: [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        // on a read only disk the journal only holds what got replayed
+        if !self.read_only {
+            if let Some(staged) = self
+                .journal
+                .as_mut()
+                .and_then(|journal| journal.staged.as_mut())
+            {
+                staged.insert(num, data);
+                return Ok(());
+            }
+        }
+        if let Some(cache) = self.cache_mut() {
+            cache.insert(num, data);
+        }
+        match self.dirty.as_mut() {
+            Some(dirty) => {
+                dirty.insert(num, data);
+                Ok(())
+            }
+            None => self.write_physical(num, &data),
+        }
+    }
+
+    fn read_physical(&self, num: usize) -> DiskResult<[u8; BLOCK_SIZE]> {
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.blocks_read += 1;
+        stats.bytes_read += BLOCK_SIZE as u64;
+        drop(stats);
+        let mut block = [0; BLOCK_SIZE];
+        self.device.read_block(num, &mut block)?;
+        Ok(block)
+    }
+
+    fn write_physical(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        self.write_physical_run(num, data)
+    }
+
+    fn write_physical_run(&mut self, start: usize, data: &[u8]) -> DiskResult<()> {
+        let blocks = data.len() / BLOCK_SIZE;
+        let stats = self.stats_mut();
+        stats.blocks_written += blocks as u64;
+        stats.bytes_written += data.len() as u64;
+        stats.write_calls += 1;
+        match blocks {
+            1 => self.device.write_block(start, data.try_into().unwrap()),
+            _ => self.device.write_blocks(start, data),
+        }
+    }
+
     // rust doesn't need to have you explicitly close a file, instead linking it to the lifetime of
     // the `File` object, as such we don't need to implement close for this struct
 }
 
+impl<const BLOCK_SIZE: usize, D: BlockDevice<BLOCK_SIZE>> Drop for Disk<BLOCK_SIZE, D> {
+    fn drop(&mut self) {
+        // nothing can be done about a failed flush in drop
+        let _ = self.flush();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -64,10 +1146,325 @@ mod tests {
     fn write_read_works() {
         const DISK_PATH: &str = "disk.bin";
         const BLOCK_SIZE: usize = 512;
-        let mut disk: Disk<BLOCK_SIZE> = Disk::open(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        let mut disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 32).unwrap();
         let block = [0x42; BLOCK_SIZE];
         disk.write_block(15, block).unwrap();
         assert_eq!(disk.read_block(15).unwrap(), block);
         fs::remove_file(DISK_PATH).unwrap();
     }
+
+    #[test]
+    fn file_device_works() {
+        const DISK_PATH: &str = "file-device-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let mut file = File::create(DISK_PATH).unwrap();
+        // out of order, so every block has to land exactly where it's asked to
+        for num in [3, 0, 2] {
+            file.write_block(num, &[num as u8 + 1; BLOCK_SIZE]).unwrap();
+        }
+        assert_eq!(BlockDevice::<BLOCK_SIZE>::block_count(&file), 4);
+        let file = File::open(DISK_PATH).unwrap();
+        let mut buf = [0; BLOCK_SIZE];
+        for (num, expected) in [(2, 3), (1, 0), (3, 4), (0, 1)] {
+            file.read_block(num, &mut buf).unwrap();
+            assert_eq!(buf, [expected; BLOCK_SIZE]);
+        }
+        assert!(matches!(
+            file.read_block(4, &mut buf),
+            Err(DiskError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn create_and_open_work() {
+        const DISK_PATH: &str = "create-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let _ = fs::remove_file(DISK_PATH);
+        for size in [0, BLOCK_SIZE + 1] {
+            assert!(matches!(
+                Disk::<BLOCK_SIZE>::create(DISK_PATH, size),
+                Err(DiskError::InvalidSize { .. })
+            ));
+        }
+        assert!(fs::metadata(DISK_PATH).is_err());
+        assert!(matches!(
+            Disk::<BLOCK_SIZE>::open_existing(DISK_PATH),
+            Err(DiskError::NotFound)
+        ));
+        assert!(matches!(
+            Disk::<BLOCK_SIZE>::open_read_only(DISK_PATH),
+            Err(DiskError::NotFound)
+        ));
+        // and looking didn't leave an empty file behind
+        assert!(fs::metadata(DISK_PATH).is_err());
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 4).unwrap();
+        disk.write_block(1, [1; BLOCK_SIZE]).unwrap();
+        drop(disk);
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            BLOCK_SIZE as u64 * 4
+        );
+        let disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        assert_eq!(disk.block_count().unwrap(), 4);
+        assert_eq!(disk.read_block(1).unwrap(), [1; BLOCK_SIZE]);
+        drop(disk);
+        // creating again starts from nothing
+        let disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 2).unwrap();
+        assert_eq!(disk.read_block(1).unwrap(), [0; BLOCK_SIZE]);
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            BLOCK_SIZE as u64 * 2
+        );
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_works() {
+        const DISK_PATH: &str = "range-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let mut disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 8).unwrap();
+        assert_eq!(disk.block_count().unwrap(), 8);
+        // a new image is its full size from the start
+        disk.write_block(7, [7; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.read_block(7).unwrap(), [7; BLOCK_SIZE]);
+        assert!(matches!(
+            disk.write_block(8, [8; BLOCK_SIZE]),
+            Err(DiskError::OutOfRange { block: 8, max: 8 })
+        ));
+        assert!(matches!(
+            disk.write_blocks(6, &[6; BLOCK_SIZE * 3]),
+            Err(DiskError::OutOfRange { block: 8, max: 8 })
+        ));
+        assert!(matches!(
+            disk.read_block(20),
+            Err(DiskError::OutOfRange { block: 20, max: 8 })
+        ));
+        assert_eq!(disk.writes(), 1);
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            BLOCK_SIZE as u64 * 8
+        );
+        drop(disk);
+
+        // reopened it's as big as the file
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        assert_eq!(disk.block_count().unwrap(), 8);
+        assert!(disk.read_block(8).is_err());
+        // and can be held to less
+        disk.set_block_count(4);
+        assert!(matches!(
+            disk.read_block(7),
+            Err(DiskError::OutOfRange { block: 7, max: 4 })
+        ));
+        assert_eq!(disk.read_block(3).unwrap(), [0; BLOCK_SIZE]);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn cache_works() {
+        const DISK_PATH: &str = "cache-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        Disk::<BLOCK_SIZE>::create(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        let mut disk: Disk<BLOCK_SIZE> = Disk::with_cache(DISK_PATH, 2).unwrap();
+        for i in 0..4 {
+            disk.write_block(i, [i as u8; BLOCK_SIZE]).unwrap();
+        }
+        // blocks 2 and 3 are still cached from being written
+        assert_eq!(disk.read_block(3).unwrap(), [3; BLOCK_SIZE]);
+        assert_eq!(disk.read_block(2).unwrap(), [2; BLOCK_SIZE]);
+        assert_eq!((disk.cache_hits(), disk.cache_misses()), (2, 0));
+        assert_eq!(disk.read_block(0).unwrap(), [0; BLOCK_SIZE]);
+        assert_eq!((disk.cache_hits(), disk.cache_misses()), (2, 1));
+        // reading 0 pushed out 3, the least recently used
+        disk.read_block(2).unwrap();
+        disk.read_block(3).unwrap();
+        assert_eq!((disk.cache_hits(), disk.cache_misses()), (3, 2));
+
+        // writes replace what's cached
+        disk.write_block(3, [0x42; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.read_block(3).unwrap(), [0x42; BLOCK_SIZE]);
+        drop(disk);
+
+        // an image that's already there is opened as it is
+        let disk: Disk<BLOCK_SIZE> = Disk::with_cache(DISK_PATH, 2).unwrap();
+        assert_eq!(disk.block_count().unwrap(), 32);
+        assert_eq!(disk.read_block(3).unwrap(), [0x42; BLOCK_SIZE]);
+        assert_eq!(disk.read_block(1).unwrap(), [1; BLOCK_SIZE]);
+        assert_eq!((disk.cache_hits(), disk.cache_misses()), (0, 2));
+        drop(disk);
+        assert!(matches!(
+            Disk::<BLOCK_SIZE>::with_cache("missing-cache-disk.bin", 2),
+            Err(DiskError::NotFound)
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn write_back_works() {
+        const DISK_PATH: &str = "write-back-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let mut disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        disk.set_write_back(true).unwrap();
+        for i in 0..50 {
+            disk.write_block(i % 5, [i as u8; BLOCK_SIZE]).unwrap();
+        }
+        assert_eq!(disk.writes(), 0);
+        // reads see what's buffered
+        assert_eq!(disk.read_block(4).unwrap(), [49; BLOCK_SIZE]);
+        disk.flush().unwrap();
+        disk.sync_all().unwrap();
+        assert_eq!(
+            disk.stats(),
+            DiskStats {
+                blocks_read: 0,
+                blocks_written: 5,
+                bytes_read: 0,
+                bytes_written: BLOCK_SIZE as u64 * 5,
+                write_calls: 5,
+                syncs: 1,
+            }
+        );
+        disk.reset_stats();
+        assert_eq!(disk.stats(), DiskStats::default());
+        drop(disk);
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        for i in 0..5 {
+            assert_eq!(disk.read_block(i).unwrap(), [45 + i as u8; BLOCK_SIZE]);
+        }
+        // dropping flushes too
+        disk.set_write_back(true).unwrap();
+        disk.write_block(0, [0x42; BLOCK_SIZE]).unwrap();
+        drop(disk);
+        let disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        assert_eq!(disk.read_block(0).unwrap(), [0x42; BLOCK_SIZE]);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn checksums_work() {
+        const DISK_PATH: &str = "checksums-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let mut disk: Disk<BLOCK_SIZE> = Disk::create(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        for i in 0..32 {
+            disk.write_block(i, [0; BLOCK_SIZE]).unwrap();
+        }
+        disk.format_checksums(1, Disk::<BLOCK_SIZE>::checksum_blocks(32))
+            .unwrap();
+        disk.write_block(5, [0x42; BLOCK_SIZE]).unwrap();
+        drop(disk);
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        disk.write_block(6, [0x42; BLOCK_SIZE]).unwrap();
+        disk.enable_checksums(1, 1).unwrap();
+        assert_eq!(disk.read_block(5).unwrap(), [0x42; BLOCK_SIZE]);
+        assert_eq!(disk.read_block(7).unwrap(), [0; BLOCK_SIZE]);
+        // written behind the table's back
+        assert!(matches!(
+            disk.read_block(6),
+            Err(DiskError::ChecksumMismatch { block: 6 })
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn write_blocks_works() {
+        const DISK_PATH: &str = "write-blocks-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        Disk::<BLOCK_SIZE>::create(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        let mut disk: Disk<BLOCK_SIZE> = Disk::with_cache(DISK_PATH, 4).unwrap();
+        disk.format_checksums(1, Disk::<BLOCK_SIZE>::checksum_blocks(32))
+            .unwrap();
+        disk.reset_stats();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 6)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        disk.write_blocks(10, &data).unwrap();
+        // the run and the checksum table
+        assert_eq!(disk.stats().write_calls, 2);
+        assert_eq!(disk.stats().blocks_written, 7);
+        assert_eq!(disk.read_block(15).unwrap(), [5; BLOCK_SIZE]);
+        assert!(matches!(
+            disk.write_blocks(0, &[0; 3]),
+            Err(DiskError::InvalidSize { .. })
+        ));
+        drop(disk);
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        disk.enable_checksums(1, 1).unwrap();
+        for i in 0..6 {
+            assert_eq!(disk.read_block(10 + i).unwrap(), [i as u8; BLOCK_SIZE]);
+        }
+        // still block by block while they're being held back
+        disk.set_write_back(true).unwrap();
+        disk.write_blocks(20, &data).unwrap();
+        assert_eq!(disk.read_block(21).unwrap(), [1; BLOCK_SIZE]);
+        assert_eq!(disk.writes(), 0);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_works() {
+        const DISK_PATH: &str = "mmap-disk.bin";
+        const BLOCK_SIZE: usize = 512;
+        let _ = fs::remove_file(DISK_PATH);
+        assert!(matches!(
+            MmapDisk::open(DISK_PATH, BLOCK_SIZE),
+            Err(DiskError::NotFound)
+        ));
+        File::create(DISK_PATH).unwrap();
+        let device = MmapDisk::open(DISK_PATH, BLOCK_SIZE * 32).unwrap();
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            BLOCK_SIZE as u64 * 32
+        );
+        let mut disk: Disk<BLOCK_SIZE, _> = Disk::new(device, false);
+        disk.set_cache_blocks(2);
+        disk.write_block(15, [0x42; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.read_block(15).unwrap(), [0x42; BLOCK_SIZE]);
+        assert_eq!(disk.read_block(3).unwrap(), [0; BLOCK_SIZE]);
+        assert!(matches!(
+            disk.write_block(40, [0x43; BLOCK_SIZE]),
+            Err(DiskError::OutOfRange { block: 40, max: 32 })
+        ));
+        // past the end grows the file and the mapping, once the disk is allowed that far
+        disk.set_block_count(41);
+        disk.write_block(40, [0x43; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.block_count().unwrap(), 41);
+        drop(disk);
+
+        // the mapped writes reached the file
+        let disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        assert_eq!(disk.read_block(15).unwrap(), [0x42; BLOCK_SIZE]);
+        assert_eq!(disk.read_block(40).unwrap(), [0x43; BLOCK_SIZE]);
+        drop(disk);
+        let disk: Disk<BLOCK_SIZE, _> = Disk::new(MmapDisk::open(DISK_PATH, 0).unwrap(), false);
+        assert_eq!(disk.read_block(40).unwrap(), [0x43; BLOCK_SIZE]);
+        assert!(disk.read_block(41).is_err());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn oversized_transaction_works() {
+        const BLOCK_SIZE: usize = 512;
+        let device = MemDisk::from_bytes(&[0; BLOCK_SIZE * 8]).unwrap();
+        let mut disk: Disk<BLOCK_SIZE, _> = Disk::new(device, false);
+        disk.enable_journal(1, 2).unwrap();
+        disk.begin_transaction();
+        disk.write_block(4, [1; BLOCK_SIZE]).unwrap();
+        disk.write_block(5, [2; BLOCK_SIZE]).unwrap();
+        assert!(matches!(
+            disk.commit(),
+            Err(DiskError::TransactionTooLarge {
+                blocks: 2,
+                capacity: 1
+            })
+        ));
+        // still staged rather than lost, or half written
+        assert_eq!(disk.read_block(5).unwrap(), [2; BLOCK_SIZE]);
+        assert_eq!(disk.read_physical(5).unwrap(), [0; BLOCK_SIZE]);
+    }
 }
@@ -0,0 +1,533 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::Path,
+};
+
+use crate::{
+    disk::Disk,
+    stored_entries,
+    structures::{decode, encode, INodeData, RootData, FLAG_PREALLOCATED, MAX_BLOCKS},
+    DirEntry, INode, SuperBlock, TfsError, TfsFs, TfsResult, BLOCK_SIZE,
+};
+
+// inodes are referred to by their block, the root directory by the first root block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckFinding {
+    // a directory entry that doesn't point at a readable inode
+    BadInode {
+        block: u16,
+    },
+    // an inode using a block past the end of the disk
+    BadBlock {
+        inode: u16,
+        block: u16,
+    },
+    // in use but not marked as allocated, so it could be handed out again
+    UnallocatedBlock {
+        inode: u16,
+        block: u16,
+    },
+    // marked as allocated but nothing uses it
+    LeakedBlock {
+        block: u16,
+    },
+    // more than one inode claims the same block
+    CrossLinkedBlock {
+        block: u16,
+        inodes: Vec<u16>,
+    },
+    // the refcount table doesn't match how many files actually share the block
+    BadRefcount {
+        block: u16,
+        refcount: u8,
+        references: usize,
+    },
+    // the inode's link count doesn't match how many names it actually has
+    BadLinkCount {
+        inode: u16,
+        nlink: u16,
+        names: u16,
+    },
+    // the size doesn't line up with the number of blocks the inode has
+    BadSize {
+        inode: u16,
+        size: u64,
+        blocks: usize,
+    },
+    DuplicateFilename {
+        dir: u16,
+        filename: String,
+    },
+}
+
+impl FsckFinding {
+    // rebuilding the bitmap and clamping sizes is safe, anything else needs a person to look at it
+    pub fn is_repairable(&self) -> bool {
+        match self {
+            FsckFinding::UnallocatedBlock { .. }
+            | FsckFinding::LeakedBlock { .. }
+            | FsckFinding::BadRefcount { .. }
+            | FsckFinding::BadLinkCount { .. } => true,
+            FsckFinding::BadSize { size, blocks, .. } => *size > (*blocks * BLOCK_SIZE) as u64,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub findings: Vec<FsckFinding>,
+    // whether the repairable findings were fixed on disk
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+pub fn check(path: impl AsRef<Path>, repair: bool) -> TfsResult<FsckReport> {
+    let mut disk: Disk<BLOCK_SIZE> = if repair {
+        Disk::open_existing(path)?
+    } else {
+        Disk::open_read_only(path)?
+    };
+    let mut data = TfsFs::read_superblock(&mut disk, 0)?;
+    TfsFs::fit_disk(&mut disk, &data)?;
+    let total_blocks = (data.total_blocks as usize).min(MAX_BLOCKS);
+    let in_range = |block: u16| block != 0 && (block as usize) < total_blocks;
+    TfsFs::enable_features(&mut disk, &mut data)?;
+    let root_block = data.root_inode;
+    let mut superblock: SuperBlock = data.into();
+    superblock.load_refcounts(&mut disk)?;
+
+    let mut findings = Vec::new();
+    // every block in use and the inodes using it
+    let mut owners: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+    // how many of those are files' data, which clones are allowed to share
+    let mut data_refs: BTreeMap<u16, usize> = BTreeMap::new();
+    for block in superblock.reserved_blocks() {
+        owners.entry(block).or_default().push(0);
+    }
+
+    let mut entries = Vec::new();
+    let mut next = root_block;
+    let mut chain = HashSet::new();
+    while chain.insert(next) {
+        if !in_range(next) {
+            findings.push(FsckFinding::BadInode { block: next });
+            break;
+        }
+        owners.entry(next).or_default().push(root_block);
+        let data: RootData = decode(&disk.read_block(next as usize)?)?;
+        entries.extend(stored_entries(&data)?);
+        if data.next == 0 {
+            break;
+        }
+        next = data.next;
+    }
+
+    let mut names: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    // the link count of every regular file, and how many names it actually has
+    let mut nlinks: BTreeMap<u16, (u16, u16)> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut pending: VecDeque<(u16, DirEntry)> = entries
+        .into_iter()
+        .map(|entry| (root_block, entry))
+        .collect();
+    while let Some((
+        dir,
+        DirEntry {
+            filename,
+            inode: block,
+        },
+    )) = pending.pop_front()
+    {
+        names.entry(dir).or_default().push(filename);
+        if !in_range(block) {
+            findings.push(FsckFinding::BadInode { block });
+            continue;
+        }
+        // a regular file can be in any number of directories, anything else only in the one
+        if let Some((_, names)) = nlinks.get_mut(&block) {
+            *names += 1;
+            continue;
+        }
+        owners.entry(block).or_default().push(dir);
+        if !seen.insert(block) {
+            continue;
+        }
+        let data = disk
+            .read_block(block as usize)
+            .map_err(TfsError::from)
+            .and_then(|data| Ok(decode::<INodeData>(&data)?));
+        let Ok(data) = data else {
+            findings.push(FsckFinding::BadInode { block });
+            continue;
+        };
+        let preallocated = data.flags & FLAG_PREALLOCATED != 0;
+        let Ok(inode) = INode::from_data(block, data, &mut disk) else {
+            findings.push(FsckFinding::BadInode { block });
+            continue;
+        };
+        for &indirect in &inode.indirect {
+            owners.entry(indirect).or_default().push(block);
+        }
+        for &data in inode.blocks.iter().filter(|&&data| data != 0) {
+            if in_range(data) {
+                owners.entry(data).or_default().push(block);
+                if !inode.is_dir() {
+                    *data_refs.entry(data).or_default() += 1;
+                }
+            } else {
+                findings.push(FsckFinding::BadBlock {
+                    inode: block,
+                    block: data,
+                });
+            }
+        }
+        if inode.is_dir() {
+            pending.extend(inode.children.into_iter().map(|entry| (block, entry)));
+            continue;
+        }
+        nlinks.insert(block, (inode.stat.nlink, 1));
+        let blocks = inode.blocks.len();
+        let size = inode.stat.size;
+        // blocks past the end are only expected if they were reserved on purpose
+        if size > (blocks * BLOCK_SIZE) as u64
+            || (!preallocated && blocks > 0 && size <= ((blocks - 1) * BLOCK_SIZE) as u64)
+        {
+            findings.push(FsckFinding::BadSize {
+                inode: block,
+                size,
+                blocks,
+            });
+        }
+    }
+
+    for (&block, inodes) in &owners {
+        let shareable = superblock.has_refcounts() && data_refs.get(&block) == Some(&inodes.len());
+        let refcount = superblock.refcount(block);
+        if shareable && refcount as usize != inodes.len() - 1 {
+            findings.push(FsckFinding::BadRefcount {
+                block,
+                refcount,
+                references: inodes.len(),
+            });
+        } else if !shareable && inodes.len() > 1 {
+            findings.push(FsckFinding::CrossLinkedBlock {
+                block,
+                inodes: inodes.clone(),
+            });
+        }
+        if !superblock.is_allocated(block) {
+            findings.push(FsckFinding::UnallocatedBlock {
+                inode: inodes[0],
+                block,
+            });
+        }
+    }
+    for block in 0..total_blocks as u16 {
+        if superblock.is_allocated(block) && !owners.contains_key(&block) {
+            findings.push(FsckFinding::LeakedBlock { block });
+        }
+        let refcount = superblock.refcount(block);
+        if refcount != 0 && !data_refs.contains_key(&block) {
+            findings.push(FsckFinding::BadRefcount {
+                block,
+                refcount,
+                references: owners.get(&block).map_or(0, Vec::len),
+            });
+        }
+    }
+    for (&inode, &(nlink, names)) in &nlinks {
+        if nlink != names {
+            findings.push(FsckFinding::BadLinkCount {
+                inode,
+                nlink,
+                names,
+            });
+        }
+    }
+    for (&dir, names) in &mut names {
+        names.sort();
+        let mut reported = HashSet::new();
+        for pair in names.windows(2) {
+            if pair[0] == pair[1] && reported.insert(&pair[0]) {
+                findings.push(FsckFinding::DuplicateFilename {
+                    dir,
+                    filename: pair[0].clone(),
+                });
+            }
+        }
+    }
+
+    let mut report = FsckReport {
+        findings,
+        repaired: false,
+    };
+    if !repair || !report.findings.iter().any(FsckFinding::is_repairable) {
+        return Ok(report);
+    }
+    for finding in &report.findings {
+        if let &FsckFinding::BadRefcount { block, .. } = finding {
+            // whatever isn't file data can't be shared at all
+            let references = data_refs.get(&block).copied().unwrap_or(1);
+            superblock.set_refcount(block, (references - 1).min(u8::MAX as usize) as u8);
+        }
+        if let &FsckFinding::BadLinkCount { inode, names, .. } = finding {
+            let mut data: INodeData = decode(&disk.read_block(inode as usize)?)?;
+            data.stat.nlink = names;
+            disk.write_block(inode as usize, encode(&data)?.try_into().unwrap())?;
+        }
+        if let &FsckFinding::BadSize { inode, blocks, .. } = finding {
+            if finding.is_repairable() {
+                let mut data: INodeData = decode(&disk.read_block(inode as usize)?)?;
+                data.stat.size = (blocks * BLOCK_SIZE) as u32;
+                disk.write_block(inode as usize, encode(&data)?.try_into().unwrap())?;
+            }
+        }
+    }
+    // the bitmap is rebuilt from scratch out of what's actually in use
+    for block in 0..total_blocks as u16 {
+        if owners.contains_key(&block) {
+            superblock.mark_allocated(block);
+        } else {
+            superblock.mark_free(block);
+        }
+    }
+    superblock.sync(&mut disk)?;
+    disk.flush()?;
+    report.repaired = true;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{testing::mkfs_fresh, MkfsOptions, Tfs, DEFAULT_DISK_SIZE};
+
+    // an image with a couple of files, handed over mounted so it can be messed with
+    fn setup(path: &str) -> TfsFs {
+        mkfs_fresh(path, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(path).unwrap();
+        tfs.write_file("first", &[0x42; BLOCK_SIZE + 1]).unwrap();
+        tfs.write_file("second", &[0x24; BLOCK_SIZE * 2]).unwrap();
+        tfs
+    }
+
+    #[test]
+    fn clean_image_works() {
+        const DISK_PATH: &str = "fsck-clean-disk.bin";
+        drop(setup(DISK_PATH));
+        let image = fs::read(DISK_PATH).unwrap();
+        assert_eq!(Tfs::check(DISK_PATH).unwrap(), FsckReport::default());
+        assert_eq!(Tfs::repair(DISK_PATH).unwrap(), FsckReport::default());
+        assert_eq!(fs::read(DISK_PATH).unwrap(), image);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn bitmap_findings_work() {
+        const DISK_PATH: &str = "fsck-bitmap-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        let first = &tfs.root.inodes[0];
+        let (inode, block) = (first.block, first.blocks[0]);
+        let leaked = tfs.superblock.allocate_block().unwrap();
+        tfs.superblock.mark_free(block);
+        tfs.sync().unwrap();
+        drop(tfs);
+
+        let report = Tfs::check(DISK_PATH).unwrap();
+        assert_eq!(
+            report.findings,
+            [
+                FsckFinding::UnallocatedBlock { inode, block },
+                FsckFinding::LeakedBlock { block: leaked },
+            ]
+        );
+        assert!(report.findings.iter().all(FsckFinding::is_repairable));
+        assert!(Tfs::repair(DISK_PATH).unwrap().repaired);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn cross_linked_works() {
+        const DISK_PATH: &str = "fsck-cross-linked-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        let block = tfs.root.inodes[0].blocks[0];
+        let second = &mut tfs.root.inodes[1];
+        let (inode, orphaned) = (second.block, second.blocks[0]);
+        second.blocks[0] = block;
+        second.dirty = true;
+        tfs.sync().unwrap();
+        let first = tfs.root.inodes[0].block;
+        drop(tfs);
+
+        let report = Tfs::repair(DISK_PATH).unwrap();
+        assert_eq!(
+            report.findings,
+            [
+                FsckFinding::CrossLinkedBlock {
+                    block,
+                    inodes: vec![first, inode],
+                },
+                FsckFinding::LeakedBlock { block: orphaned },
+            ]
+        );
+        // the leak can be fixed but the cross link can't
+        assert_eq!(
+            Tfs::check(DISK_PATH).unwrap().findings,
+            report.findings[..1]
+        );
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn bad_refcount_works() {
+        const DISK_PATH: &str = "fsck-bad-refcount-disk.bin";
+        MkfsOptions::new()
+            .reflinks(true)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.write_file("first", &[0x42; BLOCK_SIZE]).unwrap();
+        tfs.clone_file("first", "second").unwrap();
+        let block = tfs.root.inodes[0].blocks[0];
+        tfs.superblock.set_refcount(block, 0);
+        tfs.sync().unwrap();
+        drop(tfs);
+
+        let report = Tfs::repair(DISK_PATH).unwrap();
+        assert_eq!(
+            report.findings,
+            [FsckFinding::BadRefcount {
+                block,
+                refcount: 0,
+                references: 2,
+            }]
+        );
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn bad_link_count_works() {
+        const DISK_PATH: &str = "fsck-bad-link-count-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        tfs.link("first", "third").unwrap();
+        let inode = &mut tfs.root.inodes[0];
+        inode.stat.nlink = 1;
+        inode.dirty = true;
+        let inode = inode.block;
+        tfs.sync().unwrap();
+        drop(tfs);
+
+        let report = Tfs::repair(DISK_PATH).unwrap();
+        assert_eq!(
+            report.findings,
+            [FsckFinding::BadLinkCount {
+                inode,
+                nlink: 1,
+                names: 2,
+            }]
+        );
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn bad_size_works() {
+        const DISK_PATH: &str = "fsck-bad-size-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        tfs.root.inodes[0].stat.size = BLOCK_SIZE as u64 * 3;
+        tfs.root.inodes[0].dirty = true;
+        tfs.root.inodes[1].stat.size = 1;
+        tfs.root.inodes[1].dirty = true;
+        tfs.sync().unwrap();
+        let (first, second) = (tfs.root.inodes[0].block, tfs.root.inodes[1].block);
+        drop(tfs);
+
+        let report = Tfs::repair(DISK_PATH).unwrap();
+        assert_eq!(
+            report.findings,
+            [
+                FsckFinding::BadSize {
+                    inode: first,
+                    size: BLOCK_SIZE as u64 * 3,
+                    blocks: 2,
+                },
+                FsckFinding::BadSize {
+                    inode: second,
+                    size: 1,
+                    blocks: 2,
+                },
+            ]
+        );
+        // too big gets clamped, too small is left alone
+        assert_eq!(
+            Tfs::check(DISK_PATH).unwrap().findings,
+            report.findings[1..]
+        );
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("first").unwrap().size, BLOCK_SIZE as u64 * 2);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn bad_inode_works() {
+        const DISK_PATH: &str = "fsck-bad-inode-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        let garbage = tfs.superblock.allocate_block().unwrap();
+        tfs.disk
+            .write_block(garbage as usize, [0xff; BLOCK_SIZE])
+            .unwrap();
+        tfs.root
+            .entries
+            .extend([500, garbage].map(|inode| DirEntry {
+                filename: inode.to_string(),
+                inode,
+            }));
+        // they don't fit in the first root block
+        let chain = tfs.superblock.allocate_block().unwrap();
+        tfs.root.chain.push(chain);
+        tfs.root.dirty = true;
+        tfs.sync().unwrap();
+        tfs.disk.release_lock();
+        std::mem::forget(tfs);
+
+        assert_eq!(
+            Tfs::check(DISK_PATH).unwrap().findings,
+            [
+                FsckFinding::BadInode { block: 500 },
+                FsckFinding::BadInode { block: garbage },
+            ]
+        );
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn duplicate_filename_works() {
+        const DISK_PATH: &str = "fsck-duplicate-disk.bin";
+        let mut tfs = setup(DISK_PATH);
+        tfs.root.entries[1].filename = "first".to_string();
+        tfs.root.dirty = true;
+        tfs.sync().unwrap();
+        drop(tfs);
+
+        assert_eq!(
+            Tfs::check(DISK_PATH).unwrap().findings,
+            [FsckFinding::DuplicateFilename {
+                dir: 1,
+                filename: "first".to_string(),
+            }]
+        );
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+}
@@ -1,28 +1,70 @@
 use std::{
-    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
     ffi::CString,
-    path::Path,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    str,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
     time::{Duration, SystemTime},
 };
 
 use disk::Disk;
-use structures::{INodeData, StatData, ALLOCATION_TABLE_LEN};
+use structures::{
+    decode, disk_time, encode, INodeData, IndirectData, StatData, ALLOCATION_TABLE_LEN,
+    FLAG_APPEND_ONLY, FLAG_IMMUTABLE, FLAG_PREALLOCATED, INDIRECT_BLOCK_LEN, INODE_BLOCKS,
+    LABEL_LEN, MAX_BLOCKS, MAX_DIR_ENTRIES, MAX_FILENAME_LEN, MAX_FILE_BLOCKS, ROOT_ENTRIES,
+    UUID_LEN,
+};
+
+use crate::structures::{
+    v1, RootData, SuperBlockData, FEATURE_CHECKSUMS, FEATURE_JOURNAL, FORMAT_VERSION, MAGIC_NUMBER,
+    STATE_CLEAN, SUPPORTED_FEATURES,
+};
 
-use crate::structures::{RootData, SuperBlockData};
+// a tracing event, or nothing at all without the tracing feature
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
 
+#[cfg(feature = "async")]
+mod async_tfs;
 mod disk;
+mod fsck;
 mod structures;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "async")]
+pub use async_tfs::{AsyncFile, AsyncTfs};
+#[cfg(feature = "mmap")]
+pub use disk::MmapDisk;
+pub use disk::{BlockDevice, DiskError, DiskResult, DiskStats, IoDevice, SliceDisk};
+pub use fsck::{FsckFinding, FsckReport};
+
+pub type MemDisk = disk::MemDisk<BLOCK_SIZE>;
 
 // hardcoded until const generics are stable
 pub const BLOCK_SIZE: usize = 256;
 pub const DEFAULT_DISK_SIZE: usize = 10240;
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+pub const DEFAULT_DIR_MODE: u16 = 0o755;
 
 #[derive(Debug, thiserror::Error)]
 pub enum TfsError {
     #[error("Disk size of {size} too large to fit in superblock")]
     SizeError { size: usize },
     #[error("Disk IO Error: {0}")]
-    DiskError(#[from] disk::DiskError),
+    DiskError(disk::DiskError),
     #[error("Serialization Error: {0}")]
     SerializationError(#[from] bincode::Error),
     #[error("Invalid magic number: {0} should be 0x5A")]
@@ -35,100 +77,545 @@ pub enum TfsError {
     InvalidDesc,
     #[error("Unable to find file {0}")]
     FileNotFound(String),
+    #[error("Invalid seek to a negative position")]
+    InvalidSeek,
+    #[error("File not opened for writing")]
+    ReadOnly,
+    #[error("File not opened for reading")]
+    WriteOnly,
+    #[error("File {0} already exists")]
+    AlreadyExists(String),
+    #[error("Filename {name} is longer than {max} bytes")]
+    FilenameTooLong { name: String, max: usize },
+    #[error("Unsupported format version {found}, only version {supported} is supported")]
+    UnsupportedVersion { found: u16, supported: u16 },
+    #[error("Invalid inode kind {0}")]
+    InvalidKind(u8),
+    #[error("{0} is not a directory")]
+    NotADirectory(String),
+    #[error("{0} is a directory")]
+    IsADirectory(String),
+    #[error("Directory {0} is not empty")]
+    DirectoryNotEmpty(String),
+    #[error("Directory is full")]
+    DirectoryFull,
+    #[error("Cannot move {0} inside itself")]
+    MoveIntoSelf(String),
+    #[error("Invalid filename {0:?}, must be utf-8 without any nul bytes")]
+    InvalidFilename(String),
+    #[error("Filesystem is mounted read only")]
+    ReadOnlyFilesystem,
+    #[error("Invalid label {0:?}, must be at most 16 bytes without any nul bytes")]
+    InvalidLabel(String),
+    #[error("Block {block} is used by more than one inode: {inodes:?}")]
+    CrossLinkedBlock { block: u16, inodes: Vec<u16> },
+    #[error("Checksum mismatch in block {block}")]
+    ChecksumMismatch { block: u16 },
+    #[error("Unsupported features {found:#x}, only {supported:#x} are supported")]
+    UnsupportedFeatures { found: u16, supported: u16 },
+    #[error("Journal of {0} blocks is too small, it needs a header and at least one more")]
+    JournalTooSmall(usize),
+    #[error("Reflinks aren't enabled on this filesystem")]
+    ReflinksDisabled,
+    #[error("Block {0} already has as many references as it can")]
+    TooManyReferences(u16),
+    #[error("{0} already has as many links as it can")]
+    TooManyLinks(String),
+    #[error("Permission denied for {0}")]
+    PermissionDenied(String),
+    #[error("Time {0:?} can't be stored, it has to be between 1970 and 2554")]
+    TimeOutOfRange(SystemTime),
+    #[error("{0} is locked by another handle")]
+    WouldBlock(String),
+    #[error("Image is already mounted somewhere else")]
+    ImageBusy,
+    #[error("There's already an image at {0}, force mkfs to replace it")]
+    ImageExists(PathBuf),
+    #[error("Image is only {actual} blocks, but the filesystem in it is {expected}")]
+    TruncatedImage { expected: usize, actual: usize },
+    #[error("File contents aren't valid utf-8: {0}")]
+    InvalidUtf8(str::Utf8Error),
+    #[error("{0} can't be removed while it's open")]
+    FileBusy(String),
+    #[error("Shrinking would mean moving {blocks} blocks, but there are only {free} free to move them to")]
+    NoRoomToShrink { blocks: usize, free: usize },
 }
 
 pub type TfsResult<T> = Result<T, TfsError>;
 
+impl From<disk::DiskError> for TfsError {
+    fn from(err: disk::DiskError) -> Self {
+        match err {
+            disk::DiskError::ChecksumMismatch { block } => TfsError::ChecksumMismatch {
+                block: block as u16,
+            },
+            disk::DiskError::Busy => TfsError::ImageBusy,
+            err => TfsError::DiskError(err),
+        }
+    }
+}
+
+impl From<TfsError> for io::Error {
+    fn from(err: TfsError) -> Self {
+        match err {
+            TfsError::DiskError(disk::DiskError::IoError(err)) => err,
+            TfsError::DiskError(disk::DiskError::NotFound) => {
+                io::Error::new(io::ErrorKind::NotFound, err)
+            }
+            TfsError::FileNotFound(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            TfsError::AlreadyExists(_) => io::Error::new(io::ErrorKind::AlreadyExists, err),
+            TfsError::OutOfSpace => io::Error::new(io::ErrorKind::StorageFull, err),
+            TfsError::ReadOnlyFilesystem => io::Error::new(io::ErrorKind::ReadOnlyFilesystem, err),
+            TfsError::PermissionDenied(_) => io::Error::new(io::ErrorKind::PermissionDenied, err),
+            TfsError::WouldBlock(_) => io::Error::new(io::ErrorKind::WouldBlock, err),
+            TfsError::ImageBusy | TfsError::FileBusy(_) => {
+                io::Error::new(io::ErrorKind::ResourceBusy, err)
+            }
+            TfsError::ImageExists(_) => io::Error::new(io::ErrorKind::AlreadyExists, err),
+            TfsError::ReflinksDisabled => io::Error::new(io::ErrorKind::Unsupported, err),
+            TfsError::InvalidUtf8(_) => io::Error::new(io::ErrorKind::InvalidData, err),
+            TfsError::TooManyReferences(_) | TfsError::TooManyLinks(_) => {
+                io::Error::new(io::ErrorKind::TooManyLinks, err)
+            }
+            TfsError::InvalidDesc
+            | TfsError::InvalidSeek
+            | TfsError::InvalidFilename(_)
+            | TfsError::InvalidLabel(_)
+            | TfsError::JournalTooSmall(_)
+            | TfsError::TimeOutOfRange(_) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            err => io::Error::other(err),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SuperBlock {
     dirty: bool,
+    // whether the image was unmounted cleanly
+    clean: bool,
+    uuid: [u8; UUID_LEN],
+    label: String,
+    backup_block: u16,
+    checksum_start: u16,
+    checksum_blocks: u16,
+    journal_start: u16,
+    journal_blocks: u16,
+    refcount_start: u16,
+    refcount_blocks: u16,
+    // empty unless the image has reflinks, see load_refcounts
+    refcounts: Vec<u8>,
+    refcounts_dirty: bool,
+    // the table covers blocks up to here, anything after it is past the end of the filesystem
+    total_blocks: u16,
     allocated_blocks: [u8; ALLOCATION_TABLE_LEN],
+    // neither of these are kept on disk, they're worked out from the table on mount. where the
+    // next allocation starts looking, and how many blocks in the table are free
+    next_free_hint: u16,
+    free_count: u32,
 }
 
 impl SuperBlock {
     pub fn new() -> Self {
         Self {
             dirty: true,
+            clean: false,
+            uuid: [0; UUID_LEN],
+            label: String::new(),
+            backup_block: 0,
+            checksum_start: 0,
+            checksum_blocks: 0,
+            journal_start: 0,
+            journal_blocks: 0,
+            refcount_start: 0,
+            refcount_blocks: 0,
+            refcounts: Vec::new(),
+            refcounts_dirty: false,
+            total_blocks: MAX_BLOCKS as u16,
             allocated_blocks: [0; ALLOCATION_TABLE_LEN],
+            next_free_hint: 0,
+            free_count: MAX_BLOCKS as u32,
+        }
+    }
+
+    // the blocks used by the filesystem itself, the superblock, its backup, the checksum table, the
+    // journal and the refcount table
+    pub fn reserved_blocks(&self) -> Vec<u16> {
+        let mut reserved = vec![0];
+        if self.backup_block != 0 {
+            reserved.push(self.backup_block);
+        }
+        reserved.extend(self.checksum_start..self.checksum_start + self.checksum_blocks);
+        reserved.extend(self.journal_start..self.journal_start + self.journal_blocks);
+        reserved.extend(self.refcount_start..self.refcount_start + self.refcount_blocks);
+        reserved
+    }
+
+    pub fn load_refcounts<const BLOCK_SIZE: usize>(
+        &mut self,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<()> {
+        self.refcounts.clear();
+        for block in self.refcount_start..self.refcount_start + self.refcount_blocks {
+            self.refcounts
+                .extend_from_slice(&disk.read_block(block as usize)?);
+        }
+        Ok(())
+    }
+
+    pub fn has_refcounts(&self) -> bool {
+        self.refcount_blocks != 0
+    }
+
+    // how many references there are to the block past the first
+    pub fn refcount(&self, block: u16) -> u8 {
+        self.refcounts.get(block as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_refcount(&mut self, block: u16, refcount: u8) {
+        self.refcounts_dirty = true;
+        self.refcounts[block as usize] = refcount;
+    }
+
+    pub fn is_shared(&self, block: u16) -> bool {
+        self.refcount(block) != 0
+    }
+
+    // another reference to each of blocks, all or nothing
+    pub fn share_blocks(&mut self, blocks: &[u16]) -> TfsResult<()> {
+        if !self.has_refcounts() {
+            return Err(TfsError::ReflinksDisabled);
+        }
+        if let Some(&block) = blocks
+            .iter()
+            .find(|&&block| self.refcount(block) == u8::MAX)
+        {
+            return Err(TfsError::TooManyReferences(block));
         }
+        for &block in blocks {
+            self.set_refcount(block, self.refcount(block) + 1);
+        }
+        Ok(())
     }
 
+    // next fit, carrying on from the last allocation and wrapping round to the start
     pub fn allocate_block(&mut self) -> Option<u16> {
-        self.dirty = true;
-        for (i, byte) in self.allocated_blocks.iter_mut().enumerate() {
-            if *byte != u8::MAX {
-                for bit in 0..8 {
-                    if *byte & (1 << bit) == 0 {
-                        *byte |= 1 << bit;
-                        let block = i as u16 * 8 + bit;
-                        return Some(block);
-                    }
+        let bytes = (self.total_blocks as usize).div_ceil(8);
+        let start = (self.next_free_hint as usize / 8).min(bytes);
+        let found = (start..bytes)
+            .chain(0..start)
+            .take_while(|_| self.free_count != 0)
+            .filter(|&i| self.allocated_blocks[i] != u8::MAX)
+            // the last byte can be partly past the end
+            .map(|i| (i * 8) as u16 + self.allocated_blocks[i].trailing_ones() as u16)
+            .find(|&block| block < self.total_blocks);
+        if let Some(block) = found {
+            self.mark_allocated(block);
+            self.next_free_hint = block + 1;
+            event!(trace, block, "allocated block");
+            return Some(block);
+        }
+        event!(warn, "no blocks left to allocate");
+        None
+    }
+
+    // n free blocks next to each other, returning the first. None if there's no gap that big, in
+    // which case nothing is allocated
+    pub fn allocate_run(&mut self, n: usize) -> Option<u16> {
+        if n == 0 || n > self.free_count as usize {
+            return None;
+        }
+        let mut start = 0;
+        for block in 0..self.total_blocks as usize {
+            if self.is_allocated(block as u16) {
+                start = block + 1;
+            } else if block + 1 - start == n {
+                for block in start..=block {
+                    self.mark_allocated(block as u16);
                 }
+                event!(trace, start, n, "allocated run");
+                return Some(start as u16);
             }
         }
         None
     }
 
+    // all or nothing, if there isn't space for every block none are allocated
+    pub fn allocate_blocks(&mut self, count: usize) -> Option<Vec<u16>> {
+        if count > self.free_count as usize {
+            return None;
+        }
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.allocate_block() {
+                Some(block) => blocks.push(block),
+                None => {
+                    for block in blocks {
+                        self.mark_free(block);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(blocks)
+    }
+
+    // anything past the end of the table can't have been allocated
+    pub fn is_allocated(&self, block: u16) -> bool {
+        let byte = block / 8;
+        let bit = block % 8;
+        self.allocated_blocks
+            .get(byte as usize)
+            .is_some_and(|byte| byte & (1 << bit) != 0)
+    }
+
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks as usize
+    }
+
+    // as big as the filesystem can get, the tables made at mkfs only have room for so many blocks
+    pub fn max_blocks(&self) -> usize {
+        let mut max = MAX_BLOCKS;
+        if self.checksum_blocks != 0 {
+            max = max.min(self.checksum_blocks as usize * BLOCK_SIZE / mem::size_of::<u32>());
+        }
+        if self.has_refcounts() {
+            max = max.min(self.refcounts.len());
+        }
+        max
+    }
+
+    // the size the filesystem was made, which moves the end of the table
+    pub fn set_total_blocks(&mut self, total_blocks: usize) {
+        self.dirty = true;
+        self.total_blocks = total_blocks.min(MAX_BLOCKS) as u16;
+        self.recount();
+    }
+
+    // work the free count out from scratch, for when the table has been set wholesale
+    fn recount(&mut self) {
+        self.free_count = (0..self.total_blocks)
+            .filter(|&block| !self.is_allocated(block))
+            .count() as u32;
+    }
+
+    // free blocks out of the first total_blocks, which can be fewer than the filesystem has
+    pub fn free_blocks(&self, total_blocks: usize) -> usize {
+        let past_end = (total_blocks..self.total_blocks as usize)
+            .filter(|&block| !self.is_allocated(block as u16))
+            .count();
+        self.free_count() - past_end
+    }
+
+    // every allocated block in order
+    pub fn iter_allocated(&self) -> impl Iterator<Item = u16> + '_ {
+        self.allocated_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte != 0)
+            .flat_map(|(i, &byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (1 << bit) != 0)
+                    .map(move |bit| (i * 8 + bit) as u16)
+            })
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free_count as usize
+    }
+
+    // forget every allocation, for building the table back up from scratch
+    pub fn clear_allocation(&mut self) {
+        self.dirty = true;
+        self.allocated_blocks = [0; ALLOCATION_TABLE_LEN];
+        self.next_free_hint = 0;
+        self.free_count = self.total_blocks as u32;
+    }
+
     pub fn mark_allocated(&mut self, block: u16) {
         self.dirty = true;
+        if block < self.total_blocks && !self.is_allocated(block) {
+            self.free_count -= 1;
+        }
         let byte = block / 8;
         let bit = block % 8;
         self.allocated_blocks[byte as usize] |= 1 << bit;
     }
 
+    // a shared block only loses a reference, it isn't actually free until the last one goes
     pub fn mark_free(&mut self, block: u16) {
+        // a hole in a file rather than a block, the superblock itself is never freed
+        if block == 0 {
+            return;
+        }
+        if self.is_shared(block) {
+            self.set_refcount(block, self.refcount(block) - 1);
+            return;
+        }
         self.dirty = true;
+        event!(trace, block, "freed block");
+        if block < self.total_blocks && self.is_allocated(block) {
+            self.free_count += 1;
+        }
         let byte = block / 8;
         let bit = block % 8;
         self.allocated_blocks[byte as usize] &= !(1 << bit);
     }
 
-    pub fn sync<const BLOCK_SIZE: usize>(&mut self, disk: &mut Disk<BLOCK_SIZE>) -> TfsResult<()> {
+    pub fn sync<const BLOCK_SIZE: usize>(
+        &mut self,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<()> {
         if self.dirty {
-            disk.write_block(
-                0,
-                bincode::serialize(&SuperBlockData::from(self.clone()))?
-                    .try_into()
-                    .unwrap(),
-            )?;
+            let mut data = SuperBlockData::from(self.clone());
+            data.checksum = data.compute_checksum()?;
+            let data: [u8; BLOCK_SIZE] = encode(&data)?.try_into().unwrap();
+            disk.write_block(0, data)?;
+            if self.backup_block != 0 {
+                disk.write_block(self.backup_block as usize, data)?;
+            }
             self.dirty = false;
+            event!(trace, "superblock written, no longer dirty");
+        }
+        if self.refcounts_dirty {
+            for (block, refcounts) in
+                (self.refcount_start..).zip(self.refcounts.chunks_exact(BLOCK_SIZE))
+            {
+                disk.write_block(block as usize, refcounts.try_into().unwrap())?;
+            }
+            self.refcounts_dirty = false;
         }
         Ok(())
     }
 }
 
+impl Default for SuperBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<SuperBlockData> for SuperBlock {
     fn from(
         SuperBlockData {
-            allocated_blocks, ..
+            state,
+            uuid,
+            label,
+            backup_block,
+            checksum_start,
+            checksum_blocks,
+            journal_start,
+            journal_blocks,
+            refcount_start,
+            refcount_blocks,
+            total_blocks,
+            allocated_blocks,
+            ..
         }: SuperBlockData,
     ) -> Self {
-        Self {
+        let label_len = label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+        let mut superblock = Self {
             dirty: false,
+            clean: state == STATE_CLEAN,
+            uuid,
+            label: String::from_utf8_lossy(&label[..label_len]).into_owned(),
+            backup_block,
+            checksum_start,
+            checksum_blocks,
+            journal_start,
+            journal_blocks,
+            refcount_start,
+            refcount_blocks,
+            refcounts: Vec::new(),
+            refcounts_dirty: false,
+            total_blocks: total_blocks.min(MAX_BLOCKS as u16),
             allocated_blocks,
-        }
+            next_free_hint: 0,
+            free_count: 0,
+        };
+        superblock.recount();
+        superblock
+    }
+}
+
+// a copy of the allocation table as it was when taken, and only the part of it the disk actually
+// covers. see Tfs::allocation
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    superblock: SuperBlock,
+    total_blocks: usize,
+}
+
+impl Allocation {
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks
+    }
+
+    pub fn is_allocated(&self, block: u16) -> bool {
+        (block as usize) < self.total_blocks && self.superblock.is_allocated(block)
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.superblock.free_blocks(self.total_blocks)
+    }
+
+    pub fn iter_allocated(&self) -> impl Iterator<Item = u16> + '_ {
+        self.superblock
+            .iter_allocated()
+            .take_while(|&block| (block as usize) < self.total_blocks)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Stat {
-    pub size: u16,
+    pub size: u64,
+    // the last change to the inode, data or metadata
     pub ctime: SystemTime,
     pub mtime: SystemTime,
     pub atime: SystemTime,
+    // when the file was created, this one never changes
+    pub btime: SystemTime,
+    // how many names the file goes by, see TfsFs::link
+    pub nlink: u16,
+    pub kind: FileKind,
+    // unix style rwx bits, only the write bits are enforced, see TfsFs::set_permissions
+    pub mode: u16,
+    // whoever created the file, see TfsFs::chown
+    pub uid: u16,
+    pub gid: u16,
+    pub flags: FileFlags,
+    // data blocks actually on disk, which is fewer than the size suggests for sparse files
+    pub blocks_allocated: usize,
 }
 
 impl Stat {
     pub fn new() -> Self {
+        Self::at(system_clock())
+    }
+
+    fn at(now: SystemTime) -> Self {
         Self {
+            kind: FileKind::Regular,
+            mode: DEFAULT_FILE_MODE,
+            uid: 0,
+            gid: 0,
+            flags: FileFlags::default(),
+            blocks_allocated: 0,
             size: 0,
-            ctime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            atime: SystemTime::now(),
+            ctime: now,
+            mtime: now,
+            atime: now,
+            btime: now,
+            nlink: 1,
         }
     }
 }
 
+impl Default for Stat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<StatData> for Stat {
     fn from(
         StatData {
@@ -136,420 +623,7326 @@ impl From<StatData> for Stat {
             ctime,
             mtime,
             atime,
+            btime,
+            nlink,
+            mode,
+            uid,
+            gid,
         }: StatData,
     ) -> Self {
         Self {
-            size,
-            ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(ctime as u64),
-            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(mtime as u64),
-            atime: SystemTime::UNIX_EPOCH + Duration::from_secs(atime as u64),
+            size: size as u64,
+            ctime: SystemTime::UNIX_EPOCH + Duration::from_nanos(ctime),
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_nanos(mtime),
+            atime: SystemTime::UNIX_EPOCH + Duration::from_nanos(atime),
+            btime: SystemTime::UNIX_EPOCH + Duration::from_nanos(btime),
+            nlink,
+            mode,
+            uid,
+            gid,
+            // not part of StatData, the inode fills these in
+            kind: FileKind::Regular,
+            flags: FileFlags::default(),
+            blocks_allocated: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Regular = 0,
+    // a directory's data blocks hold its entries, see RootData
+    Directory = 1,
+    Symlink = 2,
+    // anything else is kept for kinds to come
+}
+
+impl TryFrom<u8> for FileKind {
+    type Error = TfsError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(FileKind::Regular),
+            1 => Ok(FileKind::Directory),
+            2 => Ok(FileKind::Symlink),
+            kind => Err(TfsError::InvalidKind(kind)),
+        }
+    }
+}
+
+// unlike the mode these hold for everyone, see TfsFs::set_flags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileFlags {
+    // no writing, truncating, renaming or removing at all
+    pub immutable: bool,
+    // writes always go on the end, and nothing already written can be changed
+    pub append_only: bool,
+}
+
+impl From<u8> for FileFlags {
+    fn from(flags: u8) -> Self {
+        Self {
+            immutable: flags & FLAG_IMMUTABLE != 0,
+            append_only: flags & FLAG_APPEND_ONLY != 0,
+        }
+    }
+}
+
+impl From<FileFlags> for u8 {
+    fn from(flags: FileFlags) -> Self {
+        let mut bits = 0;
+        if flags.immutable {
+            bits |= FLAG_IMMUTABLE;
+        }
+        if flags.append_only {
+            bits |= FLAG_APPEND_ONLY;
         }
+        bits
     }
 }
 
 #[derive(Debug, Clone)]
 struct INode {
     block: u16,
+    // tells this inode apart from whatever had its block before, see Root::next_generation
+    generation: u32,
     dirty: bool,
-    filename: String,
     stat: Stat,
+    // the blocks past the end of the file were reserved on purpose, rather than being left behind
+    preallocated: bool,
+    // every data block in order, including the ones only reachable through indirect blocks
     blocks: Vec<u16>,
+    indirect: Vec<u16>,
+    // a directory's entries, which fill its blocks in order. empty for anything else
+    children: Vec<DirEntry>,
 }
 
 impl INode {
-    pub fn new(block: u16, filename: String) -> Self {
+    pub fn new(block: u16, kind: FileKind, now: SystemTime) -> Self {
         Self {
             block,
+            generation: 0,
             dirty: true,
-            filename,
-            stat: Stat::new(),
+            stat: Stat {
+                kind,
+                mode: if kind == FileKind::Directory {
+                    DEFAULT_DIR_MODE
+                } else {
+                    DEFAULT_FILE_MODE
+                },
+                ..Stat::at(now)
+            },
+            preallocated: false,
             blocks: Vec::new(),
+            indirect: Vec::new(),
+            children: Vec::new(),
         }
     }
 
-    pub fn from_block<const BLOCK_SIZE: usize>(
+    pub fn is_dir(&self) -> bool {
+        self.stat.kind == FileKind::Directory
+    }
+
+    // reads the indirect blocks if it has any, and a directory's entries
+    pub fn from_data<const BLOCK_SIZE: usize>(
         block: u16,
-        disk: &mut Disk<BLOCK_SIZE>,
-    ) -> TfsResult<Self> {
-        let data = disk.read_block(block as usize)?;
-        let INodeData {
-            filename,
+        INodeData {
+            kind,
+            flags,
             stat,
+            generation,
             blocks,
-        }: INodeData = bincode::deserialize(&data)?;
-
-        let filename_len = filename.iter().position(|&b| b == 0);
-        let filename = if let Some(filename_len) = filename_len {
-            &filename[..filename_len]
-        } else {
-            &filename
-        };
+            indirect,
+            ..
+        }: INodeData,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<Self> {
+        let indirect: Vec<u16> = indirect.into_iter().filter(|b| *b != 0).collect();
+        let mut blocks = blocks.to_vec();
+        for &block in &indirect {
+            let data: IndirectData = decode(&disk.read_block(block as usize)?)?;
+            blocks.extend(data.blocks);
+        }
+        // zeros before the last block are holes, the last block is always allocated so anything
+        // after it is just unused space
+        let len = blocks
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |last| last + 1);
+        blocks.truncate(len);
+        let kind = kind.try_into()?;
+        let mut children = Vec::new();
+        if kind == FileKind::Directory {
+            for &block in &blocks {
+                children.extend(stored_entries(&decode(&disk.read_block(block as usize)?)?)?);
+            }
+        }
 
         Ok(Self {
             block,
+            generation,
             dirty: false,
-            filename: CString::new(filename)?.into_string().unwrap(),
-            stat: stat.into(),
-            blocks: blocks.iter().filter(|b| **b != 0).copied().collect(),
+            stat: Stat {
+                kind,
+                flags: flags.into(),
+                ..stat.into()
+            },
+            preallocated: flags & FLAG_PREALLOCATED != 0,
+            blocks,
+            indirect,
+            children,
         })
     }
 
+    // the metadata changed, which is what ctime keeps track of
+    pub fn touch(&mut self, now: SystemTime) {
+        self.dirty = true;
+        self.stat.ctime = now;
+    }
+
+    // the contents changed, which counts as a metadata change as well
+    pub fn modified(&mut self, now: SystemTime) {
+        self.touch(now);
+        self.stat.mtime = self.stat.ctime;
+    }
+
+    // with the fields that aren't kept on disk filled in
+    pub fn stat(&self) -> Stat {
+        Stat {
+            blocks_allocated: self.blocks.iter().filter(|&&block| block != 0).count(),
+            ..self.stat.clone()
+        }
+    }
+
     pub fn push_block(&mut self, block: u16) {
         self.dirty = true;
         self.blocks.push(block);
     }
 
-    pub fn sync(&mut self, disk: &mut Disk<BLOCK_SIZE>) -> TfsResult<()> {
-        if self.dirty {
-            disk.write_block(
-                self.block as usize,
-                bincode::serialize(&INodeData::from(self.clone()))?
-                    .try_into()
-                    .unwrap(),
-            )?;
-            self.dirty = false;
+    fn indirect_needed(blocks: usize) -> usize {
+        blocks
+            .saturating_sub(INODE_BLOCKS)
+            .div_ceil(INDIRECT_BLOCK_LEN)
+    }
+
+    // make room for another entry in the block list, allocating an indirect block if needed
+    fn reserve_slot(&mut self, superblock: &mut SuperBlock) -> TfsResult<()> {
+        // the inode can only address so many blocks
+        if self.blocks.len() >= MAX_FILE_BLOCKS {
+            return Err(TfsError::OutOfSpace);
+        }
+        if Self::indirect_needed(self.blocks.len() + 1) > self.indirect.len() {
+            let indirect = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+            self.indirect.push(indirect);
         }
         Ok(())
     }
-}
 
-#[derive(Debug, Clone)]
-struct Root {
-    dirty: bool,
-    inodes: Vec<INode>,
-}
+    // allocate another data block
+    fn grow(&mut self, superblock: &mut SuperBlock) -> TfsResult<u16> {
+        self.reserve_slot(superblock)?;
+        let Some(block) = superblock.allocate_block() else {
+            self.release_indirect(superblock);
+            return Err(TfsError::OutOfSpace);
+        };
+        self.push_block(block);
+        Ok(block)
+    }
 
-impl Root {
-    pub fn new() -> Self {
-        Self {
-            dirty: true,
-            inodes: Vec::new(),
+    // drop every block from index len on, holes included
+    fn shrink_blocks(&mut self, superblock: &mut SuperBlock, len: usize) {
+        for block in self.blocks.drain(len..) {
+            superblock.mark_free(block);
         }
+        self.release_indirect(superblock);
     }
 
-    pub fn from_data<const DISK_SIZE: usize>(
-        data: RootData,
-        disk: &mut Disk<DISK_SIZE>,
-    ) -> TfsResult<Self> {
-        let mut inodes = Vec::new();
-        for block in data.inodes.into_iter().filter(|b| *b != 0) {
-            inodes.push(INode::from_block(block, disk)?);
+    // free any indirect blocks that no longer point at anything
+    fn release_indirect(&mut self, superblock: &mut SuperBlock) {
+        let needed = Self::indirect_needed(self.blocks.len());
+        if needed < self.indirect.len() {
+            self.dirty = true;
+            for block in self.indirect.drain(needed..) {
+                superblock.mark_free(block);
+            }
         }
-        Ok(Self {
-            dirty: false,
-            inodes,
-        })
     }
 
-    pub fn create_inode(&mut self, block: u16, filename: String) -> usize {
+    // writes to a block shared with a clone go to a fresh copy of it instead, which then takes its
+    // place in this file
+    fn overwrite(
+        &mut self,
+        superblock: &mut SuperBlock,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+        index: usize,
+        data: [u8; BLOCK_SIZE],
+    ) -> TfsResult<()> {
+        let block = self.blocks[index];
+        if !superblock.is_shared(block) {
+            return Ok(disk.write_block(block as usize, data)?);
+        }
+        let copy = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+        if let Err(err) = disk.write_block(copy as usize, data) {
+            superblock.mark_free(copy);
+            return Err(err.into());
+        }
+        superblock.mark_free(block);
+        self.blocks[index] = copy;
         self.dirty = true;
-        self.inodes.push(INode::new(block, filename));
-        self.inodes.len() - 1
+        Ok(())
     }
 
-    pub fn sync(&mut self, disk: &mut Disk<BLOCK_SIZE>) -> TfsResult<()> {
-        for inode in self.inodes.iter_mut() {
-            inode.sync(disk)?;
+    // whole blocks on the end of the file, with each run of them that ends up next to each other on
+    // disk written in one go. returns how many blocks were written, only erroring if none were
+    fn append_blocks(
+        &mut self,
+        superblock: &mut SuperBlock,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+        data: &[u8],
+    ) -> TfsResult<usize> {
+        let len = self.blocks.len();
+        let count = data.len() / BLOCK_SIZE;
+        let mut error = None;
+        // all in one place if there's room, otherwise wherever they fit
+        match superblock.allocate_run(count) {
+            Some(start) => {
+                for block in start..start + count as u16 {
+                    if let Err(err) = self.reserve_slot(superblock) {
+                        (block..start + count as u16).for_each(|block| superblock.mark_free(block));
+                        error = Some(err);
+                        break;
+                    }
+                    self.push_block(block);
+                }
+            }
+            None => {
+                for _ in 0..count {
+                    if let Err(err) = self.grow(superblock) {
+                        error = Some(err);
+                        break;
+                    }
+                }
+            }
+        }
+        let blocks = self.blocks[len..].to_vec();
+        let mut written = 0;
+        for run in blocks.chunk_by(|a, b| *b == a + 1) {
+            let bytes = &data[written * BLOCK_SIZE..(written + run.len()) * BLOCK_SIZE];
+            if let Err(err) = disk.write_blocks(run[0] as usize, bytes) {
+                error = Some(err.into());
+                break;
+            }
+            written += run.len();
+        }
+        self.shrink_blocks(superblock, len + written);
+        match error {
+            Some(err) if written == 0 => Err(err),
+            _ => Ok(written),
+        }
+    }
+
+    // write bytes (which must fit in a single block) at offset. only the block being written gets
+    // allocated, anything skipped over on the way there is left as a hole
+    fn write_chunk(
+        &mut self,
+        superblock: &mut SuperBlock,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+        offset: usize,
+        bytes: &[u8],
+    ) -> TfsResult<()> {
+        let index = offset / BLOCK_SIZE;
+        let start = offset % BLOCK_SIZE;
+        // what goes in a block that doesn't exist yet
+        let mut fresh = [0; BLOCK_SIZE];
+        fresh[start..start + bytes.len()].copy_from_slice(bytes);
+        match self.blocks.get(index) {
+            Some(0) => {
+                let block = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+                if let Err(err) = disk.write_block(block as usize, fresh) {
+                    superblock.mark_free(block);
+                    return Err(err.into());
+                }
+                self.blocks[index] = block;
+                self.dirty = true;
+                Ok(())
+            }
+            Some(&block) => {
+                let mut data = if bytes.len() == BLOCK_SIZE {
+                    [0; BLOCK_SIZE]
+                } else {
+                    disk.read_block(block as usize)?
+                };
+                data[start..start + bytes.len()].copy_from_slice(bytes);
+                self.overwrite(superblock, disk, index, data)
+            }
+            None => {
+                let len = self.blocks.len();
+                let result = (len..index)
+                    .try_for_each(|_| {
+                        self.reserve_slot(superblock)?;
+                        self.push_block(0);
+                        Ok(())
+                    })
+                    .and_then(|()| self.grow(superblock))
+                    .and_then(|block| Ok(disk.write_block(block as usize, fresh)?));
+                if let Err(err) = result {
+                    self.shrink_blocks(superblock, len);
+                    return Err(err);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn truncate(
+        &mut self,
+        superblock: &mut SuperBlock,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+        len: usize,
+        now: SystemTime,
+    ) -> TfsResult<()> {
+        let blocks = len.div_ceil(BLOCK_SIZE);
+        if blocks > MAX_FILE_BLOCKS {
+            return Err(TfsError::OutOfSpace);
+        }
+        self.dirty = true;
+        // whatever can fail is done to the new last block before the tail goes, so that failing
+        // leaves the file as it was
+        let end = len % BLOCK_SIZE;
+        if blocks < self.blocks.len() && blocks > 0 && self.blocks[blocks - 1] == 0 {
+            // it has to be a real block, even if it's all zeros
+            let block = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+            if let Err(err) = disk.write_block(block as usize, [0; BLOCK_SIZE]) {
+                superblock.mark_free(block);
+                return Err(err.into());
+            }
+            self.blocks[blocks - 1] = block;
+        } else if end != 0 && len < self.stat.size as usize {
+            // keep everything past the end of the file zeroed so later extensions read back as
+            // zeros. a block shared with a clone needs a free one to copy into
+            let mut data = disk.read_block(self.blocks[blocks - 1] as usize)?;
+            data[end..].fill(0);
+            self.overwrite(superblock, disk, blocks - 1, data)?;
+        }
+        if blocks < self.blocks.len() {
+            self.shrink_blocks(superblock, blocks);
+        }
+        let allocated = self.blocks.len();
+        while self.blocks.len() < blocks {
+            let result = self
+                .grow(superblock)
+                .and_then(|block| Ok(disk.write_block(block as usize, [0; BLOCK_SIZE])?));
+            if let Err(err) = result {
+                // don't leave the file partially extended
+                for block in self.blocks.drain(allocated..) {
+                    superblock.mark_free(block);
+                }
+                self.release_indirect(superblock);
+                return Err(err);
+            }
+        }
+        self.stat.size = len as u64;
+        self.modified(now);
+        Ok(())
+    }
+
+    // zeroed blocks past the end of the file, which write_chunk then writes into like any other
+    fn preallocate(
+        &mut self,
+        superblock: &mut SuperBlock,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+        len: usize,
+    ) -> TfsResult<()> {
+        let blocks = len.div_ceil(BLOCK_SIZE);
+        if blocks > MAX_FILE_BLOCKS {
+            return Err(TfsError::OutOfSpace);
+        }
+        let allocated = self.blocks.len();
+        while self.blocks.len() < blocks {
+            let result = self
+                .grow(superblock)
+                .and_then(|block| Ok(disk.write_block(block as usize, [0; BLOCK_SIZE])?));
+            if let Err(err) = result {
+                for block in self.blocks.drain(allocated..) {
+                    superblock.mark_free(block);
+                }
+                self.release_indirect(superblock);
+                return Err(err);
+            }
         }
+        self.preallocated |= self.blocks.len() > allocated;
+        Ok(())
+    }
+
+    pub fn sync(
+        &mut self,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<()> {
         if self.dirty {
+            let indirect_blocks = self.blocks.get(INODE_BLOCKS..).unwrap_or(&[]);
+            for (&indirect, blocks) in self
+                .indirect
+                .iter()
+                .zip(indirect_blocks.chunks(INDIRECT_BLOCK_LEN))
+            {
+                let mut data = IndirectData::new();
+                data.blocks[..blocks.len()].copy_from_slice(blocks);
+                disk.write_block(indirect as usize, encode(&data)?.try_into().unwrap())?;
+            }
+            if self.is_dir() {
+                let mut entries = self.children.chunks(ROOT_ENTRIES);
+                for &block in &self.blocks {
+                    let data = RootData::with_entries(entries.next().unwrap_or(&[]), 0)?;
+                    disk.write_block(block as usize, encode(&data)?.try_into().unwrap())?;
+                }
+            }
             disk.write_block(
-                1,
-                bincode::serialize(&RootData::try_from(self.clone())?)?
-                    .try_into()
-                    .unwrap(),
+                self.block as usize,
+                encode(&INodeData::from(self.clone()))?.try_into().unwrap(),
             )?;
             self.dirty = false;
+            event!(trace, block = self.block, "inode written, no longer dirty");
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ReadDirEntry {
-    pub filename: String,
-    pub stat: Stat,
+// a name in a directory. a regular file can have any number of them, each as good as the others,
+// see TfsFs::link
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirEntry {
+    filename: String,
+    // the block of the inode it names
+    inode: u16,
 }
 
-#[derive(Debug)]
-pub struct TfsFsFile {
-    inode: usize,
-    offset: usize,
+// filenames are nul terminated, unless they fill the whole field
+fn stored_filename(filename: &[u8]) -> TfsResult<String> {
+    let filename_len = filename.iter().position(|&b| b == 0);
+    let filename = if let Some(filename_len) = filename_len {
+        &filename[..filename_len]
+    } else {
+        filename
+    };
+    Ok(CString::new(filename)?.into_string().unwrap())
 }
 
-#[derive(Debug)]
-pub struct TfsFile<'a> {
-    filesystem: &'a RefCell<TfsFs>,
-    file: TfsFsFile,
+// the slots in use in a block of directory entries
+fn stored_entries(data: &RootData) -> TfsResult<Vec<DirEntry>> {
+    data.entries
+        .iter()
+        .filter(|entry| entry.inode != 0)
+        .map(|entry| {
+            Ok(DirEntry {
+                filename: stored_filename(&entry.filename)?,
+                inode: entry.inode,
+            })
+        })
+        .collect()
 }
 
-impl<'a> TfsFile<'a> {
-    pub fn write(&mut self, buf: &[u8]) -> TfsResult<()> {
-        self.filesystem.borrow_mut().write(&mut self.file, buf)
-    }
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
 
-    pub fn read_byte(&mut self) -> TfsResult<Option<u8>> {
-        self.filesystem.borrow_mut().read_byte(&mut self.file)
+// paths are stored as nul terminated utf-8
+fn path_str(path: &Path) -> TfsResult<&str> {
+    match path.to_str() {
+        Some(path) if !path.contains('\0') => Ok(path),
+        _ => Err(TfsError::InvalidFilename(
+            path.to_string_lossy().into_owned(),
+        )),
     }
+}
 
-    pub fn rename(&mut self, newname: &str) -> TfsResult<()> {
-        self.filesystem.borrow_mut().rename(&mut self.file, newname)
+// holes read back as zeros
+fn read_data(
+    disk: &Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    block: u16,
+) -> TfsResult<[u8; BLOCK_SIZE]> {
+    if block == 0 {
+        return Ok([0; BLOCK_SIZE]);
     }
+    Ok(disk.read_block(block as usize)?)
+}
 
-    pub fn stat(&self, file: TfsFsFile) -> TfsResult<Stat> {
-        self.filesystem.borrow_mut().stat(file)
+// the effective ids of this process, or 0 where there are none or they don't fit
+fn process_owner() -> (u16, u16) {
+    #[cfg(unix)]
+    {
+        // safe, neither can fail or touches any memory of ours
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        (uid.try_into().unwrap_or(0), gid.try_into().unwrap_or(0))
     }
+    #[cfg(not(unix))]
+    (0, 0)
 }
 
+// where timestamps come from, see MountOptions::clock
+pub type Clock = fn() -> SystemTime;
+
+// wasm32-unknown-unknown has no clock to ask, SystemTime::now panics there, so without one being
+// given everything is stamped with the epoch
+fn system_clock() -> SystemTime {
+    if cfg!(all(target_family = "wasm", target_os = "unknown")) {
+        SystemTime::UNIX_EPOCH
+    } else {
+        SystemTime::now()
+    }
+}
+
+// a random version 4 uuid, the std hasher is randomly keyed which saves pulling in a rng
+fn generate_uuid(now: SystemTime) -> [u8; UUID_LEN] {
+    let mut uuid = [0; UUID_LEN];
+    for chunk in uuid.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            now.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+// names have to fit in the fixed size filename field of a directory entry
+fn check_filename(filename: &str) -> TfsResult<()> {
+    if filename.contains('\0') {
+        return Err(TfsError::InvalidFilename(filename.to_string()));
+    }
+    if filename.len() > MAX_FILENAME_LEN {
+        return Err(TfsError::FilenameTooLong {
+            name: filename.to_string(),
+            max: MAX_FILENAME_LEN,
+        });
+    }
+    Ok(())
+}
+
+// directories are referred to by their index in Root::inodes, with None standing in for the root
+// directory itself
+type Dir = Option<usize>;
+
+#[derive(Debug, Clone)]
+struct Root {
+    dirty: bool,
+    // the top level entries
+    entries: Vec<DirEntry>,
+    // blocks the entries spill over into once the first root block is full
+    chain: Vec<u16>,
+    // every inode in the filesystem, whichever directory it's in
+    inodes: Vec<INode>,
+    // given to the next inode created, so a handle to a removed file can't end up reading
+    // whatever was made in its block after it
+    next_generation: u32,
+}
+
+impl Root {
+    pub fn new() -> Self {
+        Self {
+            dirty: true,
+            entries: Vec::new(),
+            chain: Vec::new(),
+            inodes: Vec::new(),
+            next_generation: 1,
+        }
+    }
+
+    pub fn from_data<const DISK_SIZE: usize>(
+        data: RootData,
+        superblock: &SuperBlock,
+        disk: &mut Disk<DISK_SIZE, impl BlockDevice<DISK_SIZE>>,
+    ) -> TfsResult<Self> {
+        let mut data = data;
+        let mut entries = Vec::new();
+        let mut chain: Vec<u16> = Vec::new();
+        loop {
+            entries.extend(stored_entries(&data)?);
+            if data.next == 0 || chain.contains(&data.next) {
+                break;
+            }
+            chain.push(data.next);
+            data = decode(&disk.read_block(data.next as usize)?)?;
+        }
+        let mut inodes: Vec<INode> = Vec::new();
+        let mut pending: VecDeque<u16> = entries.iter().map(|entry| entry.inode).collect();
+        while let Some(block) = pending.pop_front() {
+            // once for each name a file has
+            if inodes.iter().any(|inode| inode.block == block) {
+                continue;
+            }
+            let inode = INode::from_data(block, decode(&disk.read_block(block as usize)?)?, disk)?;
+            pending.extend(inode.children.iter().map(|entry| entry.inode));
+            inodes.push(inode);
+        }
+        // writing through a block that belongs to two inodes would silently corrupt one of them,
+        // unless it's a clone's data block that gets copied on write
+        let mut owners: HashMap<u16, u16> = HashMap::new();
+        for inode in &inodes {
+            let data = inode
+                .blocks
+                .iter()
+                .filter(|&&block| block != 0 && !superblock.is_shared(block));
+            let used = [inode.block].into_iter().chain(data.copied());
+            for block in used.chain(inode.indirect.iter().copied()) {
+                if let Some(&owner) = owners.get(&block) {
+                    return Err(TfsError::CrossLinkedBlock {
+                        block,
+                        inodes: vec![owner, inode.block],
+                    });
+                }
+                owners.insert(block, inode.block);
+            }
+        }
+        // handles don't outlive the mount, so it only has to be past anything still around
+        let next_generation = inodes
+            .iter()
+            .map(|inode| inode.generation)
+            .max()
+            .unwrap_or(0)
+            .wrapping_add(1);
+        Ok(Self {
+            dirty: false,
+            entries,
+            chain,
+            inodes,
+            next_generation,
+        })
+    }
+
+    pub fn index_of(&self, block: u16) -> Option<usize> {
+        self.inodes.iter().position(|inode| inode.block == block)
+    }
+
+    pub fn dir_entry(&self, entry: &DirEntry) -> Option<ReadDirEntry> {
+        let inode = &self.inodes[self.index_of(entry.inode)?];
+        Some(ReadDirEntry::new(entry.filename.clone(), inode))
+    }
+
+    pub fn children(&self, dir: Dir) -> &[DirEntry] {
+        match dir {
+            Some(dir) => &self.inodes[dir].children,
+            None => &self.entries,
+        }
+    }
+
+    // for changing them, so they get written out with the next sync
+    fn children_mut(&mut self, dir: Dir) -> &mut Vec<DirEntry> {
+        match dir {
+            Some(dir) => {
+                let dir = &mut self.inodes[dir];
+                dir.dirty = true;
+                &mut dir.children
+            }
+            None => {
+                self.dirty = true;
+                &mut self.entries
+            }
+        }
+    }
+
+    pub fn find_child(&self, dir: Dir, filename: &str) -> Option<usize> {
+        self.children(dir)
+            .iter()
+            .find(|entry| entry.filename == filename)
+            .and_then(|entry| self.index_of(entry.inode))
+    }
+
+    // the first directory with a name for the inode in it
+    pub fn parent_of(&self, inode: usize) -> Dir {
+        let block = self.inodes[inode].block;
+        self.inodes
+            .iter()
+            .position(|dir| dir.children.iter().any(|entry| entry.inode == block))
+    }
+
+    // the full path of an inode, the way find takes them. a file with more than one name goes by
+    // the first one in its parent
+    pub fn path_of(&self, inode: usize) -> String {
+        let mut path = self.name_of(inode).to_string();
+        let mut dir = self.parent_of(inode);
+        while let Some(index) = dir {
+            path = format!("{}/{path}", self.name_of(index));
+            dir = self.parent_of(index);
+        }
+        path
+    }
+
+    fn name_of(&self, inode: usize) -> &str {
+        let block = self.inodes[inode].block;
+        self.children(self.parent_of(inode))
+            .iter()
+            .find(|entry| entry.inode == block)
+            .map_or("", |entry| &entry.filename)
+    }
+
+    // the directory a path refers to
+    pub fn resolve_dir(&self, path: &str) -> TfsResult<Dir> {
+        let mut dir = None;
+        for component in components(path) {
+            let inode = self
+                .find_child(dir, component)
+                .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+            if !self.inodes[inode].is_dir() {
+                return Err(TfsError::NotADirectory(path.to_string()));
+            }
+            dir = Some(inode);
+        }
+        Ok(dir)
+    }
+
+    // the directory a path is in, and the name of the last component
+    pub fn resolve_parent<'p>(&self, path: &'p str) -> TfsResult<(Dir, &'p str)> {
+        let (parent, filename) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+        if filename.is_empty() {
+            return Err(TfsError::FileNotFound(path.to_string()));
+        }
+        Ok((self.resolve_dir(parent)?, filename))
+    }
+
+    pub fn find(&self, path: &str) -> Option<usize> {
+        let (dir, filename) = self.resolve_parent(path).ok()?;
+        self.find_child(dir, filename)
+    }
+
+    pub fn create_inode(
+        &mut self,
+        dir: Dir,
+        block: u16,
+        filename: String,
+        kind: FileKind,
+        now: SystemTime,
+    ) -> usize {
+        self.add_child(
+            dir,
+            DirEntry {
+                filename,
+                inode: block,
+            },
+        );
+        let mut inode = INode::new(block, kind, now);
+        inode.generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1);
+        self.inodes.push(inode);
+        self.inodes.len() - 1
+    }
+
+    fn add_child(&mut self, dir: Dir, entry: DirEntry) {
+        self.children_mut(dir).push(entry);
+    }
+
+    fn remove_child(&mut self, dir: Dir, filename: &str) -> Option<DirEntry> {
+        let children = self.children_mut(dir);
+        let index = children
+            .iter()
+            .position(|entry| entry.filename == filename)?;
+        Some(children.remove(index))
+    }
+
+    fn chain_needed(entries: usize) -> usize {
+        entries.saturating_sub(1) / ROOT_ENTRIES
+    }
+
+    // make sure dir has room for one more entry, giving it another block if needed
+    pub fn reserve(&mut self, dir: Dir, superblock: &mut SuperBlock) -> TfsResult<()> {
+        match dir {
+            Some(dir) if self.inodes[dir].children.len() >= MAX_DIR_ENTRIES => {
+                Err(TfsError::DirectoryFull)
+            }
+            Some(dir)
+                if (self.inodes[dir].children.len() + 1).div_ceil(ROOT_ENTRIES)
+                    > self.inodes[dir].blocks.len() =>
+            {
+                let block = superblock.allocate_block().ok_or(TfsError::DirectoryFull)?;
+                self.inodes[dir].push_block(block);
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None if Self::chain_needed(self.entries.len() + 1) > self.chain.len() => {
+                let block = superblock.allocate_block().ok_or(TfsError::DirectoryFull)?;
+                self.dirty = true;
+                self.chain.push(block);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // free any directory blocks that no longer hold entries
+    pub fn release(&mut self, superblock: &mut SuperBlock) {
+        let needed = Self::chain_needed(self.entries.len());
+        if needed < self.chain.len() {
+            self.dirty = true;
+            for block in self.chain.drain(needed..) {
+                superblock.mark_free(block);
+            }
+        }
+        for dir in self.inodes.iter_mut().filter(|inode| inode.is_dir()) {
+            let needed = dir.children.len().div_ceil(ROOT_ENTRIES);
+            if needed < dir.blocks.len() {
+                dir.dirty = true;
+                dir.shrink_blocks(superblock, needed);
+            }
+        }
+    }
+
+    pub fn sync(
+        &mut self,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<()> {
+        for inode in self.inodes.iter_mut() {
+            inode.sync(disk)?;
+        }
+        self.sync_table(disk)
+    }
+
+    // what still needs writing, so a sync that doesn't make it can put it all back
+    fn dirty_flags(&self) -> Vec<bool> {
+        let inodes = self.inodes.iter().map(|inode| inode.dirty);
+        inodes.chain([self.dirty]).collect()
+    }
+
+    fn restore_dirty(&mut self, flags: &[bool]) {
+        let mut flags = flags.iter().copied();
+        for inode in self.inodes.iter_mut() {
+            inode.dirty |= flags.next().unwrap();
+        }
+        self.dirty |= flags.next().unwrap();
+    }
+
+    // sync only the top level entries, not the inodes themselves
+    pub fn sync_table(
+        &mut self,
+        disk: &mut Disk<BLOCK_SIZE, impl BlockDevice<BLOCK_SIZE>>,
+    ) -> TfsResult<()> {
+        if self.dirty {
+            let blocks = [1].into_iter().chain(self.chain.iter().copied());
+            let nexts = self.chain.iter().copied().chain([0]);
+            let mut chunks = self.entries.chunks(ROOT_ENTRIES);
+            for (block, next) in blocks.zip(nexts) {
+                let data = RootData::with_entries(chunks.next().unwrap_or(&[]), next)?;
+                disk.write_block(block as usize, encode(&data)?.try_into().unwrap())?;
+            }
+            self.dirty = false;
+            event!(
+                trace,
+                entries = self.entries.len(),
+                "root table written, no longer dirty"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsStats {
+    pub block_size: usize,
+    pub total_blocks: usize,
+    pub free_blocks: usize,
+    pub used_blocks: usize,
+    pub files: usize,
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadDirEntry {
+    pub filename: String,
+    pub kind: FileKind,
+    pub stat: Stat,
+    pub inode_block: u16,
+    pub blocks: usize,
+}
+
+impl ReadDirEntry {
+    fn new(filename: String, inode: &INode) -> Self {
+        Self {
+            filename,
+            kind: inode.stat.kind,
+            stat: inode.stat(),
+            inode_block: inode.block,
+            blocks: inode.blocks.len(),
+        }
+    }
+}
+
+// only locks the filesystem for each entry, so it's fine to change things while going through it.
+// like readdir, whether a change partway shows up or not depends on where it lands
 #[derive(Debug)]
-pub struct Tfs {
-    tfs: RefCell<TfsFs>,
+pub struct ReadDir<'a, D: BlockDevice<BLOCK_SIZE> = File> {
+    tfs: &'a RwLock<TfsFs<D>>,
+    // by inode block, since its index can change in between. None for the top level
+    dir: Option<u16>,
+    index: usize,
 }
 
-impl Tfs {
-    pub fn new(disk: Disk<BLOCK_SIZE>) -> Self {
+impl<D: BlockDevice<BLOCK_SIZE>> ReadDir<'_, D> {
+    // the directory's children, or none at all once it's been removed
+    fn children<'b>(&self, tfs: &'b TfsFs<D>) -> &'b [DirEntry] {
+        match self.dir {
+            Some(block) => match tfs.root.index_of(block) {
+                Some(dir) if tfs.root.inodes[dir].is_dir() => tfs.root.children(Some(dir)),
+                _ => &[],
+            },
+            None => tfs.root.children(None),
+        }
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Iterator for ReadDir<'_, D> {
+    type Item = ReadDirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tfs = lock_read(self.tfs);
+        loop {
+            let entry = self.children(&tfs).get(self.index)?;
+            self.index += 1;
+            if let Some(entry) = tfs.dir_entry(entry) {
+                return Some(entry);
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self
+            .children(&lock_read(self.tfs))
+            .len()
+            .saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
+}
+
+// a raw handle can't reach the filesystem it came from, so dropping one can't close it. it has to
+// be given back to TfsFs::close, or the file stays open and can't be removed until unmount.
+// TfsFile does that itself when dropped. try_clone is the only way to copy one, so each copy is
+// counted as open
+#[derive(Debug)]
+pub struct TfsFsFile {
+    // tells handles apart for locking, try_clone gives out a new one
+    id: u64,
+    inode: usize,
+    // identifies the inode in case its index changes or it goes away
+    block: u16,
+    // and in case the block has been given to another inode since
+    generation: u32,
+    offset: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    // the last block read_byte read, so it doesn't go to the disk for every byte
+    cache: Option<CachedBlock>,
+}
+
+// advisory locks, keyed by inode block and held by handle ids
+#[derive(Debug)]
+enum FileLock {
+    Shared(Vec<u64>),
+    Exclusive(u64),
+}
+
+// shared with handles waiting on a lock, so they can wait without holding the filesystem
+#[derive(Debug, Default)]
+struct LockTable {
+    held: Mutex<HashMap<u16, FileLock>>,
+    // signalled whenever a lock is let go or changes hands
+    changed: Condvar,
+}
+
+#[derive(Debug, Clone)]
+struct CachedBlock {
+    block: u16,
+    // the filesystem's data epoch when the block was read, anything older is stale
+    epoch: u64,
+    data: [u8; BLOCK_SIZE],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TfsOpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl TfsOpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    // every write goes to the end of the file, implies write
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    // fail with AlreadyExists if the file is present, implies create
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn open<'a, D: BlockDevice<BLOCK_SIZE>>(
+        &self,
+        tfs: &'a Tfs<D>,
+        filename: impl AsRef<Path>,
+    ) -> TfsResult<TfsFile<'a, D>> {
+        tfs.open_with(filename, self)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    read_only: bool,
+    shared: bool,
+    noatime: bool,
+    sync_writes: bool,
+    sync_policy: SyncPolicy,
+    cache_blocks: Option<usize>,
+    clock: Option<Clock>,
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // implies noatime, since there's nowhere to write it
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // mount read only without locking the image, so it can be looked at while it's mounted
+    // elsewhere. what's read may be out of date or half written
+    pub fn shared(&mut self, shared: bool) -> &mut Self {
+        self.shared = shared;
+        self
+    }
+
+    // leave atime alone on open and read
+    pub fn noatime(&mut self, noatime: bool) -> &mut Self {
+        self.noatime = noatime;
+        self
+    }
+
+    pub fn sync_writes(&mut self, sync_writes: bool) -> &mut Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    // keep this many recently used blocks in memory
+    pub fn cache_blocks(&mut self, cache_blocks: usize) -> &mut Self {
+        self.cache_blocks = Some(cache_blocks);
+        self
+    }
+
+    // stamp files with this rather than the system time, e.g. with Date.now from inside a browser
+    pub fn clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn mount(&self, path: impl AsRef<Path>) -> TfsResult<Tfs> {
+        Tfs::mount_with(path, self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only || self.shared
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    // generated at mkfs if not given
+    uuid: Option<[u8; UUID_LEN]>,
+    label: String,
+    size: usize,
+    checksums: bool,
+    journal_blocks: usize,
+    reflinks: bool,
+    zero_fill: bool,
+    force: bool,
+    // created in the root directory, in order
+    files: Vec<(String, Vec<u8>)>,
+    clock: Option<Clock>,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
         Self {
-            tfs: RefCell::new(TfsFs::new(disk)),
+            uuid: None,
+            label: String::new(),
+            size: DEFAULT_DISK_SIZE,
+            checksums: false,
+            journal_blocks: 0,
+            reflinks: false,
+            zero_fill: false,
+            force: false,
+            files: Vec::new(),
+            clock: None,
+        }
+    }
+}
+
+impl MkfsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // for reproducible images, otherwise every mkfs gets a fresh one
+    pub fn uuid(&mut self, uuid: [u8; UUID_LEN]) -> &mut Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    pub fn label(&mut self, label: impl Into<String>) -> &mut Self {
+        self.label = label.into();
+        self
+    }
+
+    // for the files given to mkfs, and the uuid if there isn't one, see MountOptions::clock
+    pub fn clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // size in bytes, must be a multiple of BLOCK_SIZE
+    pub fn size(&mut self, size: usize) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    pub fn blocks(&mut self, blocks: usize) -> &mut Self {
+        self.size = blocks * BLOCK_SIZE;
+        self
+    }
+
+    // keep a crc32 of every block, so corruption is caught on read rather than served up
+    pub fn checksums(&mut self, checksums: bool) -> &mut Self {
+        self.checksums = checksums;
+        self
+    }
+
+    fn checksum_blocks(&self) -> usize {
+        if self.checksums {
+            Disk::<BLOCK_SIZE>::checksum_blocks(self.size / BLOCK_SIZE)
+        } else {
+            0
+        }
+    }
+
+    // reserve blocks for a journal, so a crash partway through a sync can't leave the metadata half
+    // written. one holds the header and the rest the blocks of a transaction, 0 means no journal
+    pub fn journal_blocks(&mut self, journal_blocks: usize) -> &mut Self {
+        self.journal_blocks = journal_blocks;
+        self
+    }
+
+    // keep a count of references to every block, so files can be cloned without copying their data
+    pub fn reflinks(&mut self, reflinks: bool) -> &mut Self {
+        self.reflinks = reflinks;
+        self
+    }
+
+    fn refcount_blocks(&self) -> usize {
+        if self.reflinks {
+            (self.size / BLOCK_SIZE).div_ceil(BLOCK_SIZE)
+        } else {
+            0
+        }
+    }
+
+    // zero every block rather than only the ones the filesystem keeps its own tables in, for
+    // scrubbing whatever was on the device before. nothing else is ever read before it's written
+    pub fn zero_fill(&mut self, zero_fill: bool) -> &mut Self {
+        self.zero_fill = zero_fill;
+        self
+    }
+
+    // mkfs over an image that's already there, everything in it is lost
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    pub fn file(&mut self, filename: impl Into<String>, contents: impl Into<Vec<u8>>) -> &mut Self {
+        self.files.push((filename.into(), contents.into()));
+        self
+    }
+
+    pub fn empty_file(&mut self, filename: impl Into<String>) -> &mut Self {
+        self.file(filename, Vec::new())
+    }
+
+    pub fn mkfs(&self, path: impl AsRef<Path>) -> TfsResult<()> {
+        Tfs::mkfs_with(path, self)
+    }
+
+    // check everything up front so a bad set of options never touches the image
+    fn validate(&self) -> TfsResult<()> {
+        if !self.size.is_multiple_of(BLOCK_SIZE) {
+            return Err(disk::DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            }
+            .into());
+        }
+        let blocks = self.size / BLOCK_SIZE;
+        // room for at least the superblock, root and backup superblock, and no more than the
+        // superblock can keep track of
+        if !(3..=MAX_BLOCKS).contains(&blocks) {
+            return Err(TfsError::SizeError { size: self.size });
+        }
+        if self.label.len() > LABEL_LEN || self.label.contains('\0') {
+            return Err(TfsError::InvalidLabel(self.label.clone()));
+        }
+        if self.journal_blocks == 1 {
+            return Err(TfsError::JournalTooSmall(self.journal_blocks));
+        }
+        let mut needed = 3
+            + self.checksum_blocks()
+            + self.journal_blocks
+            + self.refcount_blocks()
+            + Root::chain_needed(self.files.len());
+        for (i, (filename, contents)) in self.files.iter().enumerate() {
+            if filename.is_empty() || filename.contains('/') {
+                return Err(TfsError::InvalidFilename(filename.clone()));
+            }
+            check_filename(filename)?;
+            if self.files[..i].iter().any(|(other, _)| other == filename) {
+                return Err(TfsError::AlreadyExists(filename.clone()));
+            }
+            let data = contents.len().div_ceil(BLOCK_SIZE);
+            if data > MAX_FILE_BLOCKS {
+                return Err(TfsError::OutOfSpace);
+            }
+            needed += 1 + data + INode::indirect_needed(data);
+        }
+        if needed > blocks {
+            return Err(TfsError::OutOfSpace);
+        }
+        Ok(())
+    }
+}
+
+// a panic partway through an operation leaves nothing worse than a crash would, which is what the
+// journal and fsck are there for, so a poisoned lock just gets carried on with
+fn lock_read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_write<D: BlockDevice<BLOCK_SIZE>>(lock: &RwLock<TfsFs<D>>) -> WriteGuard<'_, D> {
+    WriteGuard {
+        guard: Some(lock.write().unwrap_or_else(PoisonError::into_inner)),
+    }
+}
+
+// the filesystem locked for writing. whatever changed while it was held is passed on to the
+// observer once it's let go, so the observer never runs with the filesystem locked
+struct WriteGuard<'a, D: BlockDevice<BLOCK_SIZE>> {
+    // only ever taken in drop
+    guard: Option<RwLockWriteGuard<'a, TfsFs<D>>>,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Deref for WriteGuard<'_, D> {
+    type Target = TfsFs<D>;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> DerefMut for WriteGuard<'_, D> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Drop for WriteGuard<'_, D> {
+    fn drop(&mut self) {
+        let mut tfs = self.guard.take().unwrap();
+        let events = mem::take(&mut tfs.events);
+        let observer = tfs.observer.clone();
+        drop(tfs);
+        if let Some(observer) = observer {
+            let mut observer = observer.0.lock().unwrap_or_else(PoisonError::into_inner);
+            for event in events {
+                event.deliver(observer.as_mut());
+            }
+        }
+    }
+}
+
+// told about changes to the filesystem once they've succeeded, see Tfs::set_observer. paths are
+// full paths, the way the rest of the api takes them
+pub trait FsObserver: Send {
+    fn on_create(&mut self, _path: &str) {}
+    fn on_write(&mut self, _path: &str, _offset: u64, _len: usize) {}
+    fn on_rename(&mut self, _from: &str, _to: &str) {}
+    fn on_remove(&mut self, _path: &str) {}
+    fn on_sync(&mut self) {}
+}
+
+#[derive(Clone)]
+struct Observer(Arc<Mutex<Box<dyn FsObserver>>>);
+
+impl std::fmt::Debug for Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+// queued up while the filesystem is locked, then handed to the observer
+#[derive(Debug)]
+enum FsEvent {
+    Create(String),
+    Write(String, u64, usize),
+    Rename(String, String),
+    Remove(String),
+    Sync,
+}
+
+impl FsEvent {
+    fn deliver(self, observer: &mut dyn FsObserver) {
+        match self {
+            FsEvent::Create(path) => observer.on_create(&path),
+            FsEvent::Write(path, offset, len) => observer.on_write(&path, offset, len),
+            FsEvent::Rename(from, to) => observer.on_rename(&from, &to),
+            FsEvent::Remove(path) => observer.on_remove(&path),
+            FsEvent::Sync => observer.on_sync(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TfsFile<'a, D: BlockDevice<BLOCK_SIZE> = File> {
+    filesystem: &'a RwLock<TfsFs<D>>,
+    file: TfsFsFile,
+}
+
+impl<'a, D: BlockDevice<BLOCK_SIZE>> TfsFile<'a, D> {
+    pub fn write(&mut self, buf: &[u8]) -> TfsResult<()> {
+        let written = lock_write(self.filesystem).write(&mut self.file, buf)?;
+        if written < buf.len() {
+            return Err(TfsError::OutOfSpace);
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> TfsResult<Option<u8>> {
+        lock_read(self.filesystem).read_byte(&mut self.file)
+    }
+
+    pub fn peek_byte(&mut self) -> TfsResult<Option<u8>> {
+        lock_read(self.filesystem).peek_byte(&mut self.file)
+    }
+
+    // returns how far it actually went, which is less than n at the end of the file
+    pub fn skip(&mut self, n: usize) -> TfsResult<usize> {
+        lock_read(self.filesystem).skip(&mut self.file, n)
+    }
+
+    // only needs the filesystem locked for reading, so any number of handles can read at once
+    pub fn read(&mut self, buf: &mut [u8]) -> TfsResult<usize> {
+        lock_read(self.filesystem).read(&mut self.file, buf)
+    }
+
+    // everything from the offset to the end, added onto buf a block at a time
+    pub fn read_to_end(&mut self, buf: &mut Vec<u8>) -> TfsResult<usize> {
+        let tfs = lock_read(self.filesystem);
+        let start = buf.len();
+        let mut block = [0; BLOCK_SIZE];
+        loop {
+            let read = tfs.read(&mut self.file, &mut block)?;
+            if read == 0 {
+                return Ok(buf.len() - start);
+            }
+            buf.extend_from_slice(&block[..read]);
+        }
+    }
+
+    // buf is left as it was unless everything read is valid utf-8
+    pub fn read_to_string(&mut self, buf: &mut String) -> TfsResult<usize> {
+        let mut bytes = Vec::new();
+        let read = self.read_to_end(&mut bytes)?;
+        let string =
+            String::from_utf8(bytes).map_err(|err| TfsError::InvalidUtf8(err.utf8_error()))?;
+        buf.push_str(&string);
+        Ok(read)
+    }
+
+    // neither of these move the handle's offset
+    pub fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> TfsResult<usize> {
+        lock_read(self.filesystem).read_at(&mut self.file, offset, buf)
+    }
+
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> TfsResult<usize> {
+        lock_write(self.filesystem).write_at(&mut self.file, offset, buf)
+    }
+
+    pub fn set_len(&mut self, len: u64) -> TfsResult<()> {
+        lock_write(self.filesystem).truncate(&mut self.file, len)
+    }
+
+    pub fn preallocate(&mut self, len: usize) -> TfsResult<()> {
+        lock_write(self.filesystem).preallocate(&self.file, len)
+    }
+
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> TfsResult<()> {
+        lock_write(self.filesystem).punch_hole(&self.file, offset, len)
+    }
+
+    pub fn set_times(&mut self, mtime: SystemTime, atime: SystemTime) -> TfsResult<()> {
+        lock_write(self.filesystem).set_times(&self.file, mtime, atime)
+    }
+
+    pub fn rename(&mut self, newname: &str) -> TfsResult<()> {
+        let mut tfs = lock_write(self.filesystem);
+        tfs.rename(&mut self.file, newname)?;
+        tfs.sync_file(&self.file)
+    }
+
+    // while held, other handles can't read or write the file
+    pub fn try_lock_exclusive(&self) -> TfsResult<()> {
+        lock_read(self.filesystem).try_lock(&self.file, true)
+    }
+
+    // while held, other handles can still read the file but not write it
+    pub fn try_lock_shared(&self) -> TfsResult<()> {
+        lock_read(self.filesystem).try_lock(&self.file, false)
+    }
+
+    // the locks are only advisory, so waiting is just trying again each time one is let go
+    pub fn lock_exclusive(&self) -> TfsResult<()> {
+        wait_for_lock(self.filesystem, &self.file, true)
+    }
+
+    pub fn lock_shared(&self) -> TfsResult<()> {
+        wait_for_lock(self.filesystem, &self.file, false)
+    }
+
+    pub fn unlock(&self) -> TfsResult<()> {
+        lock_read(self.filesystem).unlock(&self.file)
+    }
+
+    // doesn't count as an access, so atime is left alone
+    pub fn stat(&self) -> TfsResult<Stat> {
+        lock_read(self.filesystem).stat(&self.file)
+    }
+
+    // like dup, the two handles share the file but each has its own offset
+    pub fn try_clone(&self) -> TfsResult<TfsFile<'a, D>> {
+        Ok(TfsFile {
+            filesystem: self.filesystem,
+            file: lock_read(self.filesystem).try_clone(&self.file)?,
+        })
+    }
+
+    // write this file's inode out, along with the superblock and root if they've changed
+    pub fn flush(&mut self) -> TfsResult<()> {
+        lock_write(self.filesystem).sync_file(&self.file)
+    }
+
+    // like dropping the file, but with a chance to see if flushing it failed
+    pub fn close(mut self) -> TfsResult<()> {
+        lock_write(self.filesystem).close(&mut self.file)
+    }
+}
+
+fn wait_for_lock<D: BlockDevice<BLOCK_SIZE>>(
+    filesystem: &RwLock<TfsFs<D>>,
+    file: &TfsFsFile,
+    exclusive: bool,
+) -> TfsResult<()> {
+    loop {
+        let tfs = lock_read(filesystem);
+        let table = Arc::clone(&tfs.locks);
+        let mut locks = table.held.lock().unwrap_or_else(PoisonError::into_inner);
+        match tfs.try_lock_in(&mut locks, file, exclusive) {
+            Err(TfsError::WouldBlock(_)) => {}
+            result => return result,
+        }
+        // the filesystem goes first, since closing whatever is in the way needs to write to it.
+        // the table is held right up until the wait, so a release can't slip by unseen
+        drop(tfs);
+        drop(
+            table
+                .changed
+                .wait(locks)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Drop for TfsFile<'_, D> {
+    fn drop(&mut self) {
+        // best effort, errors can't go anywhere from here. use close to see them
+        let _ = lock_write(self.filesystem).close(&mut self.file);
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> io::Seek for TfsFile<'_, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        Ok(lock_write(self.filesystem).seek(&mut self.file, pos)?)
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> io::Read for TfsFile<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(lock_read(self.filesystem).read(&mut self.file, buf)?)
+    }
+}
+
+// the buffer is the handle's own cached block, so nothing is copied until it's asked for
+impl<D: BlockDevice<BLOCK_SIZE>> io::BufRead for TfsFile<'_, D> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let len = lock_read(self.filesystem).fill_buf(&mut self.file)?;
+        let start = self.file.offset % BLOCK_SIZE;
+        Ok(match &self.file.cache {
+            Some(cached) if len != 0 => &cached.data[start..start + len],
+            _ => &[],
+        })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.file.offset += amt;
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> io::Write for TfsFile<'_, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(lock_write(self.filesystem).write(&mut self.file, buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(TfsFile::flush(self)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct Tfs<D: BlockDevice<BLOCK_SIZE> = File> {
+    tfs: RwLock<TfsFs<D>>,
+}
+
+impl Tfs {
+    pub fn mkfs(path: impl AsRef<Path>, size: usize) -> TfsResult<()> {
+        TfsFs::mkfs(path, size)
+    }
+
+    pub fn mkfs_with(path: impl AsRef<Path>, options: &MkfsOptions) -> TfsResult<()> {
+        TfsFs::mkfs_with(path, options)
+    }
+
+    pub fn upgrade(path: impl AsRef<Path>) -> TfsResult<()> {
+        TfsFs::upgrade(path)
+    }
+
+    // grow or shrink an unmounted image to new_size bytes
+    pub fn resize(path: impl AsRef<Path>, new_size: usize) -> TfsResult<()> {
+        TfsFs::resize(path, new_size)
+    }
+
+    // look an unmounted image over for problems without changing anything
+    pub fn check(path: impl AsRef<Path>) -> TfsResult<FsckReport> {
+        fsck::check(path, false)
+    }
+
+    // check, then fix whatever can be fixed safely
+    pub fn repair(path: impl AsRef<Path>) -> TfsResult<FsckReport> {
+        fsck::check(path, true)
+    }
+
+    pub fn mount(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, &MountOptions::new())
+    }
+
+    pub fn mount_read_only(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, MountOptions::new().read_only(true))
+    }
+
+    pub fn mount_shared(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, MountOptions::new().shared(true))
+    }
+
+    pub fn mount_with(path: impl AsRef<Path>, options: &MountOptions) -> TfsResult<Self> {
+        let tfs = TfsFs::mount_with(path, options)?;
+        Ok(Self {
+            tfs: RwLock::new(tfs),
+        })
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Tfs<D> {
+    pub fn new(disk: Disk<BLOCK_SIZE, D>) -> Self {
+        Self {
+            tfs: RwLock::new(TfsFs::new(disk)),
+        }
+    }
+
+    // like mkfs, but onto any block device rather than an image file
+    pub fn mkfs_on(device: D, options: &MkfsOptions) -> TfsResult<()> {
+        TfsFs::mkfs_on(device, options)
+    }
+
+    pub fn mount_on(device: D, options: &MountOptions) -> TfsResult<Self> {
+        let tfs = TfsFs::mount_on(device, options)?;
+        Ok(Self {
+            tfs: RwLock::new(tfs),
+        })
+    }
+
+    // like dropping, but any error actually gets back to the caller
+    pub fn unmount(self) -> TfsResult<()> {
+        lock_write(&self.tfs).unmount()
+    }
+
+    // the last mount never unmounted, so the metadata might not be consistent
+    pub fn was_unclean(&self) -> bool {
+        lock_read(&self.tfs).was_unclean()
+    }
+
+    // block 0 was corrupt and the filesystem was mounted from the backup superblock
+    pub fn used_backup_superblock(&self) -> bool {
+        lock_read(&self.tfs).used_backup_superblock()
+    }
+
+    pub fn readdir(&self) -> Vec<ReadDirEntry> {
+        self.read_dir().collect()
+    }
+
+    pub fn read_dir(&self) -> ReadDir<'_, D> {
+        ReadDir {
+            tfs: &self.tfs,
+            dir: None,
+            index: 0,
+        }
+    }
+
+    pub fn readdir_at(&self, path: impl AsRef<Path>) -> TfsResult<Vec<ReadDirEntry>> {
+        Ok(self.read_dir_at(path)?.collect())
+    }
+
+    pub fn read_dir_at(&self, path: impl AsRef<Path>) -> TfsResult<ReadDir<'_, D>> {
+        let tfs = lock_read(&self.tfs);
+        let dir = tfs.root.resolve_dir(path_str(path.as_ref())?)?;
+        let dir = dir.map(|dir| tfs.root.inodes[dir].block);
+        Ok(ReadDir {
+            tfs: &self.tfs,
+            dir,
+            index: 0,
+        })
+    }
+
+    pub fn touch(&self, path: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).touch(path)
+    }
+
+    pub fn mkdir(&self, path: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).mkdir(path)
+    }
+
+    pub fn rmdir(&self, path: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).rmdir(path)
+    }
+
+    pub fn open(&self, filename: impl AsRef<Path>) -> TfsResult<TfsFile<'_, D>> {
+        self.open_with(
+            filename,
+            TfsOpenOptions::new().read(true).write(true).create(true),
+        )
+    }
+
+    pub fn open_existing(&self, filename: impl AsRef<Path>) -> TfsResult<TfsFile<'_, D>> {
+        self.open_with(filename, TfsOpenOptions::new().read(true).write(true))
+    }
+
+    pub fn create(&self, filename: impl AsRef<Path>) -> TfsResult<TfsFile<'_, D>> {
+        self.open_with(
+            filename,
+            TfsOpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true),
+        )
+    }
+
+    pub fn create_new(&self, filename: impl AsRef<Path>) -> TfsResult<TfsFile<'_, D>> {
+        self.open_with(filename, TfsOpenOptions::new().write(true).create_new(true))
+    }
+
+    pub fn open_with(
+        &self,
+        filename: impl AsRef<Path>,
+        options: &TfsOpenOptions,
+    ) -> TfsResult<TfsFile<'_, D>> {
+        let mut tfs = lock_write(&self.tfs);
+        let file = tfs.open_with(filename, options)?;
+        Ok(TfsFile {
+            filesystem: &self.tfs,
+            file,
+        })
+    }
+
+    pub fn statfs(&self) -> TfsResult<FsStats> {
+        lock_read(&self.tfs).statfs()
+    }
+
+    pub fn allocation(&self) -> TfsResult<Allocation> {
+        lock_read(&self.tfs).allocation()
+    }
+
+    pub fn io_stats(&self) -> DiskStats {
+        lock_read(&self.tfs).io_stats()
+    }
+
+    pub fn reset_stats(&self) {
+        lock_write(&self.tfs).reset_stats()
+    }
+
+    pub fn label(&self) -> String {
+        lock_read(&self.tfs).label().to_string()
+    }
+
+    pub fn uuid(&self) -> [u8; UUID_LEN] {
+        lock_read(&self.tfs).uuid()
+    }
+
+    pub fn rebuild_allocation(&self) -> TfsResult<usize> {
+        lock_write(&self.tfs).rebuild_allocation()
+    }
+
+    pub fn grow(&self, new_size: usize) -> TfsResult<()> {
+        lock_write(&self.tfs).grow(new_size)
+    }
+
+    // a file can't be removed while this is non zero, so it's mostly for finding out why not
+    pub fn open_handles(&self, path: impl AsRef<Path>) -> usize {
+        lock_read(&self.tfs).open_handles(path)
+    }
+
+    // needs the only reference, every open file could be pointing at a block that moves
+    pub fn shrink(&mut self, new_size: usize) -> TfsResult<()> {
+        lock_write(&self.tfs).shrink(new_size)
+    }
+
+    // the filesystem is locked for the whole copy, so nothing can change underneath it
+    pub fn snapshot(&self, dest: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).snapshot(dest)
+    }
+
+    pub fn exists(&self, filename: impl AsRef<Path>) -> bool {
+        lock_read(&self.tfs).exists(filename)
+    }
+
+    pub fn metadata(&self, filename: impl AsRef<Path>) -> TfsResult<Stat> {
+        lock_read(&self.tfs).metadata(filename)
+    }
+
+    pub fn read(&self, filename: impl AsRef<Path>) -> TfsResult<Vec<u8>> {
+        lock_read(&self.tfs).read_file(filename)
+    }
+
+    pub fn write_file(&self, filename: impl AsRef<Path>, contents: &[u8]) -> TfsResult<()> {
+        lock_write(&self.tfs).write_file(filename, contents)
+    }
+
+    pub fn copy(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> TfsResult<u64> {
+        lock_write(&self.tfs).copy(src, dst)
+    }
+
+    pub fn clone_file(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> TfsResult<u64> {
+        lock_write(&self.tfs).clone_file(src, dst)
+    }
+
+    pub fn link(&self, existing: impl AsRef<Path>, new: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).link(existing, new)
+    }
+
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).rename_path(from, to)
+    }
+
+    pub fn set_permissions(&self, path: impl AsRef<Path>, mode: u16) -> TfsResult<()> {
+        lock_write(&self.tfs).set_permissions(path, mode)
+    }
+
+    pub fn chown(&self, path: impl AsRef<Path>, uid: u16, gid: u16) -> TfsResult<()> {
+        lock_write(&self.tfs).chown(path, uid, gid)
+    }
+
+    pub fn set_flags(&self, path: impl AsRef<Path>, flags: FileFlags) -> TfsResult<()> {
+        lock_write(&self.tfs).set_flags(path, flags)
+    }
+
+    pub fn set_times(
+        &self,
+        path: impl AsRef<Path>,
+        mtime: SystemTime,
+        atime: SystemTime,
+    ) -> TfsResult<()> {
+        lock_write(&self.tfs).set_times_path(path, mtime, atime)
+    }
+
+    pub fn remove(&self, filename: impl AsRef<Path>) -> TfsResult<()> {
+        lock_write(&self.tfs).remove(filename)
+    }
+
+    pub fn sync(&self) -> TfsResult<()> {
+        let mut tfs = lock_write(&self.tfs);
+        let writes = tfs.disk.writes();
+        tfs.sync()?;
+        // a read-only mount, an open batch or nothing being dirty all mean nothing was synced
+        if tfs.disk.writes() != writes {
+            tfs.notify(|_| FsEvent::Sync);
+        }
+        Ok(())
+    }
+
+    pub fn sync_all(&self) -> TfsResult<()> {
+        let mut tfs = lock_write(&self.tfs);
+        let writes = tfs.disk.writes();
+        tfs.sync_all()?;
+        if tfs.disk.writes() != writes {
+            tfs.notify(|_| FsEvent::Sync);
+        }
+        Ok(())
+    }
+
+    // replaces any observer already set. the callbacks run after the filesystem has been unlocked
+    // but with the observer itself locked, so they mustn't call back into the filesystem
+    pub fn set_observer(&self, observer: Box<dyn FsObserver>) {
+        lock_write(&self.tfs).observer = Some(Observer(Arc::new(Mutex::new(observer))));
+    }
+
+    pub fn set_sync_writes(&mut self, sync_writes: bool) {
+        lock_write(&self.tfs).set_sync_writes(sync_writes)
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        lock_write(&self.tfs).set_sync_policy(sync_policy)
+    }
+
+    // no syncing at all until the batch is committed or dropped, for when making lots of changes
+    // at once. everything else works just the same inside one
+    pub fn batch(&mut self) -> TfsBatch<'_, D> {
+        lock_write(&self.tfs).begin_batch();
+        TfsBatch {
+            tfs: self,
+            committed: false,
+        }
+    }
+}
+
+impl Tfs<MemDisk> {
+    // a fresh image that only lives in memory, turn it into bytes with MemDisk::into_bytes
+    pub fn mkfs_in_memory(size: usize) -> TfsResult<MemDisk> {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, MkfsOptions::new().size(size))?;
+        Ok(disk)
+    }
+
+    // everything is gone once it's dropped, mount_on a &mut MemDisk to keep hold of the image
+    pub fn mount_mem(disk: MemDisk) -> TfsResult<Self> {
+        Self::mount_on(disk, &MountOptions::new())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Tfs<MmapDisk> {
+    // the image has to have been made already, e.g. with Tfs::mkfs
+    pub fn mount_mmap(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_on(MmapDisk::open(path, 0)?, &MountOptions::new())
+    }
+}
+
+// borrows the image rather than owning it, so the filesystem can't outlive the bytes
+impl<'a> Tfs<SliceDisk<'a>> {
+    // always read only, anything that would change the image fails with ReadOnlyFilesystem
+    pub fn mount_bytes(data: &'a [u8]) -> TfsResult<Self> {
+        let disk = SliceDisk::new::<BLOCK_SIZE>(data)?;
+        Self::mount_on(disk, MountOptions::new().read_only(true))
+    }
+}
+
+impl<T: Read + Write + Seek> Tfs<IoDevice<T>> {
+    // pass a &mut to be able to mount_from the same io afterwards
+    pub fn mkfs_into(io: T, size: usize) -> TfsResult<()> {
+        Tfs::mkfs_on(IoDevice::new(io), MkfsOptions::new().size(size))
+    }
+
+    pub fn mount_from(io: T) -> TfsResult<Self> {
+        Self::mount_on(IoDevice::new(io), &MountOptions::new())
+    }
+}
+
+// derefs to the filesystem it's batching changes to
+#[derive(Debug)]
+pub struct TfsBatch<'a, D: BlockDevice<BLOCK_SIZE> = File> {
+    tfs: &'a mut Tfs<D>,
+    committed: bool,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> TfsBatch<'_, D> {
+    // sync everything put off during the batch
+    pub fn commit(mut self) -> TfsResult<()> {
+        self.committed = true;
+        lock_write(&self.tfs.tfs).end_batch()
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Deref for TfsBatch<'_, D> {
+    type Target = Tfs<D>;
+
+    fn deref(&self) -> &Tfs<D> {
+        self.tfs
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> DerefMut for TfsBatch<'_, D> {
+    fn deref_mut(&mut self) -> &mut Tfs<D> {
+        self.tfs
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Drop for TfsBatch<'_, D> {
+    fn drop(&mut self) {
+        // dropping still syncs, there's just nowhere for an error to go
+        if !self.committed {
+            let _ = lock_write(&self.tfs.tfs).end_batch();
+        }
+    }
+}
+
+// how hard sync tries to make things durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    // hand writes to the os and let it get to them, fine for tests and throwaway images
+    #[default]
+    Lazy,
+    // fsync the backing file on every sync
+    Always,
+}
+
+#[derive(Debug)]
+pub struct TfsFs<D: BlockDevice<BLOCK_SIZE> = File> {
+    superblock: SuperBlock,
+    root: Root,
+    disk: Disk<BLOCK_SIZE, D>,
+    read_only: bool,
+    noatime: bool,
+    // the image was still marked as mounted when this mount found it
+    was_unclean: bool,
+    // the primary superblock was unreadable so the copy at the end of the disk was used instead
+    used_backup_superblock: bool,
+    // flush every write straight away rather than waiting for a sync
+    sync_writes: bool,
+    sync_policy: SyncPolicy,
+    // bumped whenever file data changes, so cached blocks know when they're out of date
+    epoch: u64,
+    // syncs are put off while this is non zero, see Tfs::batch
+    batch_depth: usize,
+    // the uid and gid new files are given
+    owner: (u16, u16),
+    clock: Clock,
+    // handed out to each new handle
+    next_handle: AtomicU64,
+    // behind their own lock so handles can let go of them with only read access
+    locks: Arc<LockTable>,
+    // the ids of the handles open on each file, by inode block. a handle only comes out again
+    // when it's closed, so one dropped without closing keeps its file open until unmount
+    open_files: Mutex<HashMap<u16, Vec<u64>>>,
+    // atimes reads have left, by inode block and generation. reads only have the filesystem
+    // locked for reading, so this is folded into the inodes before anything writes them out
+    accessed: Mutex<HashMap<u16, (u32, SystemTime)>>,
+    observer: Option<Observer>,
+    // changes waiting to go to the observer, only kept while there is one
+    events: Vec<FsEvent>,
+}
+
+impl TfsFs {
+    pub fn mkfs(path: impl AsRef<Path>, size: usize) -> TfsResult<()> {
+        Self::mkfs_with(path, MkfsOptions::new().size(size))
+    }
+
+    pub fn mkfs_with(path: impl AsRef<Path>, options: &MkfsOptions) -> TfsResult<()> {
+        options.validate()?;
+        let path = path.as_ref();
+        let disk = if options.force {
+            Disk::create(path, options.size)?
+        } else {
+            Disk::create_new(path, options.size).map_err(|err| match err {
+                disk::DiskError::AlreadyExists => TfsError::ImageExists(path.to_path_buf()),
+                err => err.into(),
+            })?
+        };
+        Self::format(disk, options)
+    }
+
+    // rewrite an image in an older layout as the current one, in place. images that are already
+    // current are left alone
+    pub fn upgrade(path: impl AsRef<Path>) -> TfsResult<()> {
+        let disk: Disk<BLOCK_SIZE> = Disk::open_existing(path)?;
+        let superblock = disk.read_block(0)?;
+        if superblock[0] != MAGIC_NUMBER {
+            return Err(TfsError::MagicNumberError(superblock[0]));
+        }
+        // every layout so far has the version, or what reads as one, straight after the magic
+        match u16::from_le_bytes([superblock[1], superblock[2]]) {
+            FORMAT_VERSION => Ok(()),
+            v1::VERSION => Self::upgrade_v1(disk),
+            found => Err(TfsError::UnsupportedVersion {
+                found,
+                supported: FORMAT_VERSION,
+            }),
+        }
+    }
+
+    pub fn resize(path: impl AsRef<Path>, new_size: usize) -> TfsResult<()> {
+        let mut tfs = Self::mount(path)?;
+        if new_size < tfs.superblock.total_blocks() * BLOCK_SIZE {
+            tfs.shrink(new_size)?;
+        } else {
+            tfs.grow(new_size)?;
+        }
+        tfs.unmount()
+    }
+
+    fn upgrade_v1(disk: Disk<BLOCK_SIZE>) -> TfsResult<()> {
+        let old: v1::SuperBlockData = decode(&disk.read_block(0)?)?;
+        // the allocation table has shrunk since, so anything past the end of it can't be kept
+        if old.allocated_blocks[ALLOCATION_TABLE_LEN..]
+            .iter()
+            .any(|&byte| byte != 0)
+        {
+            return Err(TfsError::SizeError {
+                size: disk.block_count()? * BLOCK_SIZE,
+            });
+        }
+        let mut superblock = SuperBlock::new();
+        superblock
+            .allocated_blocks
+            .copy_from_slice(&old.allocated_blocks[..ALLOCATION_TABLE_LEN]);
+        superblock.set_total_blocks(disk.block_count()?);
+        superblock.uuid = generate_uuid(system_clock());
+        // old images didn't keep their last block free, so they only get a backup if it happens to be
+        let last = (disk.block_count()? - 1) as u16;
+        if !superblock.is_allocated(last) {
+            superblock.mark_allocated(last);
+            superblock.backup_block = last;
+        }
+
+        let old_root: v1::RootData = decode(&disk.read_block(old.root_inode as usize)?)?;
+        let mut root = Root::new();
+        for &block in old_root.inodes.iter().filter(|&&block| block != 0) {
+            let data: v1::INodeData = decode(&disk.read_block(block as usize)?)?;
+            // names that filled the whole field weren't nul terminated
+            let filename_len = data
+                .filename
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(v1::MAX_FILENAME_LEN);
+            let filename = String::from_utf8_lossy(&data.filename[..filename_len]).into_owned();
+            let mut inode = INode::new(block, FileKind::Regular, system_clock());
+            inode.stat = StatData {
+                size: data.stat.size as u32,
+                // v1 only kept whole seconds
+                ctime: data.stat.ctime as u64 * 1_000_000_000,
+                mtime: data.stat.mtime as u64 * 1_000_000_000,
+                atime: data.stat.atime as u64 * 1_000_000_000,
+                // ctime never changed back then, so it was really the birth time
+                btime: data.stat.ctime as u64 * 1_000_000_000,
+                nlink: 1,
+                mode: DEFAULT_FILE_MODE,
+                uid: 0,
+                gid: 0,
+            }
+            .into();
+            inode.blocks = data.blocks.into_iter().filter(|&b| b != 0).collect();
+            // fewer blocks fit in an inode now, the rest go in indirect blocks
+            for _ in 0..INode::indirect_needed(inode.blocks.len()) {
+                let indirect = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+                inode.indirect.push(indirect);
+            }
+            root.entries.push(DirEntry {
+                filename,
+                inode: block,
+            });
+            root.inodes.push(inode);
+        }
+        for _ in 0..Root::chain_needed(root.entries.len()) {
+            let block = superblock.allocate_block().ok_or(TfsError::OutOfSpace)?;
+            root.chain.push(block);
+        }
+
+        let mut tfs = TfsFs::new(disk);
+        tfs.superblock = superblock;
+        tfs.root = root;
+        tfs.unmount()
+    }
+
+    pub fn mount(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, &MountOptions::new())
+    }
+
+    // nothing will ever be written back to the image, the backing file isn't even opened writable
+    pub fn mount_read_only(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, MountOptions::new().read_only(true))
+    }
+
+    // read only without locking the image out from under whatever else has it mounted
+    pub fn mount_shared(path: impl AsRef<Path>) -> TfsResult<Self> {
+        Self::mount_with(path, MountOptions::new().shared(true))
+    }
+
+    // writable mounts lock the image so nothing else can mount it, read only ones only keep out
+    // writable mounts
+    pub fn mount_with(path: impl AsRef<Path>, options: &MountOptions) -> TfsResult<Self> {
+        let disk = if options.shared {
+            Disk::open_shared(path)?
+        } else if options.is_read_only() {
+            Disk::open_read_only(path)?
+        } else {
+            Disk::open_existing(path)?
+        };
+        Self::mount_disk(disk, options)
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> TfsFs<D> {
+    pub fn new(disk: Disk<BLOCK_SIZE, D>) -> Self {
+        let mut superblock = SuperBlock::new();
+        superblock.mark_allocated(0);
+        superblock.mark_allocated(1);
+        Self {
+            superblock,
+            root: Root::new(),
+            disk,
+            read_only: false,
+            noatime: false,
+            was_unclean: false,
+            used_backup_superblock: false,
+            sync_writes: false,
+            sync_policy: SyncPolicy::default(),
+            epoch: 0,
+            batch_depth: 0,
+            owner: process_owner(),
+            clock: system_clock,
+            next_handle: AtomicU64::new(0),
+            locks: Arc::default(),
+            open_files: Mutex::default(),
+            accessed: Mutex::default(),
+            observer: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn mkfs_on(device: D, options: &MkfsOptions) -> TfsResult<()> {
+        options.validate()?;
+        Self::format(Disk::new(device, false), options)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "mkfs", skip_all, fields(size = options.size), err)
+    )]
+    fn format(mut disk: Disk<BLOCK_SIZE, D>, options: &MkfsOptions) -> TfsResult<()> {
+        let blocks = options.size / BLOCK_SIZE;
+        // a new image file is already all zeros, anything short of the size gets filled in
+        let existing = disk.block_count()?.min(blocks);
+        disk.set_block_count(blocks);
+        let checksum_blocks = options.checksum_blocks();
+        let reserved = 2 + checksum_blocks + options.journal_blocks + options.refcount_blocks();
+        let zeroed = if options.zero_fill {
+            blocks
+        } else {
+            reserved.min(existing)
+        };
+        for range in [0..zeroed, existing.max(zeroed)..blocks] {
+            if !range.is_empty() {
+                disk.write_blocks(range.start, &vec![0; range.len() * BLOCK_SIZE])?;
+            }
+        }
+        // the checksum table goes straight after the root
+        if checksum_blocks != 0 {
+            disk.format_checksums(2, checksum_blocks)?;
+        }
+        let mut tfs = Self::new(disk);
+        tfs.superblock.set_total_blocks(options.size / BLOCK_SIZE);
+        if let Some(clock) = options.clock {
+            tfs.clock = clock;
+        }
+        tfs.superblock.checksum_start = 2;
+        tfs.superblock.checksum_blocks = checksum_blocks as u16;
+        // and then the journal, which starts out clean as it's all zeros
+        let journal_start = 2 + checksum_blocks as u16;
+        tfs.superblock.journal_start = journal_start;
+        tfs.superblock.journal_blocks = options.journal_blocks as u16;
+        // and the refcount table, where all zeros means nothing's shared
+        let refcount_start = journal_start + options.journal_blocks as u16;
+        let refcount_blocks = options.refcount_blocks();
+        tfs.superblock.refcount_start = refcount_start;
+        tfs.superblock.refcount_blocks = refcount_blocks as u16;
+        tfs.superblock.refcounts = vec![0; refcount_blocks * BLOCK_SIZE];
+        for block in 2..refcount_start + refcount_blocks as u16 {
+            tfs.superblock.mark_allocated(block);
+        }
+        let backup_block = (options.size / BLOCK_SIZE - 1) as u16;
+        tfs.superblock.mark_allocated(backup_block);
+        tfs.superblock.backup_block = backup_block;
+        tfs.superblock.uuid = options.uuid.unwrap_or_else(|| generate_uuid((tfs.clock)()));
+        tfs.superblock.label = options.label.clone();
+        for (filename, contents) in &options.files {
+            tfs.write_file(filename, contents)?;
+        }
+        tfs.unmount()?;
+
+        Ok(())
+    }
+
+    pub fn label(&self) -> &str {
+        &self.superblock.label
+    }
+
+    pub fn uuid(&self) -> [u8; UUID_LEN] {
+        self.superblock.uuid
+    }
+
+    pub fn mount_on(device: D, options: &MountOptions) -> TfsResult<Self> {
+        Self::mount_disk(Disk::new(device, options.is_read_only()), options)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "mount", skip_all, fields(read_only = options.read_only), err)
+    )]
+    fn mount_disk(mut disk: Disk<BLOCK_SIZE, D>, options: &MountOptions) -> TfsResult<Self> {
+        if let Some(cache_blocks) = options.cache_blocks {
+            disk.set_cache_blocks(cache_blocks);
+        }
+        let (mut superblock, used_backup_superblock) = match Self::read_superblock(&mut disk, 0) {
+            Ok(superblock) => (superblock, false),
+            // mkfs puts the backup in the last block
+            Err(
+                err @ (TfsError::MagicNumberError(_)
+                | TfsError::SerializationError(_)
+                | TfsError::ChecksumMismatch { .. }),
+            ) => {
+                let backup = disk.block_count()?.saturating_sub(1);
+                match Self::read_superblock(&mut disk, backup) {
+                    Ok(superblock) if backup != 0 && superblock.backup_block as usize == backup => {
+                        (superblock, true)
+                    }
+                    _ => return Err(err),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Self::fit_disk(&mut disk, &superblock)?;
+        Self::enable_features(&mut disk, &mut superblock)?;
+        let root = disk.read_block(superblock.root_inode as usize)?;
+        let root: RootData = decode(&root)?;
+        let mut superblock: SuperBlock = superblock.into();
+        superblock.load_refcounts(&mut disk)?;
+        let mut tfs = Self {
+            was_unclean: !superblock.clean,
+            used_backup_superblock,
+            root: Root::from_data(root, &superblock, &mut disk)?,
+            superblock,
+            disk,
+            read_only: options.is_read_only(),
+            noatime: options.noatime || options.is_read_only(),
+            sync_writes: options.sync_writes,
+            sync_policy: options.sync_policy,
+            epoch: 0,
+            batch_depth: 0,
+            owner: process_owner(),
+            clock: options.clock.unwrap_or(system_clock),
+            next_handle: AtomicU64::new(0),
+            locks: Arc::default(),
+            open_files: Mutex::default(),
+            accessed: Mutex::default(),
+            observer: None,
+            events: Vec::new(),
+        };
+        if !tfs.read_only {
+            // also puts back the primary superblock if it was the backup that got used
+            tfs.superblock.clean = false;
+            tfs.superblock.dirty = true;
+            tfs.sync()?;
+        }
+        Ok(tfs)
+    }
+
+    // the image can be bigger than the filesystem in it, but nothing past it belongs to us. it can't
+    // be any smaller though
+    fn fit_disk(disk: &mut Disk<BLOCK_SIZE, D>, superblock: &SuperBlockData) -> TfsResult<()> {
+        let expected = superblock.total_blocks as usize;
+        let actual = disk.block_count()?;
+        if actual < expected {
+            return Err(TfsError::TruncatedImage { expected, actual });
+        }
+        disk.set_block_count(expected);
+        Ok(())
+    }
+
+    // replays the journal before anything else is read, as any of it could be out of date
+    fn enable_features(
+        disk: &mut Disk<BLOCK_SIZE, D>,
+        superblock: &mut SuperBlockData,
+    ) -> TfsResult<()> {
+        if superblock.features & FEATURE_JOURNAL != 0
+            && disk.enable_journal(
+                superblock.journal_start as usize,
+                superblock.journal_blocks as usize,
+            )?
+        {
+            *superblock = Self::read_superblock(disk, 0)?;
+        }
+        if superblock.features & FEATURE_CHECKSUMS != 0 {
+            disk.enable_checksums(
+                superblock.checksum_start as usize,
+                superblock.checksum_blocks as usize,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn read_superblock(disk: &mut Disk<BLOCK_SIZE, D>, block: usize) -> TfsResult<SuperBlockData> {
+        let superblock = disk.read_block(block)?;
+        if superblock[0] != MAGIC_NUMBER {
+            return Err(TfsError::MagicNumberError(superblock[0]));
+        }
+        let superblock: SuperBlockData = decode(&superblock)?;
+        if superblock.version != FORMAT_VERSION {
+            return Err(TfsError::UnsupportedVersion {
+                found: superblock.version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if superblock.checksum != superblock.compute_checksum()? {
+            return Err(TfsError::ChecksumMismatch {
+                block: block as u16,
+            });
+        }
+        if superblock.features & !SUPPORTED_FEATURES != 0 {
+            return Err(TfsError::UnsupportedFeatures {
+                found: superblock.features,
+                supported: SUPPORTED_FEATURES,
+            });
+        }
+        Ok(superblock)
+    }
+
+    pub fn used_backup_superblock(&self) -> bool {
+        self.used_backup_superblock
+    }
+
+    pub fn was_unclean(&self) -> bool {
+        self.was_unclean
+    }
+
+    // everything that's gone to and from the disk since mounting or the last reset
+    pub fn io_stats(&self) -> DiskStats {
+        self.disk.stats()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.disk.reset_stats()
+    }
+
+    // sync everything and mark the image as cleanly unmounted
+    pub fn unmount(&mut self) -> TfsResult<()> {
+        if !self.read_only && !self.superblock.clean {
+            self.superblock.clean = true;
+            self.superblock.dirty = true;
+        }
+        // whatever batch was going on is over now
+        self.batch_depth = 0;
+        self.sync()
+    }
+
+    // put off syncing until the matching end_batch, batches can nest
+    pub fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    // the outermost batch ending syncs everything that was put off
+    pub fn end_batch(&mut self) -> TfsResult<()> {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        self.sync()
+    }
+
+    // find where a new inode at path would go, making sure it can actually be created there
+    fn prepare_create<'p>(&mut self, path: &'p str) -> TfsResult<(Dir, &'p str)> {
+        let (dir, filename) = self.root.resolve_parent(path)?;
+        check_filename(filename)?;
+        if self.root.find_child(dir, filename).is_some() {
+            return Err(TfsError::AlreadyExists(path.to_string()));
+        }
+        self.root.reserve(dir, &mut self.superblock)?;
+        Ok((dir, filename))
+    }
+
+    fn create_inode(&mut self, path: &str, kind: FileKind) -> TfsResult<usize> {
+        let (dir, filename) = self.prepare_create(path)?;
+        let Some(inode) = self.superblock.allocate_block() else {
+            self.root.release(&mut self.superblock);
+            return Err(TfsError::OutOfSpace);
+        };
+        let inode = self
+            .root
+            .create_inode(dir, inode, filename.to_string(), kind, (self.clock)());
+        let stat = &mut self.root.inodes[inode].stat;
+        (stat.uid, stat.gid) = self.owner;
+        self.notify(|_| FsEvent::Create(path.to_string()));
+        Ok(inode)
+    }
+
+    // an empty file if there's nothing there yet, otherwise just bumps the times of what is
+    pub fn touch(&mut self, path: impl AsRef<Path>) -> TfsResult<()> {
+        self.check_writable()?;
+        let path = path_str(path.as_ref())?;
+        match self.root.find(path) {
+            Some(inode) => {
+                self.fold_atimes();
+                let inode = &mut self.root.inodes[inode];
+                inode.touch((self.clock)());
+                inode.stat.mtime = inode.stat.ctime;
+                inode.stat.atime = inode.stat.ctime;
+            }
+            None => {
+                self.create_inode(path, FileKind::Regular)?;
+            }
+        }
+        self.sync()
+    }
+
+    pub fn mkdir(&mut self, path: impl AsRef<Path>) -> TfsResult<()> {
+        self.check_writable()?;
+        self.create_inode(path_str(path.as_ref())?, FileKind::Directory)?;
+        Ok(())
+    }
+
+    // only empty directories can be removed
+    pub fn rmdir(&mut self, path: impl AsRef<Path>) -> TfsResult<()> {
+        self.check_writable()?;
+        let path = path_str(path.as_ref())?;
+        let index = self
+            .root
+            .find(path)
+            .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+        let inode = &self.root.inodes[index];
+        if !inode.is_dir() {
+            return Err(TfsError::NotADirectory(path.to_string()));
+        }
+        if !inode.children.is_empty() {
+            return Err(TfsError::DirectoryNotEmpty(path.to_string()));
+        }
+        self.check_mutable(index)?;
+        let (dir, filename) = self.root.resolve_parent(path)?;
+        self.root.remove_child(dir, filename);
+        let inode = self.root.inodes.remove(index);
+        for block in inode.blocks.into_iter().chain([inode.block]) {
+            self.superblock.mark_free(block);
+        }
+        self.root.release(&mut self.superblock);
+        self.notify(|_| FsEvent::Remove(path.to_string()));
+        Ok(())
+    }
+
+    pub fn open(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFsFile> {
+        self.open_with(
+            filename,
+            TfsOpenOptions::new().read(true).write(true).create(true),
+        )
+    }
+
+    pub fn open_existing(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFsFile> {
+        self.open_with(filename, TfsOpenOptions::new().read(true).write(true))
+    }
+
+    // like std::fs::File::create, truncating the file if it already exists
+    pub fn create(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFsFile> {
+        self.open_with(
+            filename,
+            TfsOpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true),
+        )
+    }
+
+    pub fn create_new(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFsFile> {
+        self.open_with(filename, TfsOpenOptions::new().write(true).create_new(true))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "open",
+            skip_all,
+            fields(filename = %filename.as_ref().display()),
+            err(level = "warn")
+        )
+    )]
+    pub fn open_with(
+        &mut self,
+        filename: impl AsRef<Path>,
+        options: &TfsOpenOptions,
+    ) -> TfsResult<TfsFsFile> {
+        if options.write
+            || options.append
+            || options.truncate
+            || options.create
+            || options.create_new
+        {
+            self.check_writable()?;
+        }
+        let filename = path_str(filename.as_ref())?;
+        let existing = match self.root.find(filename) {
+            Some(_) if options.create_new => {
+                return Err(TfsError::AlreadyExists(filename.to_string()))
+            }
+            Some(inode) if self.root.inodes[inode].is_dir() => {
+                return Err(TfsError::IsADirectory(filename.to_string()))
+            }
+            Some(inode)
+                if (options.write || options.append || options.truncate)
+                    && (self.root.inodes[inode].stat.mode & 0o222 == 0
+                        || self.root.inodes[inode].stat.flags.immutable) =>
+            {
+                return Err(TfsError::PermissionDenied(filename.to_string()))
+            }
+            Some(inode) if options.truncate && self.root.inodes[inode].stat.flags.append_only => {
+                return Err(TfsError::PermissionDenied(filename.to_string()))
+            }
+            Some(inode) => Some(inode),
+            None if options.create || options.create_new => None,
+            None => return Err(TfsError::FileNotFound(filename.to_string())),
+        };
+        let (inode, changed) = match existing {
+            Some(inode) => (inode, options.truncate),
+            None => (self.create_inode(filename, FileKind::Regular)?, true),
+        };
+        let INode {
+            block, generation, ..
+        } = self.root.inodes[inode];
+        let mut file = TfsFsFile {
+            id: self.next_handle.fetch_add(1, Ordering::Relaxed),
+            inode,
+            block,
+            generation,
+            offset: 0,
+            readable: options.read,
+            writable: options.write || options.append,
+            append: options.append,
+            cache: None,
+        };
+        if options.truncate {
+            // emptying it is a write like any other, so another handle's lock keeps it out
+            self.check_lock(&file, inode, true)?;
+            self.epoch += 1;
+            self.root.inodes[inode].truncate(
+                &mut self.superblock,
+                &mut self.disk,
+                0,
+                (self.clock)(),
+            )?;
+        }
+        if changed {
+            self.sync()?;
+        }
+        self.fold_atimes();
+        let stat = &mut self.root.inodes[inode].stat;
+        if !self.noatime {
+            stat.atime = (self.clock)();
+        }
+        if options.append {
+            file.offset = stat.size as usize;
+        }
+        self.open_files
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(block)
+            .or_default()
+            .push(file.id);
+        Ok(file)
+    }
+
+    pub fn set_sync_writes(&mut self, sync_writes: bool) {
+        self.sync_writes = sync_writes;
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+
+    pub fn close(&mut self, file: &mut TfsFsFile) -> TfsResult<()> {
+        self.release(file);
+        self.sync_file(file)
+    }
+
+    // writes as much of buf as possible at the file offset, only erroring if nothing could be
+    // written - otherwise the error will just resurface on the next write. metadata is only
+    // written out on a sync (or flush/close of the file) unless sync_writes is set
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(inode = file.block, offset = file.offset, bytes = buf.len()),
+            ret,
+            err(level = "warn")
+        )
+    )]
+    pub fn write(&mut self, file: &mut TfsFsFile, buf: &[u8]) -> TfsResult<usize> {
+        self.check_writable()?;
+        if !file.writable {
+            return Err(TfsError::ReadOnly);
+        }
+        let index = self.inode_index(file)?;
+        self.check_lock(file, index, true)?;
+        // the flags may have been set since the file was opened
+        if self.root.inodes[index].stat.flags.immutable {
+            return Err(TfsError::PermissionDenied(self.root.path_of(index)));
+        }
+        let inode = &mut self.root.inodes[index];
+        if file.append || inode.stat.flags.append_only {
+            file.offset = inode.stat.size as usize;
+        }
+        let start = file.offset;
+        inode.modified((self.clock)());
+        self.epoch += 1;
+        let mut written = 0;
+        let mut error = None;
+        while written < buf.len() {
+            let whole = (buf.len() - written) / BLOCK_SIZE * BLOCK_SIZE;
+            // past the end of the file whole blocks can go out together
+            let len = if file.offset == inode.blocks.len() * BLOCK_SIZE && whole > BLOCK_SIZE {
+                match inode.append_blocks(
+                    &mut self.superblock,
+                    &mut self.disk,
+                    &buf[written..written + whole],
+                ) {
+                    Ok(blocks) => blocks * BLOCK_SIZE,
+                    Err(err) => {
+                        error = Some(err);
+                        break;
+                    }
+                }
+            } else {
+                let len = (buf.len() - written).min(BLOCK_SIZE - file.offset % BLOCK_SIZE);
+                let bytes = &buf[written..written + len];
+                if let Err(err) =
+                    inode.write_chunk(&mut self.superblock, &mut self.disk, file.offset, bytes)
+                {
+                    error = Some(err);
+                    break;
+                }
+                len
+            };
+            file.offset += len;
+            written += len;
+            inode.stat.size = inode.stat.size.max(file.offset as u64);
+        }
+        if self.sync_writes {
+            self.sync_file(file)?;
+        }
+        match error {
+            Some(err) if written == 0 => Err(err),
+            _ => {
+                self.notify(|tfs| FsEvent::Write(tfs.root.path_of(index), start as u64, written));
+                Ok(written)
+            }
+        }
+    }
+
+    pub fn read_byte(&self, file: &mut TfsFsFile) -> TfsResult<Option<u8>> {
+        if self.fill_buf(file)? == 0 {
+            return Ok(None);
+        }
+        let byte = file.cache.as_ref().unwrap().data[file.offset % BLOCK_SIZE];
+        file.offset += 1;
+        Ok(Some(byte))
+    }
+
+    // the byte read_byte would return, without moving on. looking doesn't count as an access, the
+    // read after it does
+    pub fn peek_byte(&self, file: &mut TfsFsFile) -> TfsResult<Option<u8>> {
+        if self.cache_block(file, false)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            file.cache.as_ref().unwrap().data[file.offset % BLOCK_SIZE],
+        ))
+    }
+
+    // moves the offset on by up to n bytes, stopping at the end of the file. nothing in between is
+    // read, so it's as cheap as a seek
+    pub fn skip(&self, file: &mut TfsFsFile, n: usize) -> TfsResult<usize> {
+        let inode = &self.root.inodes[self.inode_index(file)?];
+        let skipped = n.min((inode.stat.size as usize).saturating_sub(file.offset));
+        file.offset += skipped;
+        Ok(skipped)
+    }
+
+    // gets the block the offset is in into the handle's cache, if it isn't already, and returns
+    // how much of it is left to read. 0 means the end of the file
+    pub fn fill_buf(&self, file: &mut TfsFsFile) -> TfsResult<usize> {
+        self.cache_block(file, true)
+    }
+
+    fn cache_block(&self, file: &mut TfsFsFile, access: bool) -> TfsResult<usize> {
+        if !file.readable {
+            return Err(TfsError::WriteOnly);
+        }
+        let index = self.inode_index(file)?;
+        self.check_lock(file, index, false)?;
+        if access {
+            self.mark_accessed(index);
+        }
+        let inode = &self.root.inodes[index];
+        let size = inode.stat.size as usize;
+        if file.offset >= size {
+            return Ok(0);
+        }
+        let block = inode.blocks[file.offset / BLOCK_SIZE];
+        let cached = match file.cache.take() {
+            Some(cached) if cached.block == block && cached.epoch == self.epoch => cached,
+            _ => CachedBlock {
+                block,
+                epoch: self.epoch,
+                data: read_data(&self.disk, block)?,
+            },
+        };
+        file.cache = Some(cached);
+        Ok((BLOCK_SIZE - file.offset % BLOCK_SIZE).min(size - file.offset))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(inode = file.block, offset = file.offset, bytes = buf.len()),
+            ret,
+            err(level = "warn")
+        )
+    )]
+    pub fn read(&self, file: &mut TfsFsFile, buf: &mut [u8]) -> TfsResult<usize> {
+        if !file.readable {
+            return Err(TfsError::WriteOnly);
+        }
+        let index = self.inode_index(file)?;
+        self.check_lock(file, index, false)?;
+        self.mark_accessed(index);
+        let inode = &self.root.inodes[index];
+        let size = inode.stat.size as usize;
+        let mut read = 0;
+        while read < buf.len() && file.offset < size {
+            let block = read_data(&self.disk, inode.blocks[file.offset / BLOCK_SIZE])?;
+            let start = file.offset % BLOCK_SIZE;
+            // don't read past the end of the buffer, the block, or the file
+            let len = (buf.len() - read)
+                .min(BLOCK_SIZE - start)
+                .min(size - file.offset);
+            buf[read..read + len].copy_from_slice(&block[start..start + len]);
+            read += len;
+            file.offset += len;
+        }
+        Ok(read)
+    }
+
+    // pread, read at offset leaving the file's own offset where it was
+    pub fn read_at(&self, file: &mut TfsFsFile, offset: usize, buf: &mut [u8]) -> TfsResult<usize> {
+        let saved = mem::replace(&mut file.offset, offset);
+        let result = self.read(file, buf);
+        file.offset = saved;
+        result
+    }
+
+    // pwrite, the same as write but at offset, so past the end leaves a hole that reads as zeros.
+    // files opened for append still only ever get added to the end, like on linux
+    pub fn write_at(
+        &mut self,
+        file: &mut TfsFsFile,
+        offset: usize,
+        buf: &[u8],
+    ) -> TfsResult<usize> {
+        let saved = mem::replace(&mut file.offset, offset);
+        let result = self.write(file, buf);
+        file.offset = saved;
+        result
+    }
+
+    // make sure there are blocks for the first len bytes without changing the size, so writes up
+    // to there can't run out of space. it's all or nothing
+    pub fn preallocate(&mut self, file: &TfsFsFile, len: usize) -> TfsResult<()> {
+        self.check_writable()?;
+        if !file.writable {
+            return Err(TfsError::ReadOnly);
+        }
+        let inode = self.inode_index(file)?;
+        self.check_mutable(inode)?;
+        self.check_lock(file, inode, true)?;
+        self.root.inodes[inode].preallocate(&mut self.superblock, &mut self.disk, len)?;
+        self.sync()
+    }
+
+    // zero len bytes from offset without changing the size, giving back any blocks that end up
+    // entirely zero. the last block of the file is only ever zeroed, see INode::from_data
+    pub fn punch_hole(&mut self, file: &TfsFsFile, offset: u64, len: u64) -> TfsResult<()> {
+        self.check_writable()?;
+        if !file.writable {
+            return Err(TfsError::ReadOnly);
+        }
+        let index = self.inode_index(file)?;
+        self.check_lock(file, index, true)?;
+        let flags = &self.root.inodes[index].stat.flags;
+        if flags.immutable || flags.append_only {
+            return Err(TfsError::PermissionDenied(self.root.path_of(index)));
+        }
+        let inode = &mut self.root.inodes[index];
+        let size = inode.stat.size as usize;
+        let start = (offset as usize).min(size);
+        let end = (offset.saturating_add(len) as usize).min(size);
+        if start == end {
+            return Ok(());
+        }
+        self.epoch += 1;
+        let last = inode.blocks.len() - 1;
+        for index in start / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            let block = inode.blocks[index];
+            if block == 0 {
+                continue;
+            }
+            let from = start.max(index * BLOCK_SIZE) - index * BLOCK_SIZE;
+            let to = end.min((index + 1) * BLOCK_SIZE) - index * BLOCK_SIZE;
+            if from == 0 && to == BLOCK_SIZE && index != last {
+                self.superblock.mark_free(block);
+                inode.blocks[index] = 0;
+                continue;
+            }
+            let mut data = self.disk.read_block(block as usize)?;
+            data[from..to].fill(0);
+            inode.overwrite(&mut self.superblock, &mut self.disk, index, data)?;
+        }
+        inode.modified((self.clock)());
+        self.sync()
+    }
+
+    // shrink or zero extend the file to len bytes, the offset is left alone
+    pub fn truncate(&mut self, file: &mut TfsFsFile, len: u64) -> TfsResult<()> {
+        self.check_writable()?;
+        if !file.writable {
+            return Err(TfsError::ReadOnly);
+        }
+        let index = self.inode_index(file)?;
+        self.check_lock(file, index, true)?;
+        let flags = &self.root.inodes[index].stat.flags;
+        if flags.immutable || flags.append_only {
+            return Err(TfsError::PermissionDenied(self.root.path_of(index)));
+        }
+        let inode = &mut self.root.inodes[index];
+        self.epoch += 1;
+        inode.truncate(
+            &mut self.superblock,
+            &mut self.disk,
+            len as usize,
+            (self.clock)(),
+        )?;
+        self.sync()
+    }
+
+    // seeking past the end of the file is allowed, reads from there will just hit eof and writes
+    // will zero fill the gap
+    pub fn seek(&mut self, file: &mut TfsFsFile, pos: SeekFrom) -> TfsResult<u64> {
+        let inode = &self.root.inodes[self.inode_index(file)?];
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => (file.offset as u64).checked_add_signed(delta),
+            SeekFrom::End(delta) => inode.stat.size.checked_add_signed(delta),
+        }
+        .ok_or(TfsError::InvalidSeek)?;
+        file.offset = offset as usize;
+        Ok(offset)
+    }
+
+    pub fn readdir<'a>(&'a self) -> impl Iterator<Item = ReadDirEntry> + 'a {
+        self.readdir_in(None)
+    }
+
+    pub fn readdir_at<'a>(
+        &'a self,
+        path: impl AsRef<Path>,
+    ) -> TfsResult<impl Iterator<Item = ReadDirEntry> + 'a> {
+        let dir = self.root.resolve_dir(path_str(path.as_ref())?)?;
+        Ok(self.readdir_in(dir))
+    }
+
+    fn readdir_in(&self, dir: Dir) -> impl Iterator<Item = ReadDirEntry> + '_ {
+        self.root
+            .children(dir)
+            .iter()
+            .filter_map(|entry| self.dir_entry(entry))
+    }
+
+    fn dir_entry(&self, entry: &DirEntry) -> Option<ReadDirEntry> {
+        let mut entry = self.root.dir_entry(entry)?;
+        let inode = self.root.index_of(entry.inode_block)?;
+        entry.stat = self.inode_stat(inode);
+        Some(entry)
+    }
+
+    pub fn rename(&mut self, file: &mut TfsFsFile, newname: &str) -> TfsResult<()> {
+        let inode = self.inode_index(file)?;
+        let from = self.root.path_of(inode);
+        self.rename_entry(&from, newname)?;
+        self.notify(|_| FsEvent::Rename(from, newname.to_string()));
+        Ok(())
+    }
+
+    pub fn rename_path(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> TfsResult<()> {
+        let from = path_str(from.as_ref())?;
+        let to = path_str(to.as_ref())?;
+        self.rename_entry(from, to)?;
+        self.notify(|_| FsEvent::Rename(from.to_string(), to.to_string()));
+        Ok(())
+    }
+
+    // newname is a path, so this can move the entry into a different directory. only the one name
+    // moves, whatever other names the file has stay where they are
+    fn rename_entry(&mut self, from: &str, newname: &str) -> TfsResult<()> {
+        let inode = self
+            .root
+            .find(from)
+            .ok_or_else(|| TfsError::FileNotFound(from.to_string()))?;
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let (from_dir, from_name) = self.root.resolve_parent(from)?;
+        let (dir, filename) = self.root.resolve_parent(newname)?;
+        check_filename(filename)?;
+        match self.root.find_child(dir, filename) {
+            Some(existing) if existing == inode => return Ok(()),
+            Some(_) => return Err(TfsError::AlreadyExists(newname.to_string())),
+            None => (),
+        }
+        if dir == from_dir {
+            let entry = self
+                .root
+                .children_mut(dir)
+                .iter_mut()
+                .find(|entry| entry.filename == from_name)
+                .unwrap();
+            entry.filename = filename.to_string();
+        } else {
+            let mut ancestor = dir;
+            while let Some(index) = ancestor {
+                if index == inode {
+                    return Err(TfsError::MoveIntoSelf(newname.to_string()));
+                }
+                ancestor = self.root.parent_of(index);
+            }
+            self.root.reserve(dir, &mut self.superblock)?;
+            let mut entry = self.root.remove_child(from_dir, from_name).unwrap();
+            entry.filename = filename.to_string();
+            self.root.add_child(dir, entry);
+            self.root.release(&mut self.superblock);
+        }
+        self.root.inodes[inode].touch((self.clock)());
+        Ok(())
+    }
+
+    // give the regular file at existing another name, new. it only goes away once every one of
+    // its names has been removed
+    pub fn link(&mut self, existing: impl AsRef<Path>, new: impl AsRef<Path>) -> TfsResult<()> {
+        self.check_writable()?;
+        let existing = path_str(existing.as_ref())?;
+        let target = self.find_file(existing)?;
+        if self.root.inodes[target].stat.nlink == u16::MAX {
+            return Err(TfsError::TooManyLinks(existing.to_string()));
+        }
+        let (dir, filename) = self.prepare_create(path_str(new.as_ref())?)?;
+        let inode = &mut self.root.inodes[target];
+        inode.stat.nlink += 1;
+        inode.touch((self.clock)());
+        let entry = DirEntry {
+            filename: filename.to_string(),
+            inode: inode.block,
+        };
+        self.root.add_child(dir, entry);
+        self.sync()
+    }
+
+    // only the lower 9 bits are kept. with no write bits set, opening the file for writing fails
+    // with PermissionDenied
+    pub fn set_permissions(&mut self, path: impl AsRef<Path>, mode: u16) -> TfsResult<()> {
+        self.check_writable()?;
+        let path = path_str(path.as_ref())?;
+        let inode = self
+            .root
+            .find(path)
+            .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+        let inode = &mut self.root.inodes[inode];
+        inode.stat.mode = mode & 0o777;
+        inode.touch((self.clock)());
+        self.sync()
+    }
+
+    // the flags themselves can always be changed, they only guard against accidents
+    pub fn set_flags(&mut self, path: impl AsRef<Path>, flags: FileFlags) -> TfsResult<()> {
+        self.check_writable()?;
+        let path = path_str(path.as_ref())?;
+        let inode = self
+            .root
+            .find(path)
+            .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+        let inode = &mut self.root.inodes[inode];
+        inode.stat.flags = flags;
+        inode.touch((self.clock)());
+        self.sync()
+    }
+
+    pub fn chown(&mut self, path: impl AsRef<Path>, uid: u16, gid: u16) -> TfsResult<()> {
+        self.check_writable()?;
+        let path = path_str(path.as_ref())?;
+        let inode = self
+            .root
+            .find(path)
+            .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+        let inode = &mut self.root.inodes[inode];
+        (inode.stat.uid, inode.stat.gid) = (uid, gid);
+        inode.touch((self.clock)());
+        self.sync()
+    }
+
+    // for putting back the times a file had somewhere else. like utimes, ctime becomes now since
+    // that can't be faked
+    pub fn set_times(
+        &mut self,
+        file: &TfsFsFile,
+        mtime: SystemTime,
+        atime: SystemTime,
+    ) -> TfsResult<()> {
+        self.check_writable()?;
+        if !file.writable {
+            return Err(TfsError::ReadOnly);
+        }
+        let inode = self.inode_index(file)?;
+        self.set_inode_times(inode, mtime, atime)?;
+        self.sync()
+    }
+
+    pub fn set_times_path(
+        &mut self,
+        path: impl AsRef<Path>,
+        mtime: SystemTime,
+        atime: SystemTime,
+    ) -> TfsResult<()> {
+        let path = path_str(path.as_ref())?;
+        let inode = self
+            .root
+            .find(path)
+            .ok_or_else(|| TfsError::FileNotFound(path.to_string()))?;
+        self.set_inode_times(inode, mtime, atime)?;
+        self.sync()
+    }
+
+    fn set_inode_times(
+        &mut self,
+        inode: usize,
+        mtime: SystemTime,
+        atime: SystemTime,
+    ) -> TfsResult<()> {
+        self.check_writable()?;
+        for time in [mtime, atime] {
+            if disk_time(time).is_none() {
+                return Err(TfsError::TimeOutOfRange(time));
+            }
+        }
+        // or an earlier read's atime would win over this one later
+        self.fold_atimes();
+        let inode = &mut self.root.inodes[inode];
+        inode.stat.mtime = mtime;
+        inode.stat.atime = atime;
+        inode.touch((self.clock)());
+        Ok(())
+    }
+
+    pub fn statfs(&self) -> TfsResult<FsStats> {
+        let total_blocks = self.superblock.total_blocks();
+        let used_blocks = total_blocks - self.superblock.free_blocks(total_blocks);
+        Ok(FsStats {
+            block_size: BLOCK_SIZE,
+            total_blocks,
+            free_blocks: total_blocks - used_blocks,
+            used_blocks,
+            files: self.root.inodes.len(),
+            // every new file needs at least a block for its inode
+            max_files: self.root.inodes.len() + total_blocks - used_blocks,
+        })
+    }
+
+    pub fn allocation(&self) -> TfsResult<Allocation> {
+        Ok(Allocation {
+            superblock: self.superblock.clone(),
+            total_blocks: self.superblock.total_blocks(),
+        })
+    }
+
+    // make the filesystem new_size bytes, with everything past the old end free to allocate. it
+    // can only get bigger, and only as big as its checksum and refcount tables can cover
+    pub fn grow(&mut self, new_size: usize) -> TfsResult<()> {
+        self.check_writable()?;
+        if !new_size.is_multiple_of(BLOCK_SIZE) {
+            return Err(disk::DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            }
+            .into());
+        }
+        let old_blocks = self.superblock.total_blocks();
+        let blocks = new_size / BLOCK_SIZE;
+        if blocks < old_blocks || blocks > self.superblock.max_blocks() {
+            return Err(TfsError::SizeError { size: new_size });
+        }
+        if blocks == old_blocks {
+            return Ok(());
+        }
+        self.disk.extend(blocks)?;
+        self.superblock.set_total_blocks(blocks);
+        // mount looks for the backup in the last block, so it has to move to the new one
+        let old_backup = self.superblock.backup_block;
+        if old_backup != 0 && old_backup as usize == old_blocks - 1 {
+            let backup = (blocks - 1) as u16;
+            self.superblock.mark_allocated(backup);
+            self.superblock.backup_block = backup;
+            self.superblock.mark_free(old_backup);
+        }
+        self.sync()
+    }
+
+    // make the filesystem new_size bytes, moving whatever is past the new end into free blocks
+    // before it and cutting the image down to match. handles to a file whose inode had to move
+    // stop working
+    pub fn shrink(&mut self, new_size: usize) -> TfsResult<()> {
+        self.check_writable()?;
+        // inodes are about to change blocks, which is what atimes are kept by
+        self.fold_atimes();
+        if !new_size.is_multiple_of(BLOCK_SIZE) {
+            return Err(disk::DiskError::InvalidSize {
+                block_size: BLOCK_SIZE,
+            }
+            .into());
+        }
+        let old_blocks = self.superblock.total_blocks();
+        let blocks = new_size / BLOCK_SIZE;
+        if blocks > old_blocks {
+            return Err(TfsError::SizeError { size: new_size });
+        }
+        if blocks == old_blocks {
+            return Ok(());
+        }
+        // the backup has to end up in the new last block, so that's taken too
+        let old_backup = self.superblock.backup_block;
+        let moves_backup = old_backup != 0 && old_backup as usize == old_blocks - 1;
+        let end = if moves_backup { blocks - 1 } else { blocks };
+        // the filesystem's own tables can't move, and neither can the first root block
+        if end < 2
+            || self
+                .superblock
+                .reserved_blocks()
+                .into_iter()
+                .any(|block| block != old_backup && block as usize >= end)
+        {
+            return Err(TfsError::SizeError { size: new_size });
+        }
+        let moving: Vec<u16> = (end..old_blocks)
+            .map(|block| block as u16)
+            .filter(|&block| block != old_backup && self.superblock.is_allocated(block))
+            .collect();
+        let free = self.superblock.free_blocks(end);
+        if moving.len() > free {
+            return Err(TfsError::NoRoomToShrink {
+                blocks: moving.len(),
+                free,
+            });
+        }
+
+        // copy everything first, so a failure part way leaves the old blocks as they were
+        self.superblock.set_total_blocks(end);
+        let mut moved = HashMap::new();
+        for &old in &moving {
+            let new = self.superblock.allocate_block().unwrap();
+            moved.insert(old, new);
+            let copied = self
+                .disk
+                .read_block(old as usize)
+                .and_then(|data| self.disk.write_block(new as usize, data));
+            if let Err(err) = copied {
+                for &new in moved.values() {
+                    self.superblock.mark_free(new);
+                }
+                self.superblock.set_total_blocks(old_blocks);
+                return Err(err.into());
+            }
+        }
+        for (&old, &new) in &moved {
+            // a shared block's count goes with it
+            if self.superblock.is_shared(old) {
+                let refcount = self.superblock.refcount(old);
+                self.superblock.set_refcount(new, refcount);
+                self.superblock.set_refcount(old, 0);
+            }
+            self.superblock.mark_free(old);
+        }
+
+        let remap = |block: &mut u16| match moved.get(block) {
+            Some(&new) => {
+                *block = new;
+                true
+            }
+            None => false,
+        };
+        let root_moved = self
+            .root
+            .entries
+            .iter_mut()
+            .map(|entry| &mut entry.inode)
+            .chain(&mut self.root.chain)
+            .fold(false, |changed, block| remap(block) | changed);
+        self.root.dirty |= root_moved;
+        for inode in &mut self.root.inodes {
+            // a directory's entries point at inodes that may have moved as well
+            inode.dirty |= [&mut inode.block]
+                .into_iter()
+                .chain(&mut inode.blocks)
+                .chain(&mut inode.indirect)
+                .chain(inode.children.iter_mut().map(|entry| &mut entry.inode))
+                .fold(false, |changed, block| remap(block) | changed);
+        }
+        // the handles holding these can't find their inode any more, so no one can let go of them
+        self.locks().retain(|block, _| !moved.contains_key(block));
+        self.locks.changed.notify_all();
+        self.open_files
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|block, _| !moved.contains_key(block));
+
+        self.superblock.set_total_blocks(blocks);
+        if moves_backup {
+            let backup = (blocks - 1) as u16;
+            self.superblock.mark_allocated(backup);
+            self.superblock.backup_block = backup;
+            self.superblock.mark_free(old_backup);
+        }
+        // everything has to point at the new blocks on the disk before the old ones are cut off
+        let batch_depth = mem::take(&mut self.batch_depth);
+        let synced = self.sync();
+        self.batch_depth = batch_depth;
+        synced?;
+        self.disk.sync_all()?;
+        Ok(self.disk.truncate(blocks)?)
+    }
+
+    // recompute the allocation table from what the inodes actually use, freeing anything leaked.
+    // returns how many blocks were reclaimed
+    pub fn rebuild_allocation(&mut self) -> TfsResult<usize> {
+        self.check_writable()?;
+        let before = self.superblock.free_count();
+        self.superblock.clear_allocation();
+        let mut used = self.superblock.reserved_blocks();
+        used.push(1);
+        used.extend(&self.root.chain);
+        for inode in &self.root.inodes {
+            used.push(inode.block);
+            used.extend(&inode.blocks);
+            used.extend(&inode.indirect);
+        }
+        for block in used {
+            self.superblock.mark_allocated(block);
+        }
+        let after = self.superblock.free_count();
+        self.sync()?;
+        Ok(after - before)
+    }
+
+    // copy the whole image as it is right now to a new one at dest, which mounts as the same
+    // filesystem, without having to unmount
+    pub fn snapshot(&mut self, dest: impl AsRef<Path>) -> TfsResult<()> {
+        // a batch doesn't get to hold anything back from the copy
+        let batch_depth = mem::take(&mut self.batch_depth);
+        let synced = self.sync();
+        self.batch_depth = batch_depth;
+        synced?;
+        let mut disk = self.disk.snapshot(dest)?;
+        // the copy isn't mounted, so it shouldn't say it is
+        if !self.read_only {
+            if self.superblock.checksum_blocks != 0 {
+                disk.enable_checksums(
+                    self.superblock.checksum_start as usize,
+                    self.superblock.checksum_blocks as usize,
+                )?;
+            }
+            let mut superblock = self.superblock.clone();
+            superblock.clean = true;
+            superblock.dirty = true;
+            superblock.sync(&mut disk)?;
+        }
+        Ok(disk.sync_all()?)
+    }
+
+    pub fn exists(&self, filename: impl AsRef<Path>) -> bool {
+        path_str(filename.as_ref()).is_ok_and(|filename| self.root.find(filename).is_some())
+    }
+
+    // pure lookup, unlike open this doesn't touch atime
+    pub fn metadata(&self, filename: impl AsRef<Path>) -> TfsResult<Stat> {
+        let filename = path_str(filename.as_ref())?;
+        let inode = self
+            .root
+            .find(filename)
+            .ok_or_else(|| TfsError::FileNotFound(filename.to_string()))?;
+        Ok(self.inode_stat(inode))
+    }
+
+    // through a handle of its own, so locks held through any other still keep it out. nothing can
+    // remove the file while it's being read, so the handle doesn't need to be counted as open
+    pub fn read_file(&self, filename: impl AsRef<Path>) -> TfsResult<Vec<u8>> {
+        let inode = self.find_file(path_str(filename.as_ref())?)?;
+        let INode {
+            block,
+            generation,
+            ref stat,
+            ..
+        } = self.root.inodes[inode];
+        let mut file = TfsFsFile {
+            id: self.next_handle.fetch_add(1, Ordering::Relaxed),
+            inode,
+            block,
+            generation,
+            offset: 0,
+            readable: true,
+            writable: false,
+            append: false,
+            cache: None,
+        };
+        let mut contents = vec![0; stat.size as usize];
+        self.read(&mut file, &mut contents)?;
+        Ok(contents)
+    }
+
+    // replaces any existing contents of the file
+    pub fn write_file(&mut self, filename: impl AsRef<Path>, contents: &[u8]) -> TfsResult<()> {
+        let mut file = self.create(filename)?;
+        let written = self.write(&mut file, contents);
+        self.release(&file);
+        let written = written?;
+        self.sync()?;
+        if written < contents.len() {
+            return Err(TfsError::OutOfSpace);
+        }
+        Ok(())
+    }
+
+    // the index of the regular file at path
+    fn find_file(&self, path: &str) -> TfsResult<usize> {
+        match self.root.find(path) {
+            Some(inode) if self.root.inodes[inode].is_dir() => {
+                Err(TfsError::IsADirectory(path.to_string()))
+            }
+            Some(inode) => Ok(inode),
+            None => Err(TfsError::FileNotFound(path.to_string())),
+        }
+    }
+
+    // returns the number of bytes copied
+    pub fn copy(&mut self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> TfsResult<u64> {
+        self.check_writable()?;
+        let src = self.find_file(path_str(src.as_ref())?)?;
+        let dst = path_str(dst.as_ref())?;
+        let (dir, filename) = self.prepare_create(dst)?;
+        let INode {
+            stat,
+            preallocated,
+            blocks,
+            indirect,
+            ..
+        } = self.root.inodes[src].clone();
+        // allocate everything up front so running out of space can't leave a partial copy behind
+        let data_blocks = blocks.iter().filter(|&&block| block != 0).count();
+        let Some(mut copied) = self
+            .superblock
+            .allocate_blocks(data_blocks + indirect.len() + 1)
+        else {
+            self.root.release(&mut self.superblock);
+            return Err(TfsError::OutOfSpace);
+        };
+        let block = copied.pop().unwrap();
+        let copied_indirect = copied.split_off(data_blocks);
+        // holes stay holes
+        let mut fresh = copied.into_iter();
+        let copied: Vec<u16> = blocks
+            .iter()
+            .map(|&from| if from == 0 { 0 } else { fresh.next().unwrap() })
+            .collect();
+        let result = blocks.iter().zip(&copied).try_for_each(|(&from, &to)| {
+            if from == 0 {
+                return Ok(());
+            }
+            let data = self.disk.read_block(from as usize)?;
+            self.disk.write_block(to as usize, data)
+        });
+        if let Err(err) = result {
+            for block in copied.into_iter().chain(copied_indirect).chain([block]) {
+                self.superblock.mark_free(block);
+            }
+            self.root.release(&mut self.superblock);
+            return Err(err.into());
+        }
+        let inode = self.root.create_inode(
+            dir,
+            block,
+            filename.to_string(),
+            FileKind::Regular,
+            (self.clock)(),
+        );
+        let inode = &mut self.root.inodes[inode];
+        inode.blocks = copied;
+        inode.indirect = copied_indirect;
+        inode.stat.size = stat.size;
+        inode.stat.mtime = stat.mtime;
+        (inode.stat.uid, inode.stat.gid) = self.owner;
+        inode.preallocated = preallocated;
+        self.sync()?;
+        Ok(stat.size)
+    }
+
+    // like copy, except the new file shares the data blocks with src until one of them writes to
+    // them, so it only costs an inode and its indirect blocks. needs an image made with reflinks
+    pub fn clone_file(&mut self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> TfsResult<u64> {
+        self.check_writable()?;
+        if !self.superblock.has_refcounts() {
+            return Err(TfsError::ReflinksDisabled);
+        }
+        let src = self.find_file(path_str(src.as_ref())?)?;
+        let dst = path_str(dst.as_ref())?;
+        let (dir, filename) = self.prepare_create(dst)?;
+        let INode {
+            stat,
+            preallocated,
+            blocks,
+            indirect,
+            ..
+        } = self.root.inodes[src].clone();
+        let Some(mut cloned_indirect) = self.superblock.allocate_blocks(indirect.len() + 1) else {
+            self.root.release(&mut self.superblock);
+            return Err(TfsError::OutOfSpace);
+        };
+        let data: Vec<u16> = blocks.iter().copied().filter(|&block| block != 0).collect();
+        if let Err(err) = self.superblock.share_blocks(&data) {
+            for block in cloned_indirect {
+                self.superblock.mark_free(block);
+            }
+            self.root.release(&mut self.superblock);
+            return Err(err);
+        }
+        let block = cloned_indirect.pop().unwrap();
+        let inode = self.root.create_inode(
+            dir,
+            block,
+            filename.to_string(),
+            FileKind::Regular,
+            (self.clock)(),
+        );
+        let inode = &mut self.root.inodes[inode];
+        inode.blocks = blocks;
+        inode.indirect = cloned_indirect;
+        inode.stat.size = stat.size;
+        inode.stat.mtime = stat.mtime;
+        (inode.stat.uid, inode.stat.gid) = self.owner;
+        inode.preallocated = preallocated;
+        self.sync()?;
+        Ok(stat.size)
+    }
+
+    pub fn remove(&mut self, filename: impl AsRef<Path>) -> TfsResult<()> {
+        self.check_writable()?;
+        let filename = path_str(filename.as_ref())?;
+        let index = self
+            .root
+            .find(filename)
+            .ok_or_else(|| TfsError::FileNotFound(filename.to_string()))?;
+        if self.root.inodes[index].is_dir() {
+            return Err(TfsError::IsADirectory(filename.to_string()));
+        }
+        self.check_mutable(index)?;
+        let (dir, name) = self.root.resolve_parent(filename)?;
+        let inode = &mut self.root.inodes[index];
+        if inode.stat.nlink > 1 {
+            // its other names keep it around, blocks and all
+            inode.stat.nlink -= 1;
+            inode.touch((self.clock)());
+            self.root.remove_child(dir, name);
+            self.root.release(&mut self.superblock);
+            self.notify(|_| FsEvent::Remove(filename.to_string()));
+            return Ok(());
+        }
+        // its blocks would be handed out again while the handles could still write to them
+        if self
+            .open_files()
+            .contains_key(&self.root.inodes[index].block)
+        {
+            return Err(TfsError::FileBusy(filename.to_string()));
+        }
+        self.root.remove_child(dir, name);
+        let inode = self.root.inodes.remove(index);
+        self.locks().remove(&inode.block);
+        self.locks.changed.notify_all();
+        for block in inode.blocks.into_iter().chain(inode.indirect) {
+            self.superblock.mark_free(block);
+        }
+        self.superblock.mark_free(inode.block);
+        self.root.release(&mut self.superblock);
+        self.notify(|_| FsEvent::Remove(filename.to_string()));
+        Ok(())
+    }
+
+    // the event is only made if there's an observer to tell
+    fn notify(&mut self, event: impl FnOnce(&Self) -> FsEvent) {
+        if self.observer.is_some() {
+            let event = event(self);
+            self.events.push(event);
+        }
+    }
+
+    fn check_writable(&self) -> TfsResult<()> {
+        if self.read_only {
+            return Err(TfsError::ReadOnlyFilesystem);
+        }
+        Ok(())
+    }
+
+    // immutable files can't be renamed or removed either
+    fn check_mutable(&self, inode: usize) -> TfsResult<()> {
+        if self.root.inodes[inode].stat.flags.immutable {
+            return Err(TfsError::PermissionDenied(self.root.path_of(inode)));
+        }
+        Ok(())
+    }
+
+    // the inode a handle refers to, which may have moved in the inode list since it was opened
+    fn inode_index(&self, file: &TfsFsFile) -> TfsResult<usize> {
+        let index = match self.root.inodes.get(file.inode) {
+            Some(inode) if inode.block == file.block => file.inode,
+            _ => self
+                .root
+                .index_of(file.block)
+                .ok_or(TfsError::InvalidDesc)?,
+        };
+        let inode = &self.root.inodes[index];
+        if inode.generation != file.generation || inode.is_dir() {
+            return Err(TfsError::InvalidDesc);
+        }
+        Ok(index)
+    }
+
+    pub fn stat(&self, file: &TfsFsFile) -> TfsResult<Stat> {
+        Ok(self.inode_stat(self.inode_index(file)?))
+    }
+
+    // the inode's stat, with any atime a read has left that hasn't been folded in yet
+    fn inode_stat(&self, index: usize) -> Stat {
+        let inode = &self.root.inodes[index];
+        let mut stat = inode.stat();
+        if let Some(&(generation, atime)) = self.accessed().get(&inode.block) {
+            if generation == inode.generation {
+                stat.atime = atime;
+            }
+        }
+        stat
+    }
+
+    fn accessed(&self) -> MutexGuard<'_, HashMap<u16, (u32, SystemTime)>> {
+        self.accessed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn mark_accessed(&self, index: usize) {
+        if self.noatime {
+            return;
+        }
+        let inode = &self.root.inodes[index];
+        self.accessed()
+            .insert(inode.block, (inode.generation, (self.clock)()));
+    }
+
+    // anything left for an inode that's since gone, or whose block went to another, is dropped
+    fn fold_atimes(&mut self) {
+        let accessed = mem::take(
+            self.accessed
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if accessed.is_empty() {
+            return;
+        }
+        for inode in self.root.inodes.iter_mut() {
+            match accessed.get(&inode.block) {
+                Some(&(generation, atime)) if generation == inode.generation => {
+                    inode.stat.atime = atime;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // another descriptor for the same file, starting at the same offset but moving on its own
+    // locks held through the original aren't held through the copy
+    pub fn try_clone(&self, file: &TfsFsFile) -> TfsResult<TfsFsFile> {
+        let inode = self.inode_index(file)?;
+        let id = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.open_files().entry(file.block).or_default().push(id);
+        Ok(TfsFsFile {
+            id,
+            inode,
+            block: file.block,
+            generation: file.generation,
+            offset: file.offset,
+            readable: file.readable,
+            writable: file.writable,
+            append: file.append,
+            cache: file.cache.clone(),
+        })
+    }
+
+    // how many handles are open on the file, through any of its names
+    pub fn open_handles(&self, filename: impl AsRef<Path>) -> usize {
+        let Some(inode) = path_str(filename.as_ref())
+            .ok()
+            .and_then(|filename| self.root.find(filename))
+        else {
+            return 0;
+        };
+        self.open_files()
+            .get(&self.root.inodes[inode].block)
+            .map_or(0, Vec::len)
+    }
+
+    fn locks(&self) -> MutexGuard<'_, HashMap<u16, FileLock>> {
+        self.locks
+            .held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn open_files(&self) -> MutexGuard<'_, HashMap<u16, Vec<u64>>> {
+        self.open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // takes or swaps this handle's lock on the file, erroring rather than waiting if another
+    // handle is in the way
+    pub fn try_lock(&self, file: &TfsFsFile, exclusive: bool) -> TfsResult<()> {
+        self.try_lock_in(&mut self.locks(), file, exclusive)
+    }
+
+    fn try_lock_in(
+        &self,
+        locks: &mut HashMap<u16, FileLock>,
+        file: &TfsFsFile,
+        exclusive: bool,
+    ) -> TfsResult<()> {
+        let index = self.inode_index(file)?;
+        let lock = match locks.remove(&file.block) {
+            None if exclusive => FileLock::Exclusive(file.id),
+            None => FileLock::Shared(vec![file.id]),
+            Some(FileLock::Exclusive(id)) if id == file.id && exclusive => FileLock::Exclusive(id),
+            Some(FileLock::Exclusive(id)) if id == file.id => FileLock::Shared(vec![id]),
+            Some(FileLock::Shared(ids)) if exclusive && ids == [file.id] => {
+                FileLock::Exclusive(file.id)
+            }
+            Some(FileLock::Shared(mut ids)) if !exclusive => {
+                if !ids.contains(&file.id) {
+                    ids.push(file.id);
+                }
+                FileLock::Shared(ids)
+            }
+            Some(lock) => {
+                locks.insert(file.block, lock);
+                return Err(TfsError::WouldBlock(self.root.path_of(index)));
+            }
+        };
+        locks.insert(file.block, lock);
+        // going from exclusive to shared can let others in
+        self.locks.changed.notify_all();
+        Ok(())
+    }
+
+    pub fn unlock(&self, file: &TfsFsFile) -> TfsResult<()> {
+        self.inode_index(file)?;
+        self.release(file);
+        Ok(())
+    }
+
+    // drops whatever lock the handle has and closes it, even if its file has gone. closing twice is
+    // the same as once
+    fn release(&self, file: &TfsFsFile) {
+        let mut open_files = self.open_files();
+        if let Some(ids) = open_files.get_mut(&file.block) {
+            ids.retain(|&id| id != file.id);
+            if ids.is_empty() {
+                open_files.remove(&file.block);
+            }
+        }
+        drop(open_files);
+        let mut locks = self.locks();
+        match locks.get_mut(&file.block) {
+            Some(FileLock::Exclusive(id)) if *id == file.id => {
+                locks.remove(&file.block);
+            }
+            Some(FileLock::Shared(ids)) => {
+                ids.retain(|&id| id != file.id);
+                if ids.is_empty() {
+                    locks.remove(&file.block);
+                }
+            }
+            _ => return,
+        }
+        self.locks.changed.notify_all();
+    }
+
+    // another handle's exclusive lock keeps everyone else out, a shared one only stops writes
+    fn check_lock(&self, file: &TfsFsFile, inode: usize, write: bool) -> TfsResult<()> {
+        let blocked = match self.locks().get(&file.block) {
+            Some(FileLock::Exclusive(id)) => *id != file.id,
+            Some(FileLock::Shared(ids)) => write && ids.iter().any(|&id| id != file.id),
+            None => false,
+        };
+        if blocked {
+            return Err(TfsError::WouldBlock(self.root.path_of(inode)));
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, err))]
+    pub fn sync(&mut self) -> TfsResult<()> {
+        if self.read_only || self.batch_depth != 0 {
+            return Ok(());
+        }
+        self.commit_sync(|tfs| {
+            tfs.superblock.sync(&mut tfs.disk)?;
+            tfs.root.sync(&mut tfs.disk)
+        })?;
+        if self.sync_policy == SyncPolicy::Always {
+            self.disk.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // sync, and then make sure it actually hit the disk whatever the sync policy is
+    pub fn sync_all(&mut self) -> TfsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.sync()?;
+        Ok(self.disk.sync_all()?)
+    }
+
+    pub fn sync_file(&mut self, file: &TfsFsFile) -> TfsResult<()> {
+        let inode = self.inode_index(file)?;
+        if self.read_only || self.batch_depth != 0 {
+            return Ok(());
+        }
+        self.commit_sync(|tfs| {
+            tfs.superblock.sync(&mut tfs.disk)?;
+            tfs.root.inodes[inode].sync(&mut tfs.disk)?;
+            tfs.root.sync_table(&mut tfs.disk)
+        })
+    }
+
+    // write the metadata as one transaction. if any of it fails, whatever was dirty before stays
+    // dirty so the next sync writes it all again
+    fn commit_sync(&mut self, write: impl FnOnce(&mut Self) -> TfsResult<()>) -> TfsResult<()> {
+        self.fold_atimes();
+        let superblock = (self.superblock.dirty, self.superblock.refcounts_dirty);
+        let root = self.root.dirty_flags();
+        self.disk.begin_transaction();
+        let result = write(self).and_then(|()| {
+            // data goes out ahead of the metadata pointing at it
+            self.disk.flush()?;
+            Ok(self.disk.commit()?)
+        });
+        if result.is_err() {
+            self.superblock.dirty |= superblock.0;
+            self.superblock.refcounts_dirty |= superblock.1;
+            self.root.restore_dirty(&root);
+        }
+        result
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Drop for TfsFs<D> {
+    fn drop(&mut self) {
+        // panicking here could abort a thread that's already unwinding, and there's no one to give
+        // the error to. callers who need to know unmount explicitly first
+        if let Err(_err) = self.unmount() {
+            event!(warn, error = %_err, "unmount failed while dropping");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::testing::{mkfs_fresh, CrashDisk};
+
+    #[test]
+    fn mkfs_works() {
+        const DISK_PATH: &str = "mkfs-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        let superblock = disk.read_block(0).unwrap();
+        let superblock: SuperBlockData = decode(&superblock).unwrap();
+        assert_eq!(superblock.magic_number, 0x5A);
+        assert_eq!(superblock.root_inode, 1);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn mount_works() {
+        const DISK_PATH: &str = "mount-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let _tfs = TfsFs::mount(DISK_PATH).unwrap();
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_works() {
+        const DISK_PATH: &str = "open-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let _desc = tfs.open("test.txt").unwrap();
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn write_works() {
+        const DISK_PATH: &str = "write-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            let mut desc = tfs.open("test.txt").unwrap();
+            tfs.write(&mut desc, "Hello, World!".as_bytes()).unwrap();
+            let harry = include_bytes!("../harry-sm.jpg");
+            let mut desc2 = tfs.open("cat.jpg").unwrap();
+            tfs.write(&mut desc2, harry).unwrap();
+        }
+        {
+            let tfs = TfsFs::mount(DISK_PATH).unwrap();
+            assert_eq!(tfs.root.inodes.len(), 2);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn read_works() {
+        const DISK_PATH: &str = "read-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let harry = include_bytes!("../harry-sm.jpg");
+        let mut desc = tfs.open("cat.jpg").unwrap();
+        tfs.write(&mut desc, harry).unwrap();
+        let mut desc = tfs.open("cat.jpg").unwrap();
+
+        // odd sized buffer so reads start mid block and span block boundaries
+        let mut cat = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            let read = tfs.read(&mut desc, &mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            cat.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(cat, harry);
+
+        assert_eq!(tfs.read(&mut desc, &mut []).unwrap(), 0);
+        let mut desc = tfs.open("cat.jpg").unwrap();
+        assert_eq!(tfs.read(&mut desc, &mut []).unwrap(), 0);
+        assert_eq!(desc.offset, 0);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn read_partial_block_works() {
+        const DISK_PATH: &str = "read-partial-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, b"Hello, World!").unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        let mut buf = [0; BLOCK_SIZE * 2];
+        assert_eq!(tfs.read(&mut desc, &mut buf).unwrap(), 13);
+        assert_eq!(&buf[..13], b"Hello, World!");
+        assert_eq!(tfs.read(&mut desc, &mut buf).unwrap(), 0);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    // stop without syncing or unmounting, as if the process died
+    fn crash(tfs: Tfs) {
+        lock_read(&tfs.tfs).disk.release_lock();
+        std::mem::forget(tfs);
+    }
+
+    fn read_all(tfs: &mut TfsFs<impl BlockDevice<BLOCK_SIZE>>, filename: &str) -> Vec<u8> {
+        let mut desc = tfs.open(filename).unwrap();
+        let mut buf = vec![0; tfs.root.inodes[desc.inode].stat.size as usize];
+        assert_eq!(tfs.read(&mut desc, &mut buf).unwrap(), buf.len());
+        buf
+    }
+
+    #[test]
+    fn overwrite_works() {
+        const DISK_PATH: &str = "overwrite-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, b"Hello, World!").unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, b"abc").unwrap();
+        assert_eq!(read_all(&mut tfs, "test.txt"), b"abclo, World!");
+
+        // middle of the file
+        tfs.seek(&mut desc, SeekFrom::Start(7)).unwrap();
+        tfs.write(&mut desc, b"Earth").unwrap();
+        assert_eq!(read_all(&mut tfs, "test.txt"), b"abclo, Earth!");
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 1);
+
+        // extending past the end
+        tfs.write(&mut desc, b"lings!").unwrap();
+        assert_eq!(read_all(&mut tfs, "test.txt"), b"abclo, Earthlings!");
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn overwrite_block_boundary_works() {
+        const DISK_PATH: &str = "overwrite-boundary-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, &[1; BLOCK_SIZE * 2]).unwrap();
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 2);
+
+        tfs.seek(&mut desc, SeekFrom::Start(BLOCK_SIZE as u64))
+            .unwrap();
+        tfs.write(&mut desc, &[2; BLOCK_SIZE]).unwrap();
+        let mut expected = vec![1; BLOCK_SIZE];
+        expected.extend([2; BLOCK_SIZE]);
+        assert_eq!(read_all(&mut tfs, "test.txt"), expected);
+
+        // starting exactly at the end shouldn't touch the existing blocks
+        tfs.write(&mut desc, &[3; BLOCK_SIZE]).unwrap();
+        expected.extend([3; BLOCK_SIZE]);
+        assert_eq!(read_all(&mut tfs, "test.txt"), expected);
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 3);
+
+        // writing after a gap past the end zero fills it
+        tfs.seek(&mut desc, SeekFrom::End(BLOCK_SIZE as i64))
+            .unwrap();
+        tfs.write(&mut desc, &[4; 10]).unwrap();
+        expected.extend([0; BLOCK_SIZE]);
+        expected.extend([4; 10]);
+        assert_eq!(read_all(&mut tfs, "test.txt"), expected);
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 5);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn truncate_works() {
+        const DISK_PATH: &str = "truncate-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let harry = include_bytes!("../harry-sm.jpg");
+        let freed = {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            let mut desc = tfs.open("cat.jpg").unwrap();
+            tfs.write(&mut desc, harry).unwrap();
+            let freed = tfs.root.inodes[desc.inode].blocks[1..].to_vec();
+            tfs.truncate(&mut desc, 100).unwrap();
+            freed
+        };
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            assert_eq!(tfs.root.inodes[0].stat.size, 100);
+            assert_eq!(tfs.root.inodes[0].blocks.len(), 1);
+            assert_eq!(read_all(&mut tfs, "cat.jpg"), harry[..100]);
+            for block in freed {
+                assert_eq!(tfs.superblock.allocate_block(), Some(block));
+            }
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn truncate_extend_works() {
+        const DISK_PATH: &str = "truncate-extend-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, &[1; BLOCK_SIZE + 10]).unwrap();
+        // shrinking then growing again shouldn't bring back the old bytes
+        tfs.truncate(&mut desc, 5).unwrap();
+        tfs.truncate(&mut desc, BLOCK_SIZE as u64 * 2).unwrap();
+        let mut expected = vec![1; 5];
+        expected.resize(BLOCK_SIZE * 2, 0);
+        assert_eq!(read_all(&mut tfs, "test.txt"), expected);
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 2);
+
+        assert!(matches!(
+            tfs.truncate(&mut desc, (MAX_FILE_BLOCKS * BLOCK_SIZE + 1) as u64),
+            Err(TfsError::OutOfSpace)
+        ));
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 2);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_options_works() {
+        const DISK_PATH: &str = "open-options-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        assert!(matches!(
+            tfs.open_with("test.txt", TfsOpenOptions::new().read(true)),
+            Err(TfsError::FileNotFound(_))
+        ));
+        assert_eq!(tfs.root.inodes.len(), 0);
+
+        let mut desc = tfs
+            .open_with("test.txt", TfsOpenOptions::new().write(true).create(true))
+            .unwrap();
+        tfs.write(&mut desc, b"Hello").unwrap();
+        assert!(matches!(
+            tfs.read(&mut desc, &mut [0; 5]),
+            Err(TfsError::WriteOnly)
+        ));
+
+        let mut desc = tfs
+            .open_with("test.txt", TfsOpenOptions::new().append(true))
+            .unwrap();
+        assert_eq!(desc.offset, 5);
+        tfs.write(&mut desc, b", World!").unwrap();
+        tfs.seek(&mut desc, SeekFrom::Start(0)).unwrap();
+        tfs.write(&mut desc, b"!").unwrap();
+        assert_eq!(read_all(&mut tfs, "test.txt"), b"Hello, World!!");
+
+        let mut desc = tfs
+            .open_with("test.txt", TfsOpenOptions::new().read(true))
+            .unwrap();
+        assert!(matches!(
+            tfs.write(&mut desc, b"abc"),
+            Err(TfsError::ReadOnly)
+        ));
+
+        let blocks = tfs.root.inodes[desc.inode].blocks.clone();
+        let desc = tfs
+            .open_with("test.txt", TfsOpenOptions::new().write(true).truncate(true))
+            .unwrap();
+        let inode = &tfs.root.inodes[desc.inode];
+        assert_eq!(inode.stat.size, 0);
+        assert!(inode.blocks.is_empty());
+        for block in blocks {
+            assert_eq!(tfs.superblock.allocate_block(), Some(block));
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_existing_works() {
+        const DISK_PATH: &str = "open-existing-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            let allocated_blocks = tfs.superblock.allocated_blocks;
+            assert!(matches!(
+                tfs.open_existing("missing"),
+                Err(TfsError::FileNotFound(_))
+            ));
+            assert_eq!(tfs.superblock.allocated_blocks, allocated_blocks);
+            assert_eq!(tfs.root.inodes.len(), 0);
+
+            let mut desc = tfs.create("test.txt").unwrap();
+            tfs.write(&mut desc, b"Hello, World!").unwrap();
+            let mut desc = tfs.open_existing("test.txt").unwrap();
+            tfs.write(&mut desc, b"J").unwrap();
+        }
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            assert_eq!(read_all(&mut tfs, "test.txt"), b"Jello, World!");
+            tfs.create("test.txt").unwrap();
+            assert_eq!(read_all(&mut tfs, "test.txt"), b"");
+            assert_eq!(tfs.root.inodes.len(), 1);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn create_new_works() {
+        let mut disk = Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap();
+        {
+            let tfs = Tfs::mount_on(&mut disk, &MountOptions::new()).unwrap();
+            tfs.create_new("test.lck").unwrap().write(b"1234").unwrap();
+            assert!(matches!(
+                tfs.create_new("test.lck"),
+                Err(TfsError::AlreadyExists(_))
+            ));
+        }
+        {
+            let tfs = Tfs::mount_mem(disk).unwrap();
+            assert_eq!(tfs.readdir().len(), 1);
+            assert_eq!(tfs.read("test.lck").unwrap(), b"1234");
+        }
+    }
+
+    #[test]
+    fn metadata_works() {
+        let disk = Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap();
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert!(!tfs.exists("test.txt"));
+        assert!(matches!(
+            tfs.metadata("test.txt"),
+            Err(TfsError::FileNotFound(_))
+        ));
+        assert_eq!(tfs.readdir().len(), 0);
+
+        tfs.open("test.txt").unwrap().write(b"Hello").unwrap();
+        assert!(tfs.exists("test.txt"));
+        let stat = tfs.metadata("test.txt").unwrap();
+        assert_eq!(stat.size, 5);
+        assert_eq!(tfs.metadata("test.txt").unwrap().atime, stat.atime);
+    }
+
+    #[test]
+    fn copy_works() {
+        const DISK_PATH: &str = "copy-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let harry = include_bytes!("../harry-sm.jpg");
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            let mut desc = tfs.open("cat.jpg").unwrap();
+            tfs.write(&mut desc, harry).unwrap();
+            assert_eq!(
+                tfs.copy("cat.jpg", "cat2.jpg").unwrap() as usize,
+                harry.len()
+            );
+            let src = &tfs.root.inodes[0].stat;
+            let dst = &tfs.root.inodes[1].stat;
+            assert_eq!(src.mtime, dst.mtime);
+            assert!(dst.ctime >= src.ctime);
+            assert_eq!((dst.uid, dst.gid), process_owner());
+
+            assert!(matches!(
+                tfs.copy("cat.jpg", "cat2.jpg"),
+                Err(TfsError::AlreadyExists(_))
+            ));
+            assert!(matches!(
+                tfs.copy("dog.jpg", "dog2.jpg"),
+                Err(TfsError::FileNotFound(_))
+            ));
+        }
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            assert_eq!(read_all(&mut tfs, "cat2.jpg"), harry);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn clone_file_works() {
+        const DISK_PATH: &str = "clone-disk.bin";
+        let harry = include_bytes!("../harry-sm.jpg");
+        MkfsOptions::new()
+            .reflinks(true)
+            .file("cat.jpg", *harry)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let free = tfs.statfs().unwrap().free_blocks;
+        assert_eq!(
+            tfs.clone_file("cat.jpg", "clone.jpg").unwrap() as usize,
+            harry.len()
+        );
+        // just the new inode
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free - 1);
+        assert_eq!(tfs.read("clone.jpg").unwrap(), harry);
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.open("clone.jpg").unwrap().write(b"meow").unwrap();
+        // the written block got copied, the rest are still shared
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free - 2);
+        assert_eq!(tfs.read("cat.jpg").unwrap(), harry);
+        let mut cloned = harry.to_vec();
+        cloned[..4].copy_from_slice(b"meow");
+        assert_eq!(tfs.read("clone.jpg").unwrap(), cloned);
+        tfs.remove("cat.jpg").unwrap();
+        assert_eq!(tfs.read("clone.jpg").unwrap(), cloned);
+        tfs.remove("clone.jpg").unwrap();
+        assert_eq!(
+            tfs.statfs().unwrap().free_blocks,
+            free + harry.len().div_ceil(BLOCK_SIZE) + 1
+        );
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        MkfsOptions::new().force(true).mkfs(DISK_PATH).unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("cat.jpg", harry).unwrap();
+        assert!(matches!(
+            tfs.clone_file("cat.jpg", "clone.jpg"),
+            Err(TfsError::ReflinksDisabled)
+        ));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn clone_truncate_works() {
+        let contents = [0x42; BLOCK_SIZE * 4];
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(
+            &mut disk,
+            MkfsOptions::new()
+                .blocks(64)
+                .reflinks(true)
+                .file("a", contents),
+        )
+        .unwrap();
+        let tfs = Tfs::mount_on(&mut disk, &MountOptions::new()).unwrap();
+        tfs.clone_file("a", "b").unwrap();
+        let mut fill = tfs.open("fill").unwrap();
+        while fill.write(&[0; BLOCK_SIZE]).is_ok() {}
+        drop(fill);
+        assert_eq!(tfs.statfs().unwrap().free_blocks, 0);
+
+        // cutting into a shared block means copying it, which there's no room for
+        let mut b = tfs.open_existing("b").unwrap();
+        assert!(matches!(b.set_len(100), Err(TfsError::OutOfSpace)));
+        assert_eq!(b.stat().unwrap().size, contents.len() as u64);
+        let mut read = Vec::new();
+        b.read_to_end(&mut read).unwrap();
+        assert_eq!(read, contents);
+        drop(b);
+
+        tfs.remove("fill").unwrap();
+        let mut b = tfs.open_existing("b").unwrap();
+        b.set_len(100).unwrap();
+        drop(b);
+        assert_eq!(tfs.read("b").unwrap(), [0x42; 100]);
+        assert_eq!(tfs.read("a").unwrap(), contents);
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("b").unwrap(), [0x42; 100]);
+        assert_eq!(tfs.read("a").unwrap(), contents);
+    }
+
+    #[test]
+    fn link_works() {
+        const DISK_PATH: &str = "link-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let contents = [0x42; BLOCK_SIZE * 3];
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let free = tfs.statfs().unwrap().free_blocks;
+        tfs.write_file("a.txt", &contents).unwrap();
+        tfs.mkdir("docs").unwrap();
+        tfs.link("a.txt", "docs/b.txt").unwrap();
+        assert_eq!(tfs.metadata("a.txt").unwrap().nlink, 2);
+        assert_eq!(tfs.metadata("docs/b.txt").unwrap().nlink, 2);
+        let docs = tfs.readdir_at("docs").unwrap();
+        assert_eq!(docs[0].filename, "b.txt");
+        assert_eq!(docs[0].inode_block, tfs.readdir()[0].inode_block);
+        assert!(matches!(
+            tfs.link("a.txt", "docs/b.txt"),
+            Err(TfsError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            tfs.link("docs", "docs2"),
+            Err(TfsError::IsADirectory(_))
+        ));
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.rename("docs/b.txt", "docs/c.txt").unwrap();
+        tfs.remove("a.txt").unwrap();
+        assert!(!tfs.exists("a.txt"));
+        assert_eq!(tfs.read("docs/c.txt").unwrap(), contents);
+        assert_eq!(tfs.metadata("docs/c.txt").unwrap().nlink, 1);
+        assert_eq!(tfs.readdir().len(), 1);
+        // another name is just another entry, which fits in a block the directory already has
+        let used = tfs.statfs().unwrap().used_blocks;
+        tfs.link("docs/c.txt", "d.txt").unwrap();
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used);
+        tfs.remove("d.txt").unwrap();
+        assert_eq!(tfs.metadata("docs/c.txt").unwrap().nlink, 1);
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.remove("docs/c.txt").unwrap();
+        tfs.rmdir("docs").unwrap();
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn copy_out_of_space_works() {
+        const DISK_PATH: &str = "copy-space-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        tfs.write(&mut desc, &[0x42; BLOCK_SIZE * 3]).unwrap();
+        // leave space for the inode and two of the three data blocks
+        while tfs.superblock.allocate_block().is_some() {}
+        for block in 10..13 {
+            tfs.superblock.mark_free(block);
+        }
+        let allocated_blocks = tfs.superblock.allocated_blocks;
+        assert!(matches!(
+            tfs.copy("test.txt", "test2.txt"),
+            Err(TfsError::OutOfSpace)
+        ));
+        assert!(!tfs.exists("test2.txt"));
+        assert_eq!(tfs.superblock.allocated_blocks, allocated_blocks);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn large_write_doesnt_wrap() {
+        const DISK_PATH: &str = "large-write-disk.bin";
+        const LEN: usize = 70 * 1024;
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 512);
+        let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            tfs.write_file("big.bin", &data).unwrap();
+        }
+        let tfs = TfsFs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("big.bin").unwrap().size, LEN as u64);
+        assert_eq!(tfs.read_file("big.bin").unwrap(), data);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn indirect_blocks_work() {
+        const DISK_PATH: &str = "indirect-disk.bin";
+        const LEN: usize = 100 * 1024;
+        const MAX_LEN: usize = MAX_FILE_BLOCKS * BLOCK_SIZE;
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 1024);
+        let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let free = {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            let free = tfs.statfs().unwrap().free_blocks;
+            tfs.write_file("big.bin", &data).unwrap();
+            let indirect = (LEN / BLOCK_SIZE - INODE_BLOCKS).div_ceil(INDIRECT_BLOCK_LEN);
+            assert_eq!(
+                tfs.statfs().unwrap().free_blocks,
+                free - 1 - LEN / BLOCK_SIZE - indirect
+            );
+            tfs.sync().unwrap();
+            free
+        };
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read_file("big.bin").unwrap(), data);
+
+        // the file can grow to exactly what the inode can address
+        let mut desc = tfs.open("big.bin").unwrap();
+        tfs.truncate(&mut desc, MAX_LEN as u64).unwrap();
+        tfs.seek(&mut desc, SeekFrom::End(0)).unwrap();
+        assert!(matches!(
+            tfs.write(&mut desc, b"x"),
+            Err(TfsError::OutOfSpace)
+        ));
+
+        // shrinking back into the inline blocks frees the indirect blocks too
+        tfs.truncate(&mut desc, BLOCK_SIZE as u64).unwrap();
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free - 2);
+        tfs.truncate(&mut desc, LEN as u64).unwrap();
+        tfs.close(&mut desc).unwrap();
+        tfs.remove("big.bin").unwrap();
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn rename_path_works() {
+        const DISK_PATH: &str = "rename-path-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.open("a.txt").unwrap().write(b"a").unwrap();
+            tfs.open("b.txt").unwrap().write(b"b").unwrap();
+            assert!(matches!(
+                tfs.rename("c.txt", "d.txt"),
+                Err(TfsError::FileNotFound(_))
+            ));
+            assert!(matches!(
+                tfs.rename("a.txt", "b.txt"),
+                Err(TfsError::AlreadyExists(_))
+            ));
+            assert!(matches!(
+                tfs.rename("a.txt", "a".repeat(MAX_FILENAME_LEN + 1)),
+                Err(TfsError::FilenameTooLong { .. })
+            ));
+            tfs.rename("a.txt", "c.txt").unwrap();
+            tfs.open("b.txt").unwrap().rename("c.txt").unwrap_err();
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut filenames: Vec<_> = tfs.readdir().into_iter().map(|e| e.filename).collect();
+            filenames.sort();
+            assert_eq!(filenames, ["b.txt", "c.txt"]);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn long_filename_works() {
+        const DISK_PATH: &str = "long-filename-disk.bin";
+        let filename = "a-rather-long-filename-for-this-disk.txt";
+        assert_eq!(filename.len(), 40);
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.write_file(filename, b"long").unwrap();
+            assert!(matches!(
+                tfs.open("a".repeat(MAX_FILENAME_LEN + 1)),
+                Err(TfsError::FilenameTooLong {
+                    max: MAX_FILENAME_LEN,
+                    ..
+                })
+            ));
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            assert_eq!(tfs.readdir()[0].filename, filename);
+            assert_eq!(tfs.read(filename).unwrap(), b"long");
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn long_filename_leaves_disk_untouched() {
+        const DISK_PATH: &str = "long-filename-untouched-disk.bin";
+        let filename = "a".repeat(MAX_FILENAME_LEN + 1);
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.write_file("file", b"data").unwrap();
+            let before = tfs.statfs().unwrap();
+            assert!(tfs.open(&filename).is_err());
+            assert!(tfs.create(&filename).is_err());
+            assert!(tfs.mkdir(&filename).is_err());
+            assert!(tfs.copy("file", &filename).is_err());
+            assert!(tfs.rename("file", &filename).is_err());
+            assert_eq!(tfs.statfs().unwrap(), before);
+            // unmounting syncs, which used to panic on the bad name
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let filenames: Vec<_> = tfs.readdir().into_iter().map(|e| e.filename).collect();
+        assert_eq!(filenames, ["file"]);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn read_write_file_works() {
+        const DISK_PATH: &str = "read-write-file-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let harry = include_bytes!("../harry-sm.jpg");
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            assert!(matches!(
+                tfs.read("cat.jpg"),
+                Err(TfsError::FileNotFound(_))
+            ));
+            assert!(!tfs.exists("cat.jpg"));
+            tfs.write_file("cat.jpg", b"meow").unwrap();
+            tfs.write_file("cat.jpg", harry).unwrap();
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            assert_eq!(tfs.read("cat.jpg").unwrap(), harry);
+            tfs.write_file("cat.jpg", b"meow").unwrap();
+            assert_eq!(tfs.read("cat.jpg").unwrap(), b"meow");
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn root_chains_into_more_blocks() {
+        const DISK_PATH: &str = "root-chain-disk.bin";
+        const FILES: usize = 300;
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 1024);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            for i in 0..FILES {
+                tfs.write_file(format!("file{i}"), i.to_string().as_bytes())
+                    .unwrap();
+            }
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let filenames: Vec<_> = tfs.readdir().into_iter().map(|e| e.filename).collect();
+            let expected: Vec<_> = (0..FILES).map(|i| format!("file{i}")).collect();
+            assert_eq!(filenames, expected);
+            assert_eq!(tfs.read("file299").unwrap(), b"299");
+
+            // continuation blocks are given back once they're empty
+            let used = tfs.statfs().unwrap().used_blocks;
+            for i in ROOT_ENTRIES..FILES {
+                tfs.remove(format!("file{i}")).unwrap();
+            }
+            assert_eq!(
+                tfs.statfs().unwrap().used_blocks,
+                used - (FILES - ROOT_ENTRIES) * 2 - Root::chain_needed(FILES)
+            );
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.readdir().len(), ROOT_ENTRIES);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn full_directory_fails_at_create() {
+        const DISK_PATH: &str = "full-dir-disk.bin";
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 256);
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            for i in 0..ROOT_ENTRIES {
+                tfs.open(format!("{i}")).unwrap();
+            }
+            while tfs.superblock.allocate_block().is_some() {}
+            let allocated_blocks = tfs.superblock.allocated_blocks;
+            assert!(matches!(tfs.open("more"), Err(TfsError::DirectoryFull)));
+            assert_eq!(tfs.superblock.allocated_blocks, allocated_blocks);
+
+            // room for the continuation block but not the inode gives the continuation back
+            tfs.superblock.mark_free(200);
+            let allocated_blocks = tfs.superblock.allocated_blocks;
+            assert!(matches!(tfs.open("more"), Err(TfsError::OutOfSpace)));
+            assert_eq!(tfs.superblock.allocated_blocks, allocated_blocks);
+            assert!(!tfs.exists("more"));
+            tfs.sync().unwrap();
+        }
+        // dropping a full filesystem syncs fine too
+        drop(Tfs::mount(DISK_PATH).unwrap());
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.readdir().len(), ROOT_ENTRIES);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_create_errors_work() {
+        const DISK_PATH: &str = "open-errors-disk.bin";
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 512);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.mkdir("dir").unwrap();
+        for i in 0..MAX_DIR_ENTRIES {
+            tfs.open(format!("dir/{i}")).unwrap();
+        }
+        assert!(matches!(tfs.open("dir/more"), Err(TfsError::DirectoryFull)));
+        while tfs.superblock.allocate_block().is_some() {}
+        assert!(matches!(tfs.open("more"), Err(TfsError::OutOfSpace)));
+        assert!(!tfs.exists("dir/more"));
+        assert!(!tfs.exists("more"));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn invalid_desc_works() {
+        const DISK_PATH: &str = "invalid-desc-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut a = tfs.open("a").unwrap();
+        let mut b = tfs.open("b").unwrap();
+        tfs.write(&mut b, b"b").unwrap();
+        // a closed handle can still be held onto after its file is gone
+        tfs.close(&mut a).unwrap();
+        tfs.remove("a").unwrap();
+        assert!(matches!(
+            tfs.write(&mut a, b"a"),
+            Err(TfsError::InvalidDesc)
+        ));
+        assert!(matches!(tfs.try_clone(&a), Err(TfsError::InvalidDesc)));
+        assert!(matches!(tfs.read_byte(&mut a), Err(TfsError::InvalidDesc)));
+        assert!(matches!(
+            tfs.rename(&mut a, "c"),
+            Err(TfsError::InvalidDesc)
+        ));
+        assert!(matches!(
+            tfs.seek(&mut a, SeekFrom::Start(0)),
+            Err(TfsError::InvalidDesc)
+        ));
+
+        // b moved down in the inode list but the handle still finds it
+        tfs.seek(&mut b, SeekFrom::Start(0)).unwrap();
+        assert_eq!(tfs.read_byte(&mut b).unwrap(), Some(b'b'));
+
+        let mut forged = TfsFsFile {
+            id: 42,
+            inode: 42,
+            block: 42,
+            generation: 0,
+            offset: 0,
+            readable: true,
+            writable: true,
+            append: false,
+            cache: None,
+        };
+        assert!(matches!(
+            tfs.read(&mut forged, &mut [0; 4]),
+            Err(TfsError::InvalidDesc)
+        ));
+        assert!(matches!(tfs.stat(&forged), Err(TfsError::InvalidDesc)));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_filename_works() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        const DISK_PATH: &str = "invalid-filename-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let not_utf8 = OsStr::from_bytes(&[0xff, 0xfe]);
+        assert!(matches!(
+            tfs.open(not_utf8),
+            Err(TfsError::InvalidFilename(_))
+        ));
+        assert!(!tfs.exists(not_utf8));
+        assert!(matches!(
+            tfs.open("nul\0byte"),
+            Err(TfsError::InvalidFilename(_))
+        ));
+        tfs.write_file("file", b"data").unwrap();
+        assert!(matches!(
+            tfs.open("file").unwrap().rename("nul\0byte"),
+            Err(TfsError::InvalidFilename(_))
+        ));
+        // nothing bad was left behind to trip up syncing
+        tfs.sync().unwrap();
+        assert_eq!(tfs.readdir().len(), 1);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn rename_persists() {
+        const DISK_PATH: &str = "rename-persists-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.write_file("old", b"data").unwrap();
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.open("old").unwrap().rename("new").unwrap();
+            // skip the sync on unmount so only rename itself can have saved the name
+            crash(tfs);
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let filenames: Vec<_> = tfs.readdir().into_iter().map(|e| e.filename).collect();
+        assert_eq!(filenames, ["new"]);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn stat_then_read_works() {
+        const DISK_PATH: &str = "stat-read-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.write_file("file", b"hello").unwrap();
+            let mut file = tfs.open("file").unwrap();
+            let stat = file.stat().unwrap();
+            assert_eq!(stat.size, 5);
+            let mut buf = [0; 5];
+            assert_eq!(file.read(&mut buf).unwrap(), 5);
+            assert_eq!(&buf, b"hello");
+            assert_eq!(file.stat().unwrap().size, 5);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn close_works() {
+        const DISK_PATH: &str = "close-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut file = tfs.open("closed").unwrap();
+            file.write(b"closed").unwrap();
+            file.close().unwrap();
+            let mut file = tfs.open("flushed").unwrap();
+            file.write(b"flushed").unwrap();
+            file.flush().unwrap();
+            drop(file);
+            let mut file = tfs.open("dropped").unwrap();
+            file.write(b"dropped").unwrap();
+            drop(file);
+            // nothing gets synced on unmount
+            crash(tfs);
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("closed").unwrap(), b"closed");
+        assert_eq!(tfs.read("flushed").unwrap(), b"flushed");
+        assert_eq!(tfs.read("dropped").unwrap(), b"dropped");
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn small_write_syncs_only_the_file() {
+        const DISK_PATH: &str = "small-write-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.write_file("other", b"other").unwrap();
+        let mut desc = tfs.open("file").unwrap();
+        let writes = tfs.disk.writes();
+        tfs.write(&mut desc, b"hello").unwrap();
+        tfs.sync_file(&desc).unwrap();
+        // the data block, the inode, and the superblock and its backup for the allocation
+        assert!(tfs.disk.writes() - writes <= 4);
+        let writes = tfs.disk.writes();
+        tfs.rename(&mut desc, "renamed").unwrap();
+        tfs.sync().unwrap();
+        // renaming in place only touches the inode, for its ctime, and the root block it's listed in
+        assert_eq!(tfs.disk.writes() - writes, 2);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn writes_dont_sync() {
+        const DISK_PATH: &str = "writes-dont-sync-disk.bin";
+        let chunk = [0x42; BLOCK_SIZE];
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("file").unwrap();
+        let writes = tfs.disk.writes();
+        for _ in 0..32 {
+            tfs.write(&mut desc, &chunk).unwrap();
+        }
+        tfs.close(&mut desc).unwrap();
+        // one write per data block, then the inode and both copies of the superblock once each
+        assert_eq!(tfs.disk.writes() - writes, 32 + 3);
+
+        tfs.set_sync_writes(true);
+        let mut desc = tfs.open("file").unwrap();
+        let writes = tfs.disk.writes();
+        for _ in 0..32 {
+            tfs.write(&mut desc, &chunk).unwrap();
+        }
+        // overwriting in place, but the inode's mtime still has to go out every time
+        assert_eq!(tfs.disk.writes() - writes, 32 * 2);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn small_appends_share_blocks() {
+        const DISK_PATH: &str = "small-appends-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut desc = tfs.open("ab").unwrap();
+        tfs.write(&mut desc, b"a").unwrap();
+        tfs.write(&mut desc, b"b").unwrap();
+        assert_eq!(tfs.root.inodes[desc.inode].blocks.len(), 1);
+        assert_eq!(read_all(&mut tfs, "ab"), b"ab");
+
+        let mut desc = tfs
+            .open_with("log", TfsOpenOptions::new().append(true).create(true))
+            .unwrap();
+        for i in 0..1000 {
+            tfs.write(&mut desc, &[(i % 26) as u8 + b'a']).unwrap();
+        }
+        assert_eq!(
+            tfs.root.inodes[desc.inode].blocks.len(),
+            1000_usize.div_ceil(BLOCK_SIZE)
+        );
+        let expected: Vec<u8> = (0..1000).map(|i| (i % 26) as u8 + b'a').collect();
+        assert_eq!(read_all(&mut tfs, "log"), expected);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn read_byte_reads_each_block_once() {
+        const DISK_PATH: &str = "read-byte-cache-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| i as u8).collect();
+        tfs.write_file("file", &data).unwrap();
+        let mut desc = tfs.open("file").unwrap();
+        let reads = tfs.disk.reads();
+        for &expected in &data[..BLOCK_SIZE + 1] {
+            assert_eq!(tfs.read_byte(&mut desc).unwrap(), Some(expected));
+        }
+        assert_eq!(tfs.disk.reads() - reads, 2);
+
+        // writing through another handle makes the cached block stale
+        let mut other = tfs.open("file").unwrap();
+        tfs.seek(&mut other, SeekFrom::Start(BLOCK_SIZE as u64 + 1))
+            .unwrap();
+        tfs.write(&mut other, b"x").unwrap();
+        assert_eq!(tfs.read_byte(&mut desc).unwrap(), Some(b'x'));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn sync_all_works() {
+        const DISK_PATH: &str = "sync-all-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let mut tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.open("lazy").unwrap().write(b"lazy").unwrap();
+            tfs.sync_all().unwrap();
+            tfs.set_sync_policy(SyncPolicy::Always);
+            tfs.open("always").unwrap().write(b"always").unwrap();
+            tfs.sync().unwrap();
+            crash(tfs);
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("lazy").unwrap(), b"lazy");
+        assert_eq!(tfs.read("always").unwrap(), b"always");
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn read_only_works() {
+        const DISK_PATH: &str = "read-only-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        Tfs::mount(DISK_PATH)
+            .unwrap()
+            .write_file("file", b"contents")
+            .unwrap();
+        let image = fs::read(DISK_PATH).unwrap();
+
+        let tfs = Tfs::mount_read_only(DISK_PATH).unwrap();
+        assert!(matches!(
+            tfs.write_file("file", b"other"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(tfs.open("new"), Err(TfsError::ReadOnlyFilesystem)));
+        assert!(matches!(
+            tfs.rename("file", "renamed"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            tfs.remove("file"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            tfs.mkdir("dir"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert_eq!(tfs.read("file").unwrap(), b"contents");
+        tfs.sync().unwrap();
+        drop(tfs);
+        assert_eq!(fs::read(DISK_PATH).unwrap(), image);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn noatime_works() {
+        const DISK_PATH: &str = "noatime-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        Tfs::mount(DISK_PATH)
+            .unwrap()
+            .write_file("file", &[0x42; BLOCK_SIZE * 2])
+            .unwrap();
+        let atime = Tfs::mount(DISK_PATH)
+            .unwrap()
+            .metadata("file")
+            .unwrap()
+            .atime;
+
+        let mut tfs = TfsFs::mount_with(DISK_PATH, MountOptions::new().noatime(true)).unwrap();
+        // marking the image as mounted is the only write
+        let writes = tfs.disk.writes();
+        let mut desc = tfs
+            .open_with("file", TfsOpenOptions::new().read(true))
+            .unwrap();
+        while tfs.read_byte(&mut desc).unwrap().is_some() {}
+        assert_eq!(tfs.read_file("file").unwrap(), [0x42; BLOCK_SIZE * 2]);
+        tfs.sync_file(&desc).unwrap();
+        tfs.sync().unwrap();
+        assert_eq!(tfs.disk.writes() - writes, 0);
+        assert_eq!(tfs.metadata("file").unwrap().atime, atime);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn mkfs_with_works() {
+        const DISK_PATH: &str = "mkfs-with-disk.bin";
+        MkfsOptions::new()
+            .label("firmware")
+            .blocks(64)
+            .file("boot", [0x42; BLOCK_SIZE * 3])
+            .empty_file("config")
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.label(), "firmware");
+        assert_eq!(tfs.statfs().unwrap().total_blocks, 64);
+        assert_eq!(tfs.read("boot").unwrap(), [0x42; BLOCK_SIZE * 3]);
+        assert_eq!(tfs.read("config").unwrap(), b"");
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+
+        // nothing is written if any of the options are bad
+        const BAD_PATH: &str = "mkfs-with-bad-disk.bin";
+        let _ = fs::remove_file(BAD_PATH);
+        assert!(matches!(
+            MkfsOptions::new()
+                .label("a label that is too long")
+                .mkfs(BAD_PATH),
+            Err(TfsError::InvalidLabel(_))
+        ));
+        assert!(matches!(
+            MkfsOptions::new()
+                .empty_file("file")
+                .empty_file(["a"; MAX_FILENAME_LEN + 1].concat())
+                .mkfs(BAD_PATH),
+            Err(TfsError::FilenameTooLong { .. })
+        ));
+        assert!(matches!(
+            MkfsOptions::new()
+                .empty_file("file")
+                .empty_file("file")
+                .mkfs(BAD_PATH),
+            Err(TfsError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            MkfsOptions::new()
+                .blocks(8)
+                .file("big", [0; BLOCK_SIZE * 6])
+                .mkfs(BAD_PATH),
+            Err(TfsError::OutOfSpace)
+        ));
+        assert!(matches!(
+            MkfsOptions::new().blocks(MAX_BLOCKS + 1).mkfs(BAD_PATH),
+            Err(TfsError::SizeError { .. })
+        ));
+        assert!(!Path::new(BAD_PATH).exists());
+    }
+
+    #[test]
+    fn uuid_works() {
+        const DISK_PATH: &str = "uuid-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let first = Tfs::mount(DISK_PATH).unwrap().uuid();
+        MkfsOptions::new().force(true).mkfs(DISK_PATH).unwrap();
+        let second = Tfs::mount(DISK_PATH).unwrap().uuid();
+        assert_ne!(first, second);
+        assert_ne!(first, [0; UUID_LEN]);
+
+        MkfsOptions::new()
+            .uuid([0x42; UUID_LEN])
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        assert_eq!(Tfs::mount(DISK_PATH).unwrap().uuid(), [0x42; UUID_LEN]);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn upgrade_works() {
+        const DISK_PATH: &str = "upgrade-disk.bin";
+        fs::copy("fixtures/v1.disk", DISK_PATH).unwrap();
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::UnsupportedVersion {
+                found: v1::VERSION,
+                supported: FORMAT_VERSION
+            })
+        ));
+
+        Tfs::upgrade(DISK_PATH).unwrap();
+        let data: Vec<u8> = (0..90 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let names: Vec<_> = tfs.readdir().into_iter().map(|f| f.filename).collect();
+            assert_eq!(names, ["hello", "data.bin"]);
+            assert_eq!(tfs.read("hello").unwrap(), b"Hello, World!");
+            assert_eq!(tfs.read("data.bin").unwrap(), data);
+            // and it behaves like any other image from here on
+            tfs.write_file("a longer name than v1 allowed", b"new")
+                .unwrap();
+        }
+        // upgrading a current image does nothing
+        let image = fs::read(DISK_PATH).unwrap();
+        Tfs::upgrade(DISK_PATH).unwrap();
+        assert_eq!(fs::read(DISK_PATH).unwrap(), image);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("a longer name than v1 allowed").unwrap(), b"new");
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn unclean_mount_works() {
+        const DISK_PATH: &str = "unclean-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(!tfs.was_unclean());
+        tfs.write_file("file", b"contents").unwrap();
+        // simulate a crash by never unmounting
+        crash(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(tfs.was_unclean());
+        tfs.unmount().unwrap();
+        let tfs = Tfs::mount_read_only(DISK_PATH).unwrap();
+        assert!(!tfs.was_unclean());
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn backup_superblock_works() {
+        const DISK_PATH: &str = "backup-superblock-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(!tfs.used_backup_superblock());
+        tfs.write_file("file", b"contents").unwrap();
+        drop(tfs);
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        disk.write_block(0, [0; BLOCK_SIZE]).unwrap();
+        drop(disk);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(tfs.used_backup_superblock());
+        let names: Vec<_> = tfs.readdir().into_iter().map(|f| f.filename).collect();
+        assert_eq!(names, ["file"]);
+        assert_eq!(tfs.read("file").unwrap(), b"contents");
+        drop(tfs);
+        // mounting put the primary back
+        assert!(!Tfs::mount(DISK_PATH).unwrap().used_backup_superblock());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn rebuild_allocation_works() {
+        const DISK_PATH: &str = "rebuild-allocation-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.mkdir("dir").unwrap();
+        tfs.write_file("dir/file", &[0x42; BLOCK_SIZE * 2]).unwrap();
+        let free = tfs.statfs().unwrap().free_blocks;
+        for _ in 0..3 {
+            tfs.superblock.allocate_block().unwrap();
+        }
+        tfs.sync().unwrap();
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free - 3);
+
+        assert_eq!(tfs.rebuild_allocation().unwrap(), 3);
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free);
+        drop(tfs);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free);
+        assert_eq!(tfs.read("dir/file").unwrap(), [0x42; BLOCK_SIZE * 2]);
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn cross_linked_mount_fails() {
+        const DISK_PATH: &str = "cross-linked-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.write_file("first", b"first").unwrap();
+        tfs.write_file("second", b"second").unwrap();
+        let block = tfs.root.inodes[0].blocks[0];
+        let first = tfs.root.inodes[0].block;
+        let second = &mut tfs.root.inodes[1];
+        second.blocks[0] = block;
+        second.dirty = true;
+        let second = second.block;
+        drop(tfs);
+
+        let expected = vec![first, second];
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::CrossLinkedBlock { block: b, inodes }) if b == block && inodes == expected
+        ));
+        assert!(Tfs::check(DISK_PATH)
+            .unwrap()
+            .findings
+            .contains(&FsckFinding::CrossLinkedBlock {
+                block,
+                inodes: expected
+            }));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn superblock_checksum_works() {
+        const DISK_PATH: &str = "superblock-checksum-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let backup = DEFAULT_DISK_SIZE / BLOCK_SIZE - 1;
+        // somewhere in the middle of the allocation table
+        let flip = BLOCK_SIZE - 64;
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        let mut block = disk.read_block(0).unwrap();
+        block[flip] ^= 0x10;
+        disk.write_block(0, block).unwrap();
+        drop(disk);
+        // the backup is still good
+        assert!(Tfs::mount(DISK_PATH).unwrap().used_backup_superblock());
+
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        for num in [0, backup] {
+            let mut block = disk.read_block(num).unwrap();
+            block[flip] ^= 0x10;
+            disk.write_block(num, block).unwrap();
+        }
+        drop(disk);
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::ChecksumMismatch { block: 0 })
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn data_checksums_work() {
+        const DISK_PATH: &str = "data-checksums-disk.bin";
+        let harry = include_bytes!("../harry-sm.jpg");
+        MkfsOptions::new()
+            .checksums(true)
+            .file("test.txt", *b"Hello, World!")
+            .file("cat.jpg", *harry)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let block = tfs.root.inodes[1].blocks[2];
+        drop(tfs);
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        let mut data = disk.read_block(block as usize).unwrap();
+        data[17] ^= 0x01;
+        disk.write_block(block as usize, data).unwrap();
+        drop(disk);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(matches!(
+            tfs.read("cat.jpg"),
+            Err(TfsError::ChecksumMismatch { block: b }) if b == block
+        ));
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello, World!");
+        // the blocks before the bad one are still readable
+        let mut file = tfs.open_existing("cat.jpg").unwrap();
+        let mut buf = [0; BLOCK_SIZE * 2];
+        file.read(&mut buf).unwrap();
+        assert_eq!(buf, harry[..BLOCK_SIZE * 2]);
+        drop(file);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn journal_replay_works() {
+        const CRASH_PATH: &str = "journal-crash-disk.bin";
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(
+            &mut disk,
+            MkfsOptions::new()
+                .journal_blocks(8)
+                .file("test.txt", *b"Hello, World!"),
+        )
+        .unwrap();
+        let mut crash = CrashDisk::new(disk).unwrap();
+        let tfs = Tfs::mount_on(&mut crash, &MountOptions::new()).unwrap();
+        // the crash disk is as new as the mount, so they count the same writes
+        let start = lock_read(&tfs.tfs).disk.writes();
+        tfs.mkdir("dir").unwrap();
+        tfs.sync().unwrap();
+        let end = lock_read(&tfs.tfs).disk.writes();
+        drop(tfs);
+        assert!(crash.writes() > end);
+        // crash after every possible number of writes into a sync touching the superblock, its
+        // backup, the root and a new inode
+        for n in start..=end {
+            fs::write(CRASH_PATH, crash.snapshot_at(n).into_bytes()).unwrap();
+            let finished = n == end;
+            assert!(Tfs::check(CRASH_PATH).unwrap().is_clean());
+            let tfs = Tfs::mount(CRASH_PATH).unwrap();
+            let mut names: Vec<_> = tfs.readdir().into_iter().map(|f| f.filename).collect();
+            names.sort();
+            if finished {
+                assert_eq!(names, ["dir", "test.txt"]);
+            } else {
+                assert!(names == ["test.txt"] || names == ["dir", "test.txt"]);
+            }
+            assert_eq!(tfs.read("test.txt").unwrap(), b"Hello, World!");
+            drop(tfs);
+            assert!(Tfs::check(CRASH_PATH).unwrap().is_clean());
+        }
+        fs::remove_file(CRASH_PATH).unwrap();
+    }
+
+    #[test]
+    fn batch_works() {
+        const DISK_PATH: &str = "batch-disk.bin";
+        MkfsOptions::new()
+            .blocks(256)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let mut tfs = Tfs::mount(DISK_PATH).unwrap();
+        let writes = lock_read(&tfs.tfs).disk.writes();
+        let batch = tfs.batch();
+        for i in 0..100 {
+            batch.create(format!("{i}.txt")).unwrap();
+        }
+        assert_eq!(lock_read(&batch.tfs.tfs).disk.writes(), writes);
+        batch.commit().unwrap();
+        // each inode once, plus the superblock, its backup and the root's blocks
+        assert_eq!(
+            lock_read(&tfs.tfs).disk.writes() - writes,
+            100 + 3 + Root::chain_needed(100)
+        );
+        assert_eq!(tfs.readdir().len(), 100);
+
+        // dropping without committing syncs too
+        let writes = lock_read(&tfs.tfs).disk.writes();
+        let batch = tfs.batch();
+        batch.remove("0.txt").unwrap();
+        drop(batch);
+        assert!(lock_read(&tfs.tfs).disk.writes() > writes);
+        drop(tfs);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.readdir().len(), 99);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn snapshot_works() {
+        const DISK_PATH: &str = "snapshot-disk.bin";
+        let snapshot_path = std::env::temp_dir().join("tinyfs-snapshot-disk.bin");
+        MkfsOptions::new()
+            .checksums(true)
+            .file("test.txt", *b"Hello, World!")
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let mut tfs = Tfs::mount(DISK_PATH).unwrap();
+        let batch = tfs.batch();
+        batch.write_file("cat.jpg", b"meow").unwrap();
+        // not synced yet, but it makes it in anyway
+        batch.snapshot(&snapshot_path).unwrap();
+        batch.commit().unwrap();
+        tfs.write_file("test.txt", b"Goodbye, World!").unwrap();
+        tfs.remove("cat.jpg").unwrap();
+        drop(tfs);
+
+        assert!(Tfs::check(&snapshot_path).unwrap().is_clean());
+        let snapshot = Tfs::mount(&snapshot_path).unwrap();
+        assert!(!snapshot.was_unclean());
+        assert_eq!(snapshot.read("test.txt").unwrap(), b"Hello, World!");
+        assert_eq!(snapshot.read("cat.jpg").unwrap(), b"meow");
+        drop(snapshot);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Goodbye, World!");
+        assert!(!tfs.exists("cat.jpg"));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+        fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn file_kind_works() {
+        const DISK_PATH: &str = "file-kind-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.mkdir("dir").unwrap();
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("dir").unwrap().kind, FileKind::Directory);
+        assert_eq!(tfs.metadata("test.txt").unwrap().kind, FileKind::Regular);
+        for entry in tfs.readdir() {
+            assert_eq!(entry.kind, entry.stat.kind);
+        }
+        drop(tfs);
+
+        // a kind this version doesn't know about is refused rather than guessed at
+        let tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let block = tfs.root.inodes[tfs.root.find("test.txt").unwrap()].block;
+        drop(tfs);
+        let mut disk: Disk<BLOCK_SIZE> = Disk::open_existing(DISK_PATH).unwrap();
+        let mut data: INodeData = decode(&disk.read_block(block as usize).unwrap()).unwrap();
+        data.kind = 7;
+        let encoded = encode(&data).unwrap();
+        disk.write_block(block as usize, encoded.try_into().unwrap())
+            .unwrap();
+        drop(disk);
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::InvalidKind(7))
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn permissions_work() {
+        const DISK_PATH: &str = "permissions-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        tfs.mkdir("dir").unwrap();
+        assert_eq!(tfs.metadata("test.txt").unwrap().mode, DEFAULT_FILE_MODE);
+        assert_eq!(tfs.metadata("dir").unwrap().mode, DEFAULT_DIR_MODE);
+        tfs.set_permissions("test.txt", 0o444).unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("test.txt").unwrap().mode, 0o444);
+        assert!(matches!(
+            tfs.open("test.txt"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            tfs.write_file("test.txt", b"Goodbye"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        // reading is still fine
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello");
+        tfs.set_permissions("test.txt", 0o600).unwrap();
+        tfs.write_file("test.txt", b"Goodbye").unwrap();
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Goodbye");
+        assert!(matches!(
+            tfs.set_permissions("missing.txt", 0o644),
+            Err(TfsError::FileNotFound(_))
+        ));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn chown_works() {
+        const DISK_PATH: &str = "chown-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        tfs.mkdir("dir").unwrap();
+        let owner = process_owner();
+        let stat = tfs.metadata("test.txt").unwrap();
+        assert_eq!((stat.uid, stat.gid), owner);
+        tfs.chown("test.txt", 1000, 100).unwrap();
+        tfs.chown("dir", 0, 0).unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let stat = tfs.metadata("test.txt").unwrap();
+        assert_eq!((stat.uid, stat.gid), (1000, 100));
+        let stat = tfs.metadata("dir").unwrap();
+        assert_eq!((stat.uid, stat.gid), (0, 0));
+        assert!(matches!(
+            tfs.chown("missing.txt", 0, 0),
+            Err(TfsError::FileNotFound(_))
+        ));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn set_times_works() {
+        const DISK_PATH: &str = "set-times-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        // 1999-12-31 23:59:59
+        let party = SystemTime::UNIX_EPOCH + Duration::from_secs(946_684_799);
+        let later = party + Duration::from_secs(60);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        tfs.write_file("cat.jpg", b"meow").unwrap();
+        tfs.set_times("test.txt", party, later).unwrap();
+        let mut file = tfs.open_existing("cat.jpg").unwrap();
+        file.set_times(later, party).unwrap();
+        assert!(matches!(
+            file.set_times(SystemTime::UNIX_EPOCH - Duration::from_secs(1), party),
+            Err(TfsError::TimeOutOfRange(_))
+        ));
+        drop(file);
+        let mut file = tfs
+            .open_with("cat.jpg", TfsOpenOptions::new().read(true))
+            .unwrap();
+        assert!(matches!(
+            file.set_times(party, party),
+            Err(TfsError::ReadOnly)
+        ));
+        drop(file);
+        assert!(matches!(
+            tfs.set_times(
+                "test.txt",
+                party,
+                SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::MAX) + Duration::from_nanos(1)
+            ),
+            Err(TfsError::TimeOutOfRange(_))
+        ));
+        drop(tfs);
+
+        let tfs = TfsFs::mount_with(DISK_PATH, MountOptions::new().noatime(true)).unwrap();
+        let stat = tfs.metadata("test.txt").unwrap();
+        assert_eq!((stat.mtime, stat.atime), (party, later));
+        let stat = tfs.metadata("cat.jpg").unwrap();
+        assert_eq!((stat.mtime, stat.atime), (later, party));
+        assert_eq!(tfs.read_file("test.txt").unwrap(), b"Hello");
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn timestamp_resolution_works() {
+        const DISK_PATH: &str = "timestamp-resolution-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.open("test.txt").unwrap().write(b"Hello").unwrap();
+        let stat = tfs.metadata("test.txt").unwrap();
+        // well within the same second, but still told apart
+        assert_ne!(stat.btime, stat.mtime);
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let remounted = tfs.metadata("test.txt").unwrap();
+        assert_eq!(remounted.btime, stat.btime);
+        assert_eq!(remounted.mtime, stat.mtime);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn ctime_works() {
+        const DISK_PATH: &str = "ctime-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        let before = tfs.metadata("test.txt").unwrap();
+        tfs.rename("test.txt", "hello.txt").unwrap();
+        let after = tfs.metadata("hello.txt").unwrap();
+        assert!(after.ctime > before.ctime);
+        assert_eq!(after.btime, before.btime);
+        assert_eq!(after.mtime, before.mtime);
+
+        tfs.set_permissions("hello.txt", 0o600).unwrap();
+        let chmodded = tfs.metadata("hello.txt").unwrap();
+        assert!(chmodded.ctime > after.ctime);
+        tfs.open_existing("hello.txt").unwrap().set_len(2).unwrap();
+        let truncated = tfs.metadata("hello.txt").unwrap();
+        assert!(truncated.ctime > chmodded.ctime);
+        assert_eq!(truncated.mtime, truncated.ctime);
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let remounted = tfs.metadata("hello.txt").unwrap();
+        assert_eq!(remounted.btime, before.btime);
+        assert_eq!(remounted.ctime, truncated.ctime);
+        for entry in tfs.readdir() {
+            assert_eq!(entry.stat.btime, before.btime);
+        }
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn touch_works() {
+        const DISK_PATH: &str = "touch-disk.bin";
+        // room for a directory's worth of inodes
+        mkfs_fresh(DISK_PATH, BLOCK_SIZE * 512);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let used = tfs.statfs().unwrap().used_blocks;
+        tfs.touch("empty.txt").unwrap();
+        // just the inode
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used + 1);
+        tfs.write_file("test.txt", b"Hello").unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let entry = tfs
+            .readdir()
+            .into_iter()
+            .find(|entry| entry.filename == "empty.txt")
+            .unwrap();
+        assert_eq!((entry.stat.size, entry.blocks), (0, 0));
+        let before = tfs.metadata("test.txt").unwrap();
+        let used = tfs.statfs().unwrap().used_blocks;
+        tfs.touch("test.txt").unwrap();
+        let after = tfs.metadata("test.txt").unwrap();
+        assert!(after.mtime > before.mtime);
+        assert!(after.atime > before.atime);
+        assert_eq!(after.btime, before.btime);
+        assert_eq!(after.size, before.size);
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used);
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello");
+
+        tfs.mkdir("dir").unwrap();
+        for i in 0..MAX_DIR_ENTRIES {
+            tfs.touch(format!("dir/{i}")).unwrap();
+        }
+        assert!(matches!(
+            tfs.touch("dir/more"),
+            Err(TfsError::DirectoryFull)
+        ));
+        drop(tfs);
+
+        let tfs = Tfs::mount_read_only(DISK_PATH).unwrap();
+        assert!(matches!(
+            tfs.touch("test.txt"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn file_flags_work() {
+        const DISK_PATH: &str = "file-flags-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let immutable = FileFlags {
+            immutable: true,
+            ..FileFlags::default()
+        };
+        let append_only = FileFlags {
+            append_only: true,
+            ..FileFlags::default()
+        };
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        tfs.write_file("manifest.txt", b"Hello").unwrap();
+        tfs.write_file("log.txt", b"Hello").unwrap();
+        let mut open = tfs.open_existing("manifest.txt").unwrap();
+        tfs.set_flags("manifest.txt", immutable).unwrap();
+        tfs.set_flags("log.txt", append_only).unwrap();
+        // a handle from before the flags were set doesn't get around them
+        assert!(matches!(
+            tfs.write(&mut open, b"Goodbye"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            tfs.truncate(&mut open, 0),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        drop(tfs);
+
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("manifest.txt").unwrap().flags, immutable);
+        assert_eq!(tfs.metadata("log.txt").unwrap().flags, append_only);
+        assert!(matches!(
+            tfs.open("manifest.txt"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            tfs.write_file("manifest.txt", b"Goodbye"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            tfs.rename_path("manifest.txt", "other.txt"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            tfs.remove("manifest.txt"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert_eq!(tfs.read_file("manifest.txt").unwrap(), b"Hello");
+
+        assert!(matches!(
+            tfs.write_file("log.txt", b"Goodbye"),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        let mut log = tfs.open_existing("log.txt").unwrap();
+        // lands on the end no matter where the offset was
+        tfs.write(&mut log, b", World!").unwrap();
+        assert!(matches!(
+            tfs.truncate(&mut log, 0),
+            Err(TfsError::PermissionDenied(_))
+        ));
+        assert_eq!(tfs.read_file("log.txt").unwrap(), b"Hello, World!");
+        // append only files can still be renamed, and immutable ones go once the flag is cleared
+        tfs.rename_path("log.txt", "old.txt").unwrap();
+        tfs.set_flags("manifest.txt", FileFlags::default()).unwrap();
+        tfs.remove("manifest.txt").unwrap();
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn preallocate_works() {
+        const DISK_PATH: &str = "preallocate-disk.bin";
+        const LEN: usize = 4096;
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut file = tfs.open("recording.bin").unwrap();
+        let mut other = tfs.open("other.bin").unwrap();
+        let used = tfs.statfs().unwrap().used_blocks;
+        tfs.preallocate(&file, LEN).unwrap();
+        assert_eq!(tfs.stat(&file).unwrap().size, 0);
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used + LEN / BLOCK_SIZE);
+
+        // then everything else gets used up, bar a few blocks
+        let mut filler = Vec::new();
+        while let Some(block) = tfs.superblock.allocate_block() {
+            filler.push(block);
+        }
+        for &block in &filler[..3] {
+            tfs.superblock.mark_free(block);
+        }
+        // which isn't enough, so none of them are taken
+        assert!(matches!(
+            tfs.preallocate(&other, LEN),
+            Err(TfsError::OutOfSpace)
+        ));
+        assert!(tfs.root.inodes[1].blocks.is_empty());
+        for _ in 0..3 {
+            assert!(tfs.superblock.allocate_block().is_some());
+        }
+        assert!(matches!(
+            tfs.write(&mut other, &[1; BLOCK_SIZE]),
+            Err(TfsError::OutOfSpace)
+        ));
+        let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+        assert_eq!(tfs.write(&mut file, &data).unwrap(), LEN);
+        for block in filler {
+            tfs.superblock.mark_free(block);
+        }
+        tfs.sync().unwrap();
+        drop(tfs);
+
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        let tfs = TfsFs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read_file("recording.bin").unwrap(), data);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn sparse_files_work() {
+        const DISK_PATH: &str = "sparse-files-disk.bin";
+        const OFFSET: u64 = 5 * 1024;
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let used = tfs.statfs().unwrap().used_blocks;
+        let mut file = tfs.open("sparse.bin").unwrap();
+        io::Seek::seek(&mut file, SeekFrom::Start(OFFSET)).unwrap();
+        file.write(b"0123456789").unwrap();
+        drop(file);
+        // the inode and the one block that was written to
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used + 2);
+        let stat = tfs.metadata("sparse.bin").unwrap();
+        assert_eq!(stat.size, OFFSET + 10);
+        assert_eq!(stat.blocks_allocated, 1);
+        drop(tfs);
+
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let contents = tfs.read("sparse.bin").unwrap();
+        assert!(contents[..OFFSET as usize].iter().all(|&b| b == 0));
+        assert_eq!(&contents[OFFSET as usize..], b"0123456789");
+
+        // filling part of the hole back in only takes the one block
+        let mut file = tfs.open_existing("sparse.bin").unwrap();
+        io::Seek::seek(&mut file, SeekFrom::Start(BLOCK_SIZE as u64 + 1)).unwrap();
+        file.write(b"abc").unwrap();
+        assert_eq!(file.stat().unwrap().blocks_allocated, 2);
+        // punching the whole file only frees what it can, the last block is just zeroed
+        file.punch_hole(0, OFFSET + 10).unwrap();
+        let stat = file.stat().unwrap();
+        assert_eq!(stat.size, OFFSET + 10);
+        assert_eq!(stat.blocks_allocated, 1);
+        drop(file);
+        assert_eq!(tfs.statfs().unwrap().used_blocks, used + 2);
+        assert!(tfs.read("sparse.bin").unwrap().iter().all(|&b| b == 0));
+        drop(tfs);
+
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("sparse.bin").unwrap(), [0; OFFSET as usize + 10]);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn block_device_works() {
+        let mut device = MemDisk::new();
+        Tfs::mkfs_on(&mut device, &MkfsOptions::new()).unwrap();
+        assert_eq!(device.block_count(), DEFAULT_DISK_SIZE / BLOCK_SIZE);
+        {
+            let tfs = Tfs::mount_on(&mut device, &MountOptions::new()).unwrap();
+            tfs.write_file("test.txt", b"Hello, World!").unwrap();
+            tfs.mkdir("dir").unwrap();
+            tfs.write_file("dir/big", &[7; 3 * BLOCK_SIZE]).unwrap();
+            tfs.unmount().unwrap();
+        }
+        let tfs = Tfs::mount_on(&mut device, MountOptions::new().read_only(true)).unwrap();
+        assert!(!tfs.was_unclean());
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello, World!");
+        assert_eq!(tfs.read("dir/big").unwrap(), vec![7; 3 * BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn io_device_works() {
+        let mut cursor = io::Cursor::new(Vec::new());
+        Tfs::mkfs_into(&mut cursor, DEFAULT_DISK_SIZE).unwrap();
+        assert_eq!(cursor.get_ref().len(), DEFAULT_DISK_SIZE);
+        Tfs::mount_from(&mut cursor)
+            .unwrap()
+            .write_file("test.txt", b"Hello, World!")
+            .unwrap();
+        let tfs = Tfs::mount_from(&mut cursor).unwrap();
+        assert!(!tfs.was_unclean());
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello, World!");
+    }
+
+    #[test]
+    fn mount_bytes_works() {
+        // hello.txt and docs/readme.txt, made with mkfs at the current format version
+        const IMAGE: &[u8] = include_bytes!("../fixtures/embedded.disk");
+        let tfs = Tfs::mount_bytes(IMAGE).unwrap();
+        assert_eq!(tfs.read("hello.txt").unwrap(), b"Hello, World!");
+        assert_eq!(
+            tfs.read("docs/readme.txt").unwrap(),
+            b"served straight out of the binary"
+        );
+        assert!(matches!(
+            tfs.write_file("hello.txt", b"meow"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            tfs.mkdir("more"),
+            Err(TfsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            Tfs::mount_bytes(&IMAGE[1..]),
+            Err(TfsError::DiskError(DiskError::InvalidSize { .. }))
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_works() {
+        const DISK_PATH: &str = "mmap-tfs-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let data: Vec<u8> = (0..BLOCK_SIZE * 12).map(|i| (i % 251) as u8).collect();
+        Tfs::mount_mmap(DISK_PATH)
+            .unwrap()
+            .write_file("big", &data)
+            .unwrap();
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        let overhead = count_read_syscalls(|| ()).1;
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let reads = tfs.disk.reads();
+        let (contents, file_syscalls) = count_read_syscalls(|| read_all(&mut tfs, "big"));
+        assert_eq!(contents, data);
+        let file_reads = tfs.disk.reads() - reads;
+        drop(tfs);
+        let device = MmapDisk::open(DISK_PATH, 0).unwrap();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        let reads = tfs.disk.reads();
+        let (contents, mmap_syscalls) = count_read_syscalls(|| read_all(&mut tfs, "big"));
+        assert_eq!(contents, data);
+        // the disk's own counters are kept above the device, so they're the same either way
+        assert_eq!(tfs.disk.reads() - reads, file_reads);
+        // underneath them, every block read from the file is a syscall, and none from the map are
+        if let (Some(overhead), Some(file), Some(mmap)) = (overhead, file_syscalls, mmap_syscalls) {
+            assert!(file >= overhead + file_reads as u64);
+            assert!(mmap <= overhead);
+        }
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    // how many read syscalls f makes on this thread, where linux keeps count
+    #[cfg(feature = "mmap")]
+    fn count_read_syscalls<T>(f: impl FnOnce() -> T) -> (T, Option<u64>) {
+        fn read_syscalls() -> Option<u64> {
+            let io = fs::read_to_string("/proc/thread-self/io").ok()?;
+            io.lines()
+                .find_map(|line| line.strip_prefix("syscr: "))?
+                .parse()
+                .ok()
+        }
+        let before = read_syscalls();
+        let result = f();
+        let after = read_syscalls();
+        (
+            result,
+            after.zip(before).map(|(after, before)| after - before),
+        )
+    }
+
+    #[test]
+    fn clock_works() {
+        fn clock() -> SystemTime {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
+        }
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(
+            &mut disk,
+            MkfsOptions::new()
+                .clock(clock)
+                .file("made.txt", *b"at mkfs"),
+        )
+        .unwrap();
+        let tfs = Tfs::mount_on(&mut disk, MountOptions::new().clock(clock)).unwrap();
+        assert_eq!(tfs.metadata("made.txt").unwrap().btime, clock());
+        tfs.write_file("test.txt", b"Hello, World!").unwrap();
+        tfs.read("test.txt").unwrap();
+        tfs.mkdir("dir").unwrap();
+        for path in ["test.txt", "dir"] {
+            let stat = tfs.metadata(path).unwrap();
+            assert_eq!(
+                [stat.btime, stat.ctime, stat.mtime, stat.atime],
+                [clock(); 4]
+            );
+        }
+    }
+
+    #[test]
+    fn mem_disk_works() {
+        const DISK_PATH: &str = "mem-disk.bin";
+        let mut disk = Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap();
+        Tfs::mount_on(&mut disk, &MountOptions::new())
+            .unwrap()
+            .write_file("test.txt", b"Hello, World!")
+            .unwrap();
+        // the bytes are a perfectly good image file
+        let bytes = disk.into_bytes();
+        assert_eq!(bytes.len(), DEFAULT_DISK_SIZE);
+        fs::write(DISK_PATH, &bytes).unwrap();
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        assert_eq!(
+            Tfs::mount(DISK_PATH).unwrap().read("test.txt").unwrap(),
+            b"Hello, World!"
+        );
+        // and back again
+        let disk = MemDisk::from_bytes(&fs::read(DISK_PATH).unwrap()).unwrap();
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("test.txt").unwrap(), b"Hello, World!");
+        assert!(matches!(
+            MemDisk::from_bytes(&bytes[1..]),
+            Err(DiskError::InvalidSize { .. })
+        ));
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_handles_work() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut a = tfs.open("a.txt").unwrap();
+        let mut b = tfs.open("b.txt").unwrap();
+        let mut reader = tfs.open("a.txt").unwrap();
+        a.write(b"Hello, ").unwrap();
+        b.write(b"Goodbye").unwrap();
+        let mut buf = [0; 7];
+        assert_eq!(reader.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf, b"Hello, ");
+        a.write(b"World!").unwrap();
+        b.write(b", World!").unwrap();
+        // each handle keeps its own offset
+        assert_eq!(reader.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"World!");
+        assert_eq!(a.stat().unwrap().size, 13);
+        drop((a, b, reader));
+        assert_eq!(tfs.read("a.txt").unwrap(), b"Hello, World!");
+        assert_eq!(tfs.read("b.txt").unwrap(), b"Goodbye, World!");
+    }
+
+    #[test]
+    fn try_clone_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut file = tfs.open("test.txt").unwrap();
+        file.write(b"header").unwrap();
+        let mut clone = file.try_clone().unwrap();
+        // starts where the original was
+        assert_eq!(io::Seek::stream_position(&mut clone).unwrap(), 6);
+        file.write(b"payload").unwrap();
+        io::Seek::seek(&mut clone, SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 13];
+        assert_eq!(clone.read(&mut buf).unwrap(), 13);
+        assert_eq!(&buf, b"headerpayload");
+        // and the other way around, with the original's offset left alone
+        io::Seek::seek(&mut clone, SeekFrom::Start(0)).unwrap();
+        clone.write(b"HEADER").unwrap();
+        assert_eq!(io::Seek::stream_position(&mut file).unwrap(), 13);
+        io::Seek::seek(&mut file, SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 13);
+        assert_eq!(&buf, b"HEADERpayload");
+
+        clone.close().unwrap();
+        file.write(b"!").unwrap();
+        file.flush().unwrap();
+        assert_eq!(file.stat().unwrap().size, 14);
+        drop(file);
+        assert_eq!(tfs.read("test.txt").unwrap(), b"HEADERpayload!");
+        // the clone keeps the file open as much as the original does
+        let file = tfs.open("test.txt").unwrap();
+        let clone = file.try_clone().unwrap();
+        drop(file);
+        assert!(matches!(tfs.remove("test.txt"), Err(TfsError::FileBusy(_))));
+        drop(clone);
+        tfs.remove("test.txt").unwrap();
+    }
+
+    #[test]
+    fn read_to_end_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let contents: Vec<u8> = (0..BLOCK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        tfs.write_file("big", &contents).unwrap();
+        let mut file = tfs.open_existing("big").unwrap();
+        let mut buf = b"before".to_vec();
+        assert_eq!(file.read_to_end(&mut buf).unwrap(), contents.len());
+        assert_eq!(&buf[..6], b"before");
+        assert_eq!(&buf[6..], contents);
+        // already at the end
+        assert_eq!(file.read_to_end(&mut buf).unwrap(), 0);
+
+        // from wherever the offset is, not the start
+        io::Seek::seek(&mut file, SeekFrom::Start(BLOCK_SIZE as u64 + 1)).unwrap();
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, contents[BLOCK_SIZE + 1..]);
+    }
+
+    #[test]
+    fn read_to_string_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let text = "héllo ".repeat(BLOCK_SIZE / 4);
+        tfs.write_file("text", text.as_bytes()).unwrap();
+        let mut string = String::new();
+        let read = tfs
+            .open_existing("text")
+            .unwrap()
+            .read_to_string(&mut string)
+            .unwrap();
+        assert_eq!(read, text.len());
+        assert_eq!(string, text);
+
+        tfs.write_file("binary", &[b'o', b'k', 0xFF, 0xFE]).unwrap();
+        let mut string = String::from("untouched");
+        let err = tfs
+            .open_existing("binary")
+            .unwrap()
+            .read_to_string(&mut string)
+            .unwrap_err();
+        assert!(matches!(err, TfsError::InvalidUtf8(_)));
+        assert_eq!(string, "untouched");
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn buf_read_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut text = String::new();
+        let mut records = Vec::new();
+        for i in 0.. {
+            let record = format!("key{i} = {}", "value".repeat(i % 7));
+            if text.len() + record.len() + 1 > BLOCK_SIZE * 3 {
+                break;
+            }
+            text.push_str(&record);
+            text.push('\n');
+            records.push(record);
+        }
+        // at least one of them has to cross into the next block
+        let mut offset = 0;
+        assert!(records.iter().any(|record| {
+            let start = offset;
+            offset += record.len() + 1;
+            start / BLOCK_SIZE != (offset - 1) / BLOCK_SIZE
+        }));
+        tfs.write_file("config", text.as_bytes()).unwrap();
+
+        let file = tfs.open_existing("config").unwrap();
+        let lines: Vec<String> = io::BufRead::lines(file).map(Result::unwrap).collect();
+        assert_eq!(lines, records);
+
+        let mut file = tfs.open_existing("config").unwrap();
+        let mut line = String::new();
+        io::BufRead::read_line(&mut file, &mut line).unwrap();
+        assert_eq!(line, format!("{}\n", records[0]));
+        // plain reads carry on from wherever the lines left off
+        let mut rest = Vec::new();
+        io::Read::read_to_end(&mut file, &mut rest).unwrap();
+        assert_eq!(rest, text.as_bytes()[line.len()..]);
+        io::Seek::seek(&mut file, SeekFrom::Start(0)).unwrap();
+        let keys = io::BufRead::split(file, b'=').count();
+        assert_eq!(keys, records.len() + 1);
+    }
+
+    #[test]
+    fn peek_and_skip_work() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let contents: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 251) as u8).collect();
+        tfs.write_file("data", &contents).unwrap();
+        let mut file = tfs.open_existing("data").unwrap();
+        let atime = file.stat().unwrap().atime;
+        assert_eq!(file.peek_byte().unwrap(), Some(contents[0]));
+        assert_eq!(file.peek_byte().unwrap(), Some(contents[0]));
+        assert_eq!(file.stat().unwrap().atime, atime);
+        assert_eq!(file.read_byte().unwrap(), Some(contents[0]));
+        assert_eq!(file.read_byte().unwrap(), Some(contents[1]));
+
+        // straight over the middle blocks without reading them
+        tfs.reset_stats();
+        assert_eq!(file.skip(BLOCK_SIZE * 3).unwrap(), BLOCK_SIZE * 3);
+        assert_eq!(tfs.io_stats().blocks_read, 0);
+        assert_eq!(
+            io::Seek::stream_position(&mut file).unwrap(),
+            BLOCK_SIZE as u64 * 3 + 2
+        );
+        assert_eq!(
+            file.peek_byte().unwrap(),
+            Some(contents[BLOCK_SIZE * 3 + 2])
+        );
+        let mut buf = [0; 2];
+        file.read(&mut buf).unwrap();
+        assert_eq!(buf, contents[BLOCK_SIZE * 3 + 2..BLOCK_SIZE * 3 + 4]);
+
+        // only as far as the end
+        assert_eq!(file.skip(BLOCK_SIZE).unwrap(), BLOCK_SIZE - 4);
+        assert_eq!(file.peek_byte().unwrap(), None);
+        assert_eq!(file.skip(1).unwrap(), 0);
+        io::Seek::seek(&mut file, SeekFrom::End(10)).unwrap();
+        assert_eq!(file.skip(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn positional_io_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut file = tfs.open("records").unwrap();
+        file.write(b"0123456789").unwrap();
+        assert_eq!(file.write_at(2, b"ab").unwrap(), 2);
+        let mut buf = [0; 4];
+        assert_eq!(file.read_at(1, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"1ab4");
+        assert_eq!(io::Seek::stream_position(&mut file).unwrap(), 10);
+        // the sequential writes carry on from where they left off
+        file.write(b"!").unwrap();
+        assert_eq!(file.read_at(8, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"89!");
+
+        // past the end leaves a hole
+        let mut clone = file.try_clone().unwrap();
+        clone.write_at(BLOCK_SIZE * 2, b"end").unwrap();
+        assert_eq!(file.stat().unwrap().size, BLOCK_SIZE as u64 * 2 + 3);
+        assert_eq!(file.read_at(BLOCK_SIZE, &mut buf).unwrap(), 4);
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(file.read_at(BLOCK_SIZE * 2 + 1, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"nd");
+        assert_eq!(io::Seek::stream_position(&mut file).unwrap(), 11);
+        assert_eq!(io::Seek::stream_position(&mut clone).unwrap(), 11);
+        let mut buf = [0; 11];
+        io::Seek::seek(&mut file, SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 11);
+        assert_eq!(&buf, b"01ab456789!");
+    }
+
+    #[test]
+    fn file_locks_work() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut a = tfs.open("locked").unwrap();
+        let mut b = tfs.open("locked").unwrap();
+        a.write(b"mine").unwrap();
+        a.lock_exclusive().unwrap();
+        assert!(matches!(b.write(b"yours"), Err(TfsError::WouldBlock(_))));
+        assert!(matches!(b.read(&mut [0; 4]), Err(TfsError::WouldBlock(_))));
+        assert!(matches!(b.try_lock_shared(), Err(TfsError::WouldBlock(_))));
+        assert!(matches!(
+            tfs.open_with("locked", TfsOpenOptions::new().write(true).truncate(true)),
+            Err(TfsError::WouldBlock(_))
+        ));
+        // the holder carries on as normal
+        a.write_at(0, b"MINE").unwrap();
+        a.unlock().unwrap();
+        b.write_at(4, b"!").unwrap();
+
+        // readers can share, but nobody gets to write under them
+        a.lock_shared().unwrap();
+        b.lock_shared().unwrap();
+        assert_eq!(b.read_at(0, &mut [0; 5]).unwrap(), 5);
+        assert!(matches!(a.write(b"x"), Err(TfsError::WouldBlock(_))));
+        assert!(matches!(
+            a.try_lock_exclusive(),
+            Err(TfsError::WouldBlock(_))
+        ));
+        drop(b);
+        a.try_lock_exclusive().unwrap();
+
+        // a clone is another handle as far as locks go
+        let mut clone = a.try_clone().unwrap();
+        let err = clone.set_len(0).unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::WouldBlock);
+        drop(a);
+        clone.set_len(0).unwrap();
+        assert_eq!(clone.stat().unwrap().size, 0);
+    }
+
+    #[test]
+    fn image_lock_works() {
+        const DISK_PATH: &str = "image-lock-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.write_file("file", b"contents").unwrap();
+            assert!(matches!(Tfs::mount(DISK_PATH), Err(TfsError::ImageBusy)));
+            assert!(matches!(
+                Tfs::mount_read_only(DISK_PATH),
+                Err(TfsError::ImageBusy)
+            ));
+            assert!(matches!(Tfs::check(DISK_PATH), Err(TfsError::ImageBusy)));
+            // opting out still gets to look
+            let shared = Tfs::mount_shared(DISK_PATH).unwrap();
+            assert!(matches!(
+                shared.write_file("other", b""),
+                Err(TfsError::ReadOnlyFilesystem)
+            ));
+        }
+        {
+            // read only mounts only keep out writers
+            let _a = Tfs::mount_read_only(DISK_PATH).unwrap();
+            let b = Tfs::mount_read_only(DISK_PATH).unwrap();
+            assert_eq!(b.read("file").unwrap(), b"contents");
+            let err = Tfs::mount(DISK_PATH).unwrap_err();
+            assert_eq!(io::Error::from(err).kind(), io::ErrorKind::ResourceBusy);
         }
+        Tfs::mount(DISK_PATH).unwrap();
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn mkfs(path: impl AsRef<Path>, size: usize) -> TfsResult<()> {
-        TfsFs::mkfs(path, size)
-    }
+    #[test]
+    fn io_stats_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut file = tfs.open("file").unwrap();
+        file.write(&[1; BLOCK_SIZE]).unwrap();
+        file.flush().unwrap();
+        tfs.reset_stats();
+        file.write_at(0, b"small").unwrap();
+        file.flush().unwrap();
+        let stats = tfs.io_stats();
+        // the data block, the inode and the superblock
+        assert!(stats.blocks_written <= 3);
+        assert_eq!(
+            stats.bytes_written,
+            stats.blocks_written * BLOCK_SIZE as u64
+        );
+        assert_eq!(stats.syncs, 0);
 
-    pub fn mount(path: impl AsRef<Path>) -> TfsResult<Self> {
-        let tfs = TfsFs::mount(path)?;
-        Ok(Self {
-            tfs: RefCell::new(tfs),
-        })
+        tfs.reset_stats();
+        tfs.sync_all().unwrap();
+        assert_eq!(tfs.io_stats().syncs, 1);
+        assert_eq!(tfs.read("file").unwrap()[..5], *b"small");
+        assert_eq!(tfs.io_stats().blocks_read, 1);
     }
 
-    pub fn readdir<'a>(&'a self) -> Vec<ReadDirEntry> {
-        self.tfs.borrow().readdir().collect()
-    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_works() {
+        use tracing::{field::Field, span, Event, Subscriber};
+        use tracing_subscriber::{
+            layer::{Context, SubscriberExt},
+            Layer,
+        };
 
-    pub fn open(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFile> {
-        let mut tfs = self.tfs.borrow_mut();
-        let file = tfs.open(filename)?;
-        Ok(TfsFile {
-            filesystem: &self.tfs,
-            file,
-        })
+        // span names as they're created, and events as their level and fields
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl<S: Subscriber> Layer<S> for Recorder {
+            fn on_new_span(&self, attrs: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(attrs.metadata().name().to_string());
+            }
+
+            fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+                let mut fields = event.metadata().level().to_string();
+                event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                    fields += &format!(" {field}={value:?}");
+                });
+                self.0.lock().unwrap().push(fields);
+            }
+        }
+
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut disk = MemDisk::new();
+            Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+            let mut tfs = TfsFs::mount_on(disk, &MountOptions::new()).unwrap();
+            let mut file = tfs.open("file").unwrap();
+            tfs.write(&mut file, b"traced").unwrap();
+            tfs.sync().unwrap();
+            let mut read_only = tfs
+                .open_with("file", TfsOpenOptions::new().read(true))
+                .unwrap();
+            assert!(tfs.write(&mut read_only, b"nope").is_err());
+        });
+        let recorded = recorder.0.lock().unwrap();
+        for name in ["mkfs", "mount", "open", "write", "sync"] {
+            assert!(recorded.iter().any(|r| r == name), "no {name} span");
+        }
+        let has = |level: &str, text: &str| {
+            recorded
+                .iter()
+                .any(|r| r.starts_with(level) && r.contains(text))
+        };
+        assert!(has("TRACE", "allocated block"));
+        assert!(has("TRACE", "inode written"));
+        assert!(has("TRACE", "superblock written"));
+        assert!(has("WARN", "File not opened for writing"));
     }
 
-    pub fn sync(&mut self) -> TfsResult<()> {
-        // TODO: sync only this file not the whole filesystem
-        self.tfs.borrow_mut().sync()
+    #[test]
+    fn observer_works() {
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl Recorder {
+            fn push(&self, event: String) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        impl FsObserver for Recorder {
+            fn on_create(&mut self, path: &str) {
+                self.push(format!("create {path}"));
+            }
+
+            fn on_write(&mut self, path: &str, offset: u64, len: usize) {
+                self.push(format!("write {path} {offset} {len}"));
+            }
+
+            fn on_rename(&mut self, from: &str, to: &str) {
+                self.push(format!("rename {from} {to}"));
+            }
+
+            fn on_remove(&mut self, path: &str) {
+                self.push(format!("remove {path}"));
+            }
+
+            fn on_sync(&mut self) {
+                self.push("sync".to_string());
+            }
+        }
+
+        let mut tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        tfs.write_file("before", b"not seen").unwrap();
+        let recorder = Recorder::default();
+        tfs.set_observer(Box::new(recorder.clone()));
+        {
+            let mut file = tfs.open("a").unwrap();
+            file.write(b"hello").unwrap();
+            file.write(b" world").unwrap();
+            // failures aren't reported
+            assert!(tfs.mkdir("a").is_err());
+            tfs.mkdir("dir").unwrap();
+            file.rename("dir/b").unwrap();
+        }
+        tfs.write_file("dir/c", b"c").unwrap();
+        tfs.rename("before", "after").unwrap();
+        tfs.remove("dir/b").unwrap();
+        tfs.sync().unwrap();
+        // nothing is left to write, so neither of these are reported
+        tfs.sync().unwrap();
+        {
+            let batch = tfs.batch();
+            batch.sync().unwrap();
+        }
+        let mut file = tfs.open("dir/c").unwrap();
+        file.write(b"d").unwrap();
+        tfs.sync().unwrap();
+        drop(file);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "create a",
+                "write a 0 5",
+                "write a 5 6",
+                "create dir",
+                "rename a dir/b",
+                "create dir/c",
+                "write dir/c 0 1",
+                "rename before after",
+                "remove dir/b",
+                "sync",
+                "write dir/c 0 1",
+                "sync",
+            ]
+        );
     }
-}
 
-impl Drop for Tfs {
-    fn drop(&mut self) {
-        self.sync().unwrap();
+    #[test]
+    fn write_runs_work() {
+        const DISK_PATH: &str = "write-runs-disk.bin";
+        let harry = include_bytes!("../harry-sm.jpg");
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut file = tfs.open("cat.jpg").unwrap();
+            tfs.reset_stats();
+            file.write(harry).unwrap();
+            let stats = tfs.io_stats();
+            assert!(stats.blocks_written as usize >= harry.len() / BLOCK_SIZE);
+            // the whole blocks in runs, then the rest
+            assert!(stats.write_calls <= 4, "{stats:?}");
+            // a single block still goes on its own
+            tfs.reset_stats();
+            file.write(&[1; BLOCK_SIZE]).unwrap();
+            assert!(tfs.io_stats().write_calls <= 2);
+        }
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let contents = tfs.read("cat.jpg").unwrap();
+        assert_eq!(contents[..harry.len()], harry[..]);
+        assert_eq!(contents[harry.len()..], [1; BLOCK_SIZE]);
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
     }
-}
 
-#[derive(Debug)]
-pub struct TfsFs {
-    superblock: SuperBlock,
-    root: Root,
-    disk: Disk<BLOCK_SIZE>,
-}
+    #[test]
+    fn allocate_run_works() {
+        let mut superblock = SuperBlock::new();
+        // leave a gap of 3 from 5 and one of 10 from 13 that crosses into the next byte
+        for block in (0..5).chain(8..13).chain([23]) {
+            superblock.mark_allocated(block);
+        }
+        assert_eq!(superblock.allocate_run(0), None);
+        assert_eq!(superblock.allocate_run(4), Some(13));
+        assert!((13..17).all(|block| superblock.is_allocated(block)));
+        assert_eq!(superblock.allocate_run(3), Some(5));
+        assert_eq!(superblock.allocate_run(6), Some(17));
+        assert!(!superblock.is_allocated(24));
+        assert_eq!(superblock.allocate_run(2), Some(24));
 
-impl TfsFs {
-    pub fn new(disk: Disk<BLOCK_SIZE>) -> Self {
+        // with nothing big enough nothing is taken
+        let mut full = SuperBlock::new();
+        for block in (0..(ALLOCATION_TABLE_LEN * 8) as u16).step_by(2) {
+            full.mark_allocated(block);
+        }
+        let free = full.allocated_blocks;
+        assert_eq!(full.allocate_run(2), None);
+        assert_eq!(full.allocated_blocks, free);
+        assert_eq!(full.allocate_block(), Some(1));
+    }
+
+    #[test]
+    fn free_count_works() {
         let mut superblock = SuperBlock::new();
+        let recount = |superblock: &SuperBlock| {
+            (0..MAX_BLOCKS as u16)
+                .filter(|&block| !superblock.is_allocated(block))
+                .count()
+        };
+        // block 0 is the superblock's, and never freed
         superblock.mark_allocated(0);
-        superblock.mark_allocated(1);
-        Self {
-            superblock,
-            root: Root::new(),
-            disk,
+        let mut allocated = Vec::new();
+        // a cheap, repeatable shuffle of allocations and frees
+        let mut state = 12345u32;
+        for _ in 0..5000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            if state.is_multiple_of(3) && !allocated.is_empty() {
+                let block = allocated.swap_remove(state as usize / 3 % allocated.len());
+                superblock.mark_free(block);
+            } else if let Some(block) = superblock.allocate_block() {
+                allocated.push(block);
+            }
+            assert_eq!(superblock.free_count(), recount(&superblock));
         }
+        // marking twice or freeing twice doesn't count twice
+        let block = allocated.swap_remove(0);
+        superblock.mark_allocated(block);
+        superblock.mark_free(block);
+        superblock.mark_free(block);
+        assert_eq!(superblock.free_count(), recount(&superblock));
+        assert_eq!(superblock.free_count(), MAX_BLOCKS - allocated.len() - 1);
+
+        while superblock.allocate_block().is_some() {}
+        assert_eq!(superblock.free_count(), 0);
+        assert_eq!(superblock.allocate_run(1), None);
+        // and a remount works it out again from the table
+        let data = SuperBlockData::from(superblock.clone());
+        assert_eq!(SuperBlock::from(data).free_count(), 0);
     }
 
-    pub fn mkfs(path: impl AsRef<Path>, size: usize) -> TfsResult<()> {
-        let mut disk: Disk<BLOCK_SIZE> = Disk::open(path, size)?;
-        for i in 0..(size / BLOCK_SIZE) {
-            disk.write_block(i, [0; BLOCK_SIZE])?;
+    #[test]
+    fn next_fit_works() {
+        let mut superblock = SuperBlock::new();
+        let first: Vec<_> = (0..10)
+            .map(|_| superblock.allocate_block().unwrap())
+            .collect();
+        assert_eq!(first, (0..10).collect::<Vec<_>>());
+        // a gap behind the cursor is only gone back for once everything after it is used
+        superblock.mark_free(3);
+        assert_eq!(superblock.allocate_block(), Some(10));
+        while superblock.free_count() > 1 {
+            assert_ne!(superblock.allocate_block(), Some(3));
         }
-        TfsFs::new(disk).sync()?;
+        assert_eq!(superblock.allocate_block(), Some(3));
+        assert_eq!(superblock.allocate_block(), None);
+    }
 
-        Ok(())
+    #[test]
+    fn allocation_queries_work() {
+        let mut superblock = SuperBlock::new();
+        // either side of a byte boundary, and into the last byte of the table
+        for block in [0, 7, 8, 9, 15, 16, MAX_BLOCKS as u16 - 1] {
+            superblock.mark_allocated(block);
+        }
+        assert!(superblock.is_allocated(7) && superblock.is_allocated(8));
+        assert!(!superblock.is_allocated(6) && !superblock.is_allocated(10));
+        assert!(!superblock.is_allocated(MAX_BLOCKS as u16));
+        assert_eq!(
+            superblock.iter_allocated().collect::<Vec<_>>(),
+            [0, 7, 8, 9, 15, 16, MAX_BLOCKS as u16 - 1]
+        );
+        assert_eq!(superblock.free_blocks(MAX_BLOCKS), MAX_BLOCKS - 7);
+        // a disk ending part way through a byte
+        assert_eq!(superblock.free_blocks(13), 13 - 4);
+        assert_eq!(superblock.free_blocks(16), 16 - 5);
+        assert_eq!(superblock.free_blocks(17), 17 - 6);
+
+        let allocation = Allocation {
+            superblock,
+            total_blocks: 13,
+        };
+        assert_eq!(
+            allocation.iter_allocated().collect::<Vec<_>>(),
+            [0, 7, 8, 9]
+        );
+        assert!(!allocation.is_allocated(15));
+        assert_eq!(allocation.free_blocks(), 9);
+
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let before = tfs.allocation().unwrap();
+        assert_eq!(before.total_blocks(), DEFAULT_DISK_SIZE / BLOCK_SIZE);
+        assert_eq!(before.free_blocks(), tfs.statfs().unwrap().free_blocks);
+        let mut file = tfs.open("file").unwrap();
+        file.write_all(&[1; BLOCK_SIZE]).unwrap();
+        let after = tfs.allocation().unwrap();
+        assert_eq!(after.free_blocks(), before.free_blocks() - 2);
+        let new: Vec<_> = after
+            .iter_allocated()
+            .filter(|&block| !before.is_allocated(block))
+            .collect();
+        assert_eq!(new.len(), 2);
     }
 
-    pub fn mount(path: impl AsRef<Path>) -> TfsResult<Self> {
-        let mut disk: Disk<BLOCK_SIZE> = Disk::open(path, 0)?;
-        let superblock = disk.read_block(0)?;
-        if superblock[0] != 0x5A {
-            return Err(TfsError::MagicNumberError(superblock[0]));
+    #[test]
+    fn fragmented_write_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let mut fs = lock_write(&tfs.tfs);
+        // leave the free space near the start in single block gaps
+        let taken: Vec<u16> = (0..16)
+            .filter_map(|_| fs.superblock.allocate_block())
+            .collect();
+        for &block in taken.iter().step_by(2) {
+            fs.superblock.mark_free(block);
         }
-        let superblock: SuperBlockData = bincode::deserialize(&superblock)?;
-        let root = disk.read_block(superblock.root_inode as usize)?;
-        let root: RootData = bincode::deserialize(&root)?;
-        Ok(Self {
-            superblock: superblock.into(),
-            root: Root::from_data(root, &mut disk)?,
-            disk,
-        })
+        let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i / 7) as u8).collect();
+        let mut file = fs.open("file").unwrap();
+        assert_eq!(fs.write(&mut file, &data).unwrap(), data.len());
+        let blocks = &fs.root.inodes[file.inode].blocks;
+        assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert!(blocks[0] > taken[15]);
+        fs.seek(&mut file, SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
     }
 
-    fn create_inode(&mut self, filename: String) -> TfsResult<usize> {
-        let inode = self
-            .superblock
-            .allocate_block()
-            .ok_or(TfsError::OutOfSpace)?;
-        Ok(self.root.create_inode(inode, filename))
+    #[test]
+    fn allocation_stays_on_disk() {
+        const DISK_PATH: &str = "bounded-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let chunk = [7; BLOCK_SIZE];
+        let mut file = tfs.open("big").unwrap();
+        let err = loop {
+            match file.write(&chunk) {
+                Ok(_) => {
+                    assert!(fs::metadata(DISK_PATH).unwrap().len() <= DEFAULT_DISK_SIZE as u64)
+                }
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, TfsError::OutOfSpace));
+        assert_eq!(tfs.statfs().unwrap().free_blocks, 0);
+        assert!(matches!(tfs.touch("more"), Err(TfsError::OutOfSpace)));
+        drop(file);
+        drop(tfs);
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            DEFAULT_DISK_SIZE as u64
+        );
+
+        // the size comes from the superblock, growing the image doesn't make any more room
+        let image = fs::OpenOptions::new().write(true).open(DISK_PATH).unwrap();
+        image.set_len(DEFAULT_DISK_SIZE as u64 * 2).unwrap();
+        drop(image);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(
+            tfs.statfs().unwrap().total_blocks,
+            DEFAULT_DISK_SIZE / BLOCK_SIZE
+        );
+        assert!(matches!(tfs.touch("more"), Err(TfsError::OutOfSpace)));
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn open(&mut self, filename: impl AsRef<Path>) -> TfsResult<TfsFsFile> {
-        let filename = filename.as_ref().to_str().unwrap();
-        let inode = self
-            .root
-            .inodes
-            .iter()
-            .enumerate()
-            .find(|(_, inode_)| inode_.filename == filename)
-            .map(|(i, _)| i)
-            .or_else(|| self.create_inode(filename.to_string()).ok());
-        self.sync()?;
-        if let Some(inode) = inode {
-            self.root.inodes[inode].stat.atime = SystemTime::now();
-            Ok(TfsFsFile { inode, offset: 0 })
-        } else {
-            Err(TfsError::OutOfSpace)
+    #[test]
+    fn mount_missing_fails() {
+        const DISK_PATH: &str = "missing-disk.bin";
+        let _ = fs::remove_file(DISK_PATH);
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::DiskError(DiskError::NotFound))
+        ));
+        let err = io::Error::from(TfsFs::mount(DISK_PATH).unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(
+            Tfs::check(DISK_PATH),
+            Err(TfsError::DiskError(DiskError::NotFound))
+        ));
+        // rather than an empty image being left there to fail the magic number check
+        assert!(fs::metadata(DISK_PATH).is_err());
+    }
+
+    #[test]
+    fn truncated_image_fails() {
+        const DISK_PATH: &str = "truncated-disk.bin";
+        // as big as an image gets, which is only sized rather than written out block by block
+        MkfsOptions::new()
+            .blocks(MAX_BLOCKS)
+            .journal_blocks(8)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let size = (MAX_BLOCKS * BLOCK_SIZE) as u64;
+        assert_eq!(fs::metadata(DISK_PATH).unwrap().len(), size);
+        drop(Tfs::mount(DISK_PATH).unwrap());
+
+        let image = fs::OpenOptions::new().write(true).open(DISK_PATH).unwrap();
+        image.set_len(size / 2).unwrap();
+        drop(image);
+        for result in [
+            Tfs::mount(DISK_PATH).map(drop),
+            Tfs::check(DISK_PATH).map(drop),
+        ] {
+            assert!(matches!(
+                result,
+                Err(TfsError::TruncatedImage { expected, actual })
+                    if expected == MAX_BLOCKS && actual == MAX_BLOCKS / 2
+            ));
         }
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn close(&mut self, _file: &mut TfsFsFile) -> TfsResult<()> {
-        self.sync()
+    #[test]
+    fn zero_fill_works() {
+        let old = vec![0xAA; DEFAULT_DISK_SIZE];
+        let mut disk = MemDisk::from_bytes(&old).unwrap();
+        Tfs::mkfs_on(&mut disk, MkfsOptions::new().journal_blocks(8)).unwrap();
+        // only the filesystem's own blocks were cleared, and it's none the worse for it. the
+        // superblock could have any bytes at all in it, but an empty root and journal are zeros
+        let image = disk.clone().into_bytes();
+        assert!(image[BLOCK_SIZE..10 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(image[10 * BLOCK_SIZE..11 * BLOCK_SIZE]
+            .iter()
+            .all(|&b| b == 0xAA));
+        let tfs = Tfs::mount_on(&mut disk, &MountOptions::new()).unwrap();
+        tfs.write_file("file", b"Hello").unwrap();
+        tfs.unmount().unwrap();
+        let tfs = Tfs::mount_on(&mut disk, &MountOptions::new()).unwrap();
+        assert!(!tfs.was_unclean());
+        assert_eq!(tfs.read("file").unwrap(), b"Hello");
+        assert_eq!(tfs.readdir().len(), 1);
+        drop(tfs);
+
+        let mut disk = MemDisk::from_bytes(&old).unwrap();
+        Tfs::mkfs_on(&mut disk, MkfsOptions::new().zero_fill(true)).unwrap();
+        let image = disk.into_bytes();
+        assert!(image[BLOCK_SIZE..DEFAULT_DISK_SIZE - BLOCK_SIZE]
+            .iter()
+            .all(|&b| b == 0));
+        assert_eq!(image.len(), DEFAULT_DISK_SIZE);
     }
 
-    pub fn write(&mut self, file: &mut TfsFsFile, buf: &[u8]) -> TfsResult<()> {
-        let inode = self.root.inodes.get_mut(file.inode).unwrap();
-        inode.stat.mtime = SystemTime::now();
-        for bytes in buf.chunks(BLOCK_SIZE) {
-            let block = self
-                .superblock
-                .allocate_block()
-                .ok_or(TfsError::OutOfSpace)?;
-            inode.push_block(block);
-            let bytes_written = bytes.len();
-            let bytes = if bytes.len() == BLOCK_SIZE {
-                bytes.try_into().unwrap()
-            } else {
-                let mut bytes = bytes.to_vec();
-                bytes.resize(BLOCK_SIZE, 0);
-                bytes.try_into().unwrap()
-            };
-            self.disk.write_block(block as usize, bytes)?;
-            inode.stat.size += bytes_written as u16;
-            file.offset += bytes_written;
-        }
-        file.offset = 0;
-        self.sync()?;
-        Ok(())
+    #[test]
+    fn mkfs_refuses_existing() {
+        const DISK_PATH: &str = "existing-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        Tfs::mount(DISK_PATH)
+            .unwrap()
+            .write_file("keep", b"me")
+            .unwrap();
+        assert!(matches!(
+            TfsFs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE),
+            Err(TfsError::ImageExists(path)) if path == Path::new(DISK_PATH)
+        ));
+        // left exactly as it was
+        assert_eq!(Tfs::mount(DISK_PATH).unwrap().read("keep").unwrap(), b"me");
+
+        MkfsOptions::new().force(true).mkfs(DISK_PATH).unwrap();
+        assert!(Tfs::mount(DISK_PATH).unwrap().readdir().is_empty());
+
+        // made ahead of time but with nothing in it yet
+        fs::File::create(DISK_PATH).unwrap();
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        assert!(Tfs::mount(DISK_PATH).unwrap().readdir().is_empty());
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn read_byte(&mut self, file: &mut TfsFsFile) -> TfsResult<Option<u8>> {
-        let inode = self.root.inodes.get_mut(file.inode).unwrap();
-        inode.stat.atime = SystemTime::now();
-        if file.offset >= inode.stat.size as usize {
-            return Ok(None);
+    #[test]
+    fn grow_works() {
+        const DISK_PATH: &str = "grow-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let chunk = [3; BLOCK_SIZE];
+        let mut file = tfs.open("full").unwrap();
+        while file.write(&chunk).is_ok() {}
+        let full = tfs.metadata("full").unwrap().size;
+        drop(file);
+
+        assert!(matches!(
+            tfs.grow(DEFAULT_DISK_SIZE - BLOCK_SIZE),
+            Err(TfsError::SizeError { .. })
+        ));
+        assert!(matches!(
+            tfs.grow((MAX_BLOCKS + 1) * BLOCK_SIZE),
+            Err(TfsError::SizeError { .. })
+        ));
+        tfs.grow(DEFAULT_DISK_SIZE * 2).unwrap();
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            DEFAULT_DISK_SIZE as u64 * 2
+        );
+        let stats = tfs.statfs().unwrap();
+        assert_eq!(stats.total_blocks, DEFAULT_DISK_SIZE * 2 / BLOCK_SIZE);
+        // the old backup block is free, the new one's taken
+        assert_eq!(stats.free_blocks, DEFAULT_DISK_SIZE / BLOCK_SIZE);
+        tfs.write_file("more", &[4; BLOCK_SIZE * 8]).unwrap();
+        drop(tfs);
+
+        Tfs::resize(DISK_PATH, DEFAULT_DISK_SIZE * 3).unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.metadata("full").unwrap().size, full);
+        assert_eq!(tfs.read("more").unwrap(), [4; BLOCK_SIZE * 8]);
+        assert_eq!(
+            tfs.statfs().unwrap().total_blocks,
+            DEFAULT_DISK_SIZE * 3 / BLOCK_SIZE
+        );
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        // the backup made it to the new last block
+        let mut image = fs::read(DISK_PATH).unwrap();
+        image[0] = 0;
+        fs::write(DISK_PATH, &image).unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert!(tfs.used_backup_superblock());
+        assert_eq!(tfs.read("more").unwrap(), [4; BLOCK_SIZE * 8]);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn shrink_works() {
+        const DISK_PATH: &str = "shrink-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE * 2);
+        let mut tfs = Tfs::mount(DISK_PATH).unwrap();
+        let big: Vec<u8> = (0..BLOCK_SIZE * 12).map(|i| (i % 251) as u8).collect();
+        // the first file takes the low blocks, so everything after ends up in the upper half
+        tfs.write_file("early", &[1; BLOCK_SIZE * 30]).unwrap();
+        tfs.mkdir("dir").unwrap();
+        tfs.write_file("dir/late", &[2; BLOCK_SIZE * 12]).unwrap();
+        tfs.write_file("big", &big).unwrap();
+        tfs.link("dir/late", "alias").unwrap();
+        let half = DEFAULT_DISK_SIZE / BLOCK_SIZE;
+        assert!(tfs
+            .allocation()
+            .unwrap()
+            .iter_allocated()
+            .any(|block| block as usize >= half));
+
+        assert!(matches!(
+            tfs.shrink(DEFAULT_DISK_SIZE),
+            Err(TfsError::NoRoomToShrink { .. })
+        ));
+        assert!(matches!(
+            tfs.shrink(DEFAULT_DISK_SIZE * 3),
+            Err(TfsError::SizeError { .. })
+        ));
+        tfs.remove("early").unwrap();
+        tfs.shrink(DEFAULT_DISK_SIZE).unwrap();
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            DEFAULT_DISK_SIZE as u64
+        );
+        assert_eq!(tfs.read("big").unwrap(), big);
+        drop(tfs);
+
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.statfs().unwrap().total_blocks, half);
+        assert_eq!(tfs.read("dir/late").unwrap(), [2; BLOCK_SIZE * 12]);
+        assert_eq!(tfs.read("alias").unwrap(), [2; BLOCK_SIZE * 12]);
+        assert_eq!(tfs.read("big").unwrap(), big);
+        tfs.write_file("more", &[3; BLOCK_SIZE]).unwrap();
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        // offline it goes through the same thing
+        Tfs::resize(DISK_PATH, DEFAULT_DISK_SIZE * 2).unwrap();
+        Tfs::resize(DISK_PATH, DEFAULT_DISK_SIZE - BLOCK_SIZE * 4).unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        assert_eq!(tfs.read("big").unwrap(), big);
+        assert_eq!(tfs.read("more").unwrap(), [3; BLOCK_SIZE]);
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn generation_works() {
+        const DISK_PATH: &str = "generation-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let mut old = tfs.open("old").unwrap();
+        tfs.write(&mut old, b"old").unwrap();
+        tfs.seek(&mut old, SeekFrom::Start(0)).unwrap();
+        // with nothing else free, the new file has to go where the old one was
+        while tfs.superblock.allocate_block().is_some() {}
+        tfs.close(&mut old).unwrap();
+        tfs.remove("old").unwrap();
+        let mut new = tfs.open("new").unwrap();
+        tfs.write(&mut new, b"new").unwrap();
+        assert_eq!(new.block, old.block);
+        assert_ne!(new.generation, old.generation);
+
+        assert!(matches!(
+            tfs.read(&mut old, &mut [0; 3]),
+            Err(TfsError::InvalidDesc)
+        ));
+        assert!(matches!(
+            tfs.write(&mut old, b"x"),
+            Err(TfsError::InvalidDesc)
+        ));
+        assert!(matches!(tfs.stat(&old), Err(TfsError::InvalidDesc)));
+        tfs.seek(&mut new, SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 3];
+        tfs.read(&mut new, &mut buf).unwrap();
+        assert_eq!(&buf, b"new");
+        tfs.unmount().unwrap();
+        drop(tfs);
+
+        // it's kept on disk, and nothing made after a remount gets one already in use
+        let tfs = TfsFs::mount(DISK_PATH).unwrap();
+        let index = tfs.root.index_of(new.block).unwrap();
+        assert_eq!(tfs.root.inodes[index].generation, new.generation);
+        assert!(tfs.root.next_generation > new.generation);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn open_files_work() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        assert_eq!(tfs.open_handles("file"), 0);
+        let first = tfs.open("file").unwrap();
+        let mut second = tfs.open("file").unwrap();
+        assert_eq!(tfs.open_handles("file"), 2);
+        tfs.link("file", "other").unwrap();
+        assert_eq!(tfs.open_handles("other"), 2);
+        second.write(b"shared").unwrap();
+
+        // other names can go, but not the last one
+        tfs.remove("other").unwrap();
+        assert!(matches!(tfs.remove("file"), Err(TfsError::FileBusy(_))));
+        assert_eq!(
+            io::Error::from(tfs.remove("file").unwrap_err()).kind(),
+            io::ErrorKind::ResourceBusy
+        );
+        assert_eq!(tfs.read("file").unwrap(), b"shared");
+        // reading the whole file opens and closes it, it doesn't stay open
+        assert_eq!(tfs.open_handles("file"), 2);
+
+        first.close().unwrap();
+        assert_eq!(tfs.open_handles("file"), 1);
+        assert!(matches!(tfs.remove("file"), Err(TfsError::FileBusy(_))));
+        drop(second);
+        assert_eq!(tfs.open_handles("file"), 0);
+        tfs.remove("file").unwrap();
+        assert!(!tfs.exists("file"));
+        assert_eq!(tfs.open_handles("file"), 0);
+    }
+
+    #[test]
+    fn shared_reads_work() {
+        fn clock() -> SystemTime {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
         }
-        let block = inode.blocks.get(file.offset / BLOCK_SIZE).unwrap();
-        let block = self.disk.read_block(*block as usize)?;
-        let byte = block[file.offset % BLOCK_SIZE];
-        file.offset += 1;
-        Ok(Some(byte))
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        tfs.write_file("file", b"contents").unwrap();
+        let mut file = tfs.open("file").unwrap();
+        lock_write(&tfs.tfs).clock = clock;
+        // reading only needs the filesystem for reading, so a reader elsewhere doesn't hold it up
+        let held = lock_read(&tfs.tfs);
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf).unwrap(), 8);
+        assert_eq!(tfs.read("file").unwrap(), b"contents");
+        assert_eq!(held.metadata("file").unwrap().atime, clock());
+        drop(held);
+        assert_eq!(file.stat().unwrap().atime, clock());
     }
 
-    pub fn readdir<'a>(&'a self) -> impl Iterator<Item = ReadDirEntry> + 'a {
-        self.root
-            .inodes
-            .iter()
-            .map(|INode { filename, stat, .. }| ReadDirEntry {
-                filename: filename.to_string(),
-                stat: stat.clone(),
-            })
+    #[test]
+    fn lock_waiting_works() {
+        let tfs = Tfs::mount_mem(Tfs::mkfs_in_memory(DEFAULT_DISK_SIZE).unwrap()).unwrap();
+        let a = tfs.open("locked").unwrap();
+        let b = tfs.open("locked").unwrap();
+        a.lock_exclusive().unwrap();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| b.lock_shared());
+            thread::sleep(Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            a.unlock().unwrap();
+            waiter.join().unwrap().unwrap();
+        });
+        // closing the handle in the way lets go of its lock too
+        b.unlock().unwrap();
+        a.lock_shared().unwrap();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| b.lock_exclusive());
+            thread::sleep(Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            a.close().unwrap();
+            waiter.join().unwrap().unwrap();
+        });
     }
 
-    pub fn rename(&mut self, file: &mut TfsFsFile, newname: &str) -> TfsResult<()> {
-        let inode = self.root.inodes.get_mut(file.inode).unwrap();
-        inode.stat.mtime = SystemTime::now();
-        inode.filename = newname.to_string();
-        Ok(())
+    #[test]
+    fn threads_work() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Tfs>();
+        assert_send_sync::<TfsFile>();
+        assert_send_sync::<Tfs<MemDisk>>();
+
+        const DISK_PATH: &str = "threads-disk.bin";
+        MkfsOptions::new()
+            .blocks(512)
+            .force(true)
+            .mkfs(DISK_PATH)
+            .unwrap();
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let contents = |i: usize| vec![i as u8; BLOCK_SIZE * 2 + i];
+        thread::scope(|scope| {
+            for i in 0..8 {
+                let tfs = &tfs;
+                scope.spawn(move || {
+                    // chunk by chunk so the threads really do interleave
+                    let mut file = tfs.create(format!("{i}.bin")).unwrap();
+                    for chunk in contents(i).chunks(100) {
+                        file.write(chunk).unwrap();
+                    }
+                    drop(file);
+                    tfs.write_file(format!("{i}.txt"), &contents(i)).unwrap();
+                });
+            }
+        });
+        assert_eq!(tfs.readdir().len(), 16);
+        for i in 0..8 {
+            assert_eq!(tfs.read(format!("{i}.bin")).unwrap(), contents(i));
+            assert_eq!(tfs.read(format!("{i}.txt")).unwrap(), contents(i));
+        }
+        drop(tfs);
+        assert!(Tfs::check(DISK_PATH).unwrap().is_clean());
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn stat(&self, file: TfsFsFile) -> TfsResult<Stat> {
-        let inode = self.root.inodes.get(file.inode).unwrap();
-        Ok(inode.stat.clone())
+    #[test]
+    fn statfs_works() {
+        const DISK_PATH: &str = "statfs-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let total_blocks = DEFAULT_DISK_SIZE / BLOCK_SIZE;
+        assert_eq!(
+            tfs.statfs().unwrap(),
+            FsStats {
+                block_size: BLOCK_SIZE,
+                total_blocks,
+                free_blocks: total_blocks - 3,
+                used_blocks: 3,
+                files: 0,
+                max_files: total_blocks - 3,
+            }
+        );
+        tfs.write_file("test.txt", &[0x42; BLOCK_SIZE * 3]).unwrap();
+        let stats = tfs.statfs().unwrap();
+        assert_eq!(stats.used_blocks, 7);
+        assert_eq!(stats.free_blocks, total_blocks - 7);
+        assert_eq!(stats.files, 1);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
     }
 
-    pub fn sync(&mut self) -> TfsResult<()> {
-        self.superblock.sync(&mut self.disk)?;
-        self.root.sync(&mut self.disk)?;
-        Ok(())
+    #[test]
+    fn read_dir_works() {
+        const DISK_PATH: &str = "read-dir-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.write_file("a.txt", b"a").unwrap();
+        tfs.write_file("b.txt", b"bb").unwrap();
+        let mut read_dir = tfs.read_dir();
+        assert_eq!(read_dir.size_hint(), (2, Some(2)));
+        let a = read_dir.next().unwrap();
+        // the filesystem isn't held in between, so it can change partway through
+        tfs.write_file("c.txt", b"ccc").unwrap();
+        let b = read_dir.next().unwrap();
+        assert_eq!(read_dir.next().unwrap().filename, "c.txt");
+        assert!(read_dir.next().is_none());
+        assert_eq!((a.filename.as_str(), a.stat.size), ("a.txt", 1));
+        assert_eq!((b.filename.as_str(), b.stat.size), ("b.txt", 2));
+        assert_eq!((a.inode_block, a.blocks), (2, 1));
+        assert_eq!((b.inode_block, b.blocks), (4, 1));
+
+        // entries are owned so they outlive later changes
+        tfs.remove("a.txt").unwrap();
+        assert_eq!(a.filename, "a.txt");
+        assert_eq!(tfs.readdir().len(), 2);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
     }
-}
 
-impl Drop for TfsFs {
-    fn drop(&mut self) {
-        // nothing can be done if sync fails in drop
-        self.sync().unwrap()
+    #[test]
+    fn read_dir_blocks_work() {
+        const DISK_PATH: &str = "read-dir-blocks-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        tfs.touch("empty").unwrap();
+        tfs.write_file("big", &[0x42; BLOCK_SIZE * 3 + 1]).unwrap();
+        let entries = tfs.readdir();
+        drop(tfs);
+
+        // the same after a remount, since they come straight from the inodes
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let remounted = tfs.readdir();
+        for entries in [&entries, &remounted] {
+            let [empty, big] = &entries[..] else {
+                panic!("{entries:?}");
+            };
+            assert_eq!(empty.blocks, 0);
+            assert_eq!(big.blocks, 4);
+            assert_ne!(empty.inode_block, big.inode_block);
+        }
+        assert_eq!(entries[1].inode_block, remounted[1].inode_block);
+        // and they're the blocks the file owns, so removing it gives them and the inode back
+        let free = tfs.statfs().unwrap().free_blocks;
+        tfs.remove("big").unwrap();
+        assert_eq!(
+            tfs.statfs().unwrap().free_blocks,
+            free + remounted[1].blocks + 1
+        );
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fs;
+    #[test]
+    fn mkdir_works() {
+        const DISK_PATH: &str = "mkdir-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.mkdir("docs").unwrap();
+            tfs.mkdir("docs/old").unwrap();
+            tfs.write_file("docs/a.txt", b"a").unwrap();
+            tfs.write_file("docs/old/b.txt", b"bb").unwrap();
+            tfs.write_file("top.txt", b"top").unwrap();
+            assert!(matches!(tfs.mkdir("docs"), Err(TfsError::AlreadyExists(_))));
+            assert!(matches!(
+                tfs.mkdir("nope/dir"),
+                Err(TfsError::FileNotFound(_))
+            ));
+            assert!(matches!(tfs.open("docs"), Err(TfsError::IsADirectory(_))));
+            assert!(matches!(
+                tfs.open("top.txt/a"),
+                Err(TfsError::NotADirectory(_))
+            ));
+        }
+        {
+            // the tree survives a remount
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let root: Vec<_> = tfs.readdir().into_iter().map(|e| e.filename).collect();
+            assert_eq!(root, ["docs", "top.txt"]);
+            let docs = tfs.readdir_at("docs").unwrap();
+            assert_eq!(docs.len(), 2);
+            assert_eq!(
+                (docs[0].filename.as_str(), docs[0].kind),
+                ("old", FileKind::Directory)
+            );
+            assert_eq!(
+                (docs[1].filename.as_str(), docs[1].kind),
+                ("a.txt", FileKind::Regular)
+            );
+            assert_eq!(tfs.read("docs/old/b.txt").unwrap(), b"bb");
+            assert_eq!(tfs.read("/docs/a.txt").unwrap(), b"a");
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
 
-    use super::*;
+    #[test]
+    fn rmdir_works() {
+        const DISK_PATH: &str = "rmdir-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let tfs = Tfs::mount(DISK_PATH).unwrap();
+        let free = tfs.statfs().unwrap().free_blocks;
+        tfs.mkdir("dir").unwrap();
+        tfs.write_file("dir/file", b"data").unwrap();
+        tfs.write_file("file", b"data").unwrap();
+        assert!(matches!(
+            tfs.rmdir("dir"),
+            Err(TfsError::DirectoryNotEmpty(_))
+        ));
+        assert!(matches!(tfs.rmdir("file"), Err(TfsError::NotADirectory(_))));
+        assert!(matches!(tfs.remove("dir"), Err(TfsError::IsADirectory(_))));
+        tfs.remove("dir/file").unwrap();
+        tfs.remove("file").unwrap();
+        tfs.rmdir("dir").unwrap();
+        assert!(!tfs.exists("dir"));
+        assert_eq!(tfs.statfs().unwrap().free_blocks, free);
+        drop(tfs);
+        fs::remove_file(DISK_PATH).unwrap();
+    }
 
     #[test]
-    fn mkfs_works() {
-        const DISK_PATH: &str = "mkfs-disk.bin";
-        TfsFs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE).unwrap();
-        let mut disk: Disk<BLOCK_SIZE> = Disk::open(DISK_PATH, DEFAULT_DISK_SIZE).unwrap();
-        let superblock = disk.read_block(0).unwrap();
-        let superblock: SuperBlockData = bincode::deserialize(&superblock).unwrap();
-        assert_eq!(superblock.magic_number, 0x5A);
-        assert_eq!(superblock.root_inode, 1);
+    fn rename_into_dir_works() {
+        const DISK_PATH: &str = "rename-dir-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            tfs.mkdir("a").unwrap();
+            tfs.mkdir("a/b").unwrap();
+            tfs.write_file("file", b"moved").unwrap();
+            tfs.rename("file", "a/b/moved").unwrap();
+            assert!(matches!(
+                tfs.rename("a", "a/b/a"),
+                Err(TfsError::MoveIntoSelf(_))
+            ));
+            assert!(!tfs.exists("file"));
+            assert_eq!(tfs.readdir().len(), 1);
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            assert_eq!(tfs.read("a/b/moved").unwrap(), b"moved");
+            tfs.rename("a/b", "b").unwrap();
+            assert_eq!(tfs.read("b/moved").unwrap(), b"moved");
+            assert!(tfs.readdir_at("a").unwrap().is_empty());
+        }
         fs::remove_file(DISK_PATH).unwrap();
     }
 
     #[test]
-    fn mount_works() {
-        const DISK_PATH: &str = "mount-disk.bin";
-        TfsFs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE).unwrap();
-        let _tfs = TfsFs::mount(DISK_PATH).unwrap();
+    fn mount_rejects_other_versions() {
+        const DISK_PATH: &str = "version-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let mut disk = Disk::<BLOCK_SIZE>::open_existing(DISK_PATH).unwrap();
+        let mut superblock = disk.read_block(0).unwrap();
+        superblock[1..3].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        disk.write_block(0, superblock).unwrap();
+        drop(disk);
+        assert!(matches!(
+            Tfs::mount(DISK_PATH),
+            Err(TfsError::UnsupportedVersion { found, supported })
+                if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+        ));
         fs::remove_file(DISK_PATH).unwrap();
     }
 
     #[test]
-    fn open_works() {
-        const DISK_PATH: &str = "open-disk.bin";
-        TfsFs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE).unwrap();
+    fn io_write_works() {
+        const DISK_PATH: &str = "io-write-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        let harry = include_bytes!("../harry-sm.jpg");
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut file = tfs.open("cat.jpg").unwrap();
+            let copied = io::copy(&mut &harry[..], &mut file).unwrap();
+            assert_eq!(copied as usize, harry.len());
+            io::Write::flush(&mut file).unwrap();
+        }
+        {
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut file = tfs.open("cat.jpg").unwrap();
+            let mut cat = vec![0; harry.len() + 1];
+            assert_eq!(file.read(&mut cat).unwrap(), harry.len());
+            assert_eq!(&cat[..harry.len()], harry);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn io_write_partial_works() {
+        const DISK_PATH: &str = "io-write-partial-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
         let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
-        let _desc = tfs.open("test.txt").unwrap();
+        let mut desc = tfs.open("test.txt").unwrap();
+        // make sure there's only space for 2 blocks left
+        while tfs.superblock.allocate_block().is_some() {}
+        tfs.superblock.mark_free(20);
+        tfs.superblock.mark_free(21);
+        let data = [0x42; BLOCK_SIZE * 3];
+        assert_eq!(tfs.write(&mut desc, &data).unwrap(), BLOCK_SIZE * 2);
+        assert!(matches!(
+            tfs.write(&mut desc, &data),
+            Err(TfsError::OutOfSpace)
+        ));
+        assert_eq!(
+            tfs.root.inodes[desc.inode].stat.size as usize,
+            BLOCK_SIZE * 2
+        );
         fs::remove_file(DISK_PATH).unwrap();
     }
 
     #[test]
-    fn write_works() {
-        const DISK_PATH: &str = "write-disk.bin";
-        TfsFs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE).unwrap();
+    fn seek_works() {
+        const DISK_PATH: &str = "seek-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let harry = include_bytes!("../harry-sm.jpg");
+            let tfs = Tfs::mount(DISK_PATH).unwrap();
+            let mut file = tfs.open("cat.jpg").unwrap();
+            file.write(harry).unwrap();
+
+            let middle = harry.len() / 2;
+            let mut buf = [0; 300];
+            assert_eq!(
+                io::Seek::seek(&mut file, SeekFrom::Start(middle as u64)).unwrap(),
+                middle as u64
+            );
+            file.read(&mut buf).unwrap();
+            assert_eq!(buf, harry[middle..middle + 300]);
+
+            io::Seek::seek(&mut file, SeekFrom::Current(-600)).unwrap();
+            file.read(&mut buf).unwrap();
+            assert_eq!(buf, harry[middle - 300..middle]);
+
+            io::Seek::seek(&mut file, SeekFrom::End(-300)).unwrap();
+            file.read(&mut buf).unwrap();
+            assert_eq!(buf, harry[harry.len() - 300..]);
+
+            // past the end is remembered but reads nothing
+            io::Seek::seek(&mut file, SeekFrom::End(10)).unwrap();
+            assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+            assert_eq!(
+                io::Seek::seek(&mut file, SeekFrom::Current(-(harry.len() as i64) - 11))
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+            assert_eq!(
+                io::Seek::stream_position(&mut file).unwrap(),
+                harry.len() as u64 + 10
+            );
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn remove_works() {
+        const DISK_PATH: &str = "remove-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
         {
             let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
             let mut desc = tfs.open("test.txt").unwrap();
-            tfs.write(&mut desc, &"Hello, World!".as_bytes()).unwrap();
-            let harry = include_bytes!("../harry-sm.jpg");
-            let mut desc2 = tfs.open("cat.jpg").unwrap();
-            tfs.write(&mut desc2, harry).unwrap();
+            tfs.write(&mut desc, b"Hello, World!").unwrap();
+            assert!(matches!(tfs.remove("test.txt"), Err(TfsError::FileBusy(_))));
+            tfs.close(&mut desc).unwrap();
+            tfs.remove("test.txt").unwrap();
+            assert!(matches!(
+                tfs.remove("test.txt"),
+                Err(TfsError::FileNotFound(_))
+            ));
         }
         {
             let tfs = TfsFs::mount(DISK_PATH).unwrap();
-            assert_eq!(tfs.root.inodes.len(), 2);
+            assert_eq!(tfs.readdir().count(), 0);
+        }
+        fs::remove_file(DISK_PATH).unwrap();
+    }
+
+    #[test]
+    fn remove_recycles_blocks() {
+        const DISK_PATH: &str = "remove-recycle-disk.bin";
+        mkfs_fresh(DISK_PATH, DEFAULT_DISK_SIZE);
+        {
+            let mut tfs = TfsFs::mount(DISK_PATH).unwrap();
+            // each round uses an inode and four data blocks, so without recycling this would need
+            // far more blocks than the disk has
+            let data = [0x42; BLOCK_SIZE * 4];
+            for _ in 0..DEFAULT_DISK_SIZE / BLOCK_SIZE {
+                let mut desc = tfs.open("test.txt").unwrap();
+                tfs.write(&mut desc, &data).unwrap();
+                tfs.close(&mut desc).unwrap();
+                tfs.remove("test.txt").unwrap();
+            }
+            let mut desc = tfs.open("test.txt").unwrap();
+            tfs.write(&mut desc, &data).unwrap();
         }
+        assert_eq!(
+            fs::metadata(DISK_PATH).unwrap().len(),
+            DEFAULT_DISK_SIZE as u64
+        );
         fs::remove_file(DISK_PATH).unwrap();
     }
 }
@@ -1,12 +1,10 @@
-use std::{thread, time::Duration};
-
 use ansi_colours::ColourExt;
 use ansi_term::Color;
 use anyhow::Result;
 use chrono::{DateTime, Local};
 use image::{imageops, Pixel, Rgb, RgbImage};
 use supports_color::{ColorLevel, Stream};
-use tinyfs_rs::{Tfs, DEFAULT_DISK_SIZE};
+use tinyfs_rs::{FileKind, MkfsOptions, Tfs, DEFAULT_DISK_SIZE};
 
 fn to_ascii(image: &RgbImage, color_support: Option<ColorLevel>) -> String {
     let (width, height) = image.dimensions();
@@ -57,15 +55,40 @@ fn intensity_to_ascii(intensity: u8) -> char {
     ascii_chars[scaled_intensity]
 }
 
+// the usual ls -l rendering, e.g. drwxr-xr-x
+fn mode_string(kind: FileKind, mode: u16) -> String {
+    let mut string = String::from(match kind {
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::Regular => '-',
+    });
+    for shift in [6, 3, 0] {
+        let bits = mode >> shift;
+        string.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        string.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    string
+}
+
 fn ls(tfs: &Tfs) -> Result<()> {
     println!("listing files...");
     for f in tfs.readdir() {
         println!(
-            " - {} created: {} modified: {} accessed: {}",
+            " {} {}{} inode: {} blocks: {} created: {} changed: {} modified: {} accessed: {}",
+            mode_string(f.kind, f.stat.mode),
             f.filename,
-            DateTime::<Local>::from(f.stat.ctime).format("%H:%M:%S"),
-            DateTime::<Local>::from(f.stat.mtime).format("%H:%M:%S"),
-            DateTime::<Local>::from(f.stat.atime).format("%H:%M:%S"),
+            if f.kind == FileKind::Directory {
+                "/"
+            } else {
+                ""
+            },
+            f.inode_block,
+            f.blocks,
+            DateTime::<Local>::from(f.stat.btime).format("%H:%M:%S%.3f"),
+            DateTime::<Local>::from(f.stat.ctime).format("%H:%M:%S%.3f"),
+            DateTime::<Local>::from(f.stat.mtime).format("%H:%M:%S%.3f"),
+            DateTime::<Local>::from(f.stat.atime).format("%H:%M:%S%.3f"),
         );
     }
     Ok(())
@@ -75,46 +98,39 @@ fn main() -> Result<()> {
     const DISK_PATH: &str = "demo.disk";
     {
         println!("making filesystem...");
-        Tfs::mkfs(DISK_PATH, DEFAULT_DISK_SIZE)?;
+        // a fresh image every run, whatever the last one left behind
+        MkfsOptions::new()
+            .size(DEFAULT_DISK_SIZE)
+            .force(true)
+            .mkfs(DISK_PATH)?;
         println!("mouting filesystem...");
-        let mut tfs = Tfs::mount(DISK_PATH)?;
+        let tfs = Tfs::mount(DISK_PATH)?;
         println!("creating test.txt - a file containing \"Hello, World!\"");
-        let mut file1 = tfs.open("test.txt")?;
-        file1.write(b"Hello, World!")?;
+        tfs.write_file("test.txt", b"Hello, World!")?;
         println!("creating cat.jpg - a file containing a picture of a cat");
         let harry = include_bytes!("../harry-sm.jpg");
-        let mut file2 = tfs.open("cat.jpg")?;
-        file2.write(harry)?;
+        tfs.write_file("cat.jpg", harry)?;
         println!("unmounting filesystem...");
     }
-    println!("sleeping so timestamps can change...");
-    thread::sleep(Duration::from_secs_f32(1.5));
     {
         println!("mouting filesystem...");
-        let mut tfs = Tfs::mount(DISK_PATH)?;
+        let tfs = Tfs::mount(DISK_PATH)?;
 
         ls(&tfs)?;
 
         println!("rename cat.jpg");
-        let mut hary = tfs.open("cat.jpg")?;
-        hary.rename("hary.jpg")?;
+        tfs.open("cat.jpg")?.rename("hary.jpg")?;
 
         ls(&tfs)?;
 
         println!("reading test.txt");
-        let mut test = tfs.open("test.txt")?;
         let mut hello = String::new();
-        while let Some(byte) = test.read_byte()? {
-            hello.push(byte as char);
-        }
+        tfs.open_existing("test.txt")?.read_to_string(&mut hello)?;
         println!("contents: \"{}\"", hello);
 
         println!("reading hary.jpg");
-        let mut hary = tfs.open("hary.jpg")?;
         let mut cat = Vec::new();
-        while let Some(byte) = hary.read_byte()? {
-            cat.push(byte);
-        }
+        tfs.open_existing("hary.jpg")?.read_to_end(&mut cat)?;
 
         println!("printing hary.jpg");
         let img = image::load_from_memory(&cat)?;
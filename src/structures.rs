@@ -1,19 +1,105 @@
-use std::{ffi::CString, mem, time::UNIX_EPOCH};
+use std::{
+    ffi::CString,
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
-use crate::{INode, Root, Stat, SuperBlock, TfsError, TfsResult, BLOCK_SIZE, DEFAULT_DISK_SIZE};
+use crate::{
+    DirEntry, FileKind, INode, Stat, SuperBlock, TfsError, TfsResult, BLOCK_SIZE, DEFAULT_DISK_SIZE,
+};
 
-pub const ALLOCATION_TABLE_LEN: usize = BLOCK_SIZE - mem::size_of::<u8>() - mem::size_of::<u16>();
-const MAX_BLOCKS: usize = (ALLOCATION_TABLE_LEN) * 8;
+pub const MAGIC_NUMBER: u8 = 0x5A;
+// bump whenever the on disk layout changes
+pub const FORMAT_VERSION: u16 = 24;
+// optional parts of the format, a reader has to refuse images with features it doesn't know
+pub const FEATURE_CHECKSUMS: u16 = 1;
+pub const FEATURE_JOURNAL: u16 = 2;
+pub const FEATURE_REFLINKS: u16 = 4;
+pub const SUPPORTED_FEATURES: u16 = FEATURE_CHECKSUMS | FEATURE_JOURNAL | FEATURE_REFLINKS;
+// set while mounted, so a mount that finds it already dirty knows the last one didn't finish
+pub const STATE_CLEAN: u8 = 0;
+pub const STATE_DIRTY: u8 = 1;
+
+pub const LABEL_LEN: usize = 16;
+pub const UUID_LEN: usize = 16;
+pub const ALLOCATION_TABLE_LEN: usize = BLOCK_SIZE
+    - mem::size_of::<u8>()
+    - mem::size_of::<u16>()
+    - mem::size_of::<u8>()
+    - mem::size_of::<[u8; UUID_LEN]>()
+    - mem::size_of::<[u8; LABEL_LEN]>()
+    - mem::size_of::<u16>()
+    - mem::size_of::<u16>()
+    - mem::size_of::<u16>() * 8
+    - mem::size_of::<u32>();
+pub const MAX_BLOCKS: usize = (ALLOCATION_TABLE_LEN) * 8;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SuperBlockData {
     pub magic_number: u8,
+    pub version: u16,
+    pub features: u16,
+    pub state: u8,
+    pub uuid: [u8; UUID_LEN],
+    pub label: [u8; LABEL_LEN],
     pub root_inode: u16,
+    // where the copy of this block lives, 0 if there isn't one
+    pub backup_block: u16,
+    // the table of per block checksums, if FEATURE_CHECKSUMS is set
+    pub checksum_start: u16,
+    pub checksum_blocks: u16,
+    // the metadata journal, if FEATURE_JOURNAL is set
+    pub journal_start: u16,
+    pub journal_blocks: u16,
+    // a byte per block counting the references to it past the first, if FEATURE_REFLINKS is set
+    pub refcount_start: u16,
+    pub refcount_blocks: u16,
+    // how big the filesystem was made, nothing past this is ever allocated whatever the size of the
+    // image it's in
+    pub total_blocks: u16,
     #[serde(with = "BigArray")]
     pub allocated_blocks: [u8; ALLOCATION_TABLE_LEN],
+    // crc32 of everything before it, so it has to stay last
+    pub checksum: u32,
+}
+
+// every block goes through these, so the encoding is spelled out here rather than left to
+// bincode's defaults: integers are fixed width little endian, structs and arrays are their fields
+// back to back with no lengths or padding in between, and a block can have unused space after the
+// struct in it
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+pub fn encode<T: Serialize>(value: &T) -> bincode::Result<Vec<u8>> {
+    options().serialize(value)
+}
+
+pub fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
+    options().deserialize(bytes)
+}
+
+// plain bitwise crc32 (ieee), it only ever runs over a block at a time
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
 }
 
 impl SuperBlockData {
@@ -22,80 +108,193 @@ impl SuperBlockData {
     }
 
     pub fn new_with_size(root_inode: u16, size: usize) -> TfsResult<Self> {
-        // subtract size of magic number and root inode
+        // the allocation table has a bit for every block
         let blocks = size / BLOCK_SIZE;
-        let allocated_needed = blocks / 8 + if blocks % 8 != 0 { 1 } else { 0 };
-        if allocated_needed > MAX_BLOCKS {
+        if blocks > MAX_BLOCKS {
             return Err(TfsError::SizeError { size });
         }
         Ok(Self {
-            magic_number: 0x5A,
+            magic_number: MAGIC_NUMBER,
+            version: FORMAT_VERSION,
+            features: 0,
+            state: STATE_CLEAN,
+            uuid: [0; UUID_LEN],
+            label: [0; LABEL_LEN],
             root_inode,
+            backup_block: 0,
+            checksum_start: 0,
+            checksum_blocks: 0,
+            journal_start: 0,
+            journal_blocks: 0,
+            refcount_start: 0,
+            refcount_blocks: 0,
+            total_blocks: blocks as u16,
             allocated_blocks: [0; ALLOCATION_TABLE_LEN],
+            checksum: 0,
         })
     }
+
+    pub fn compute_checksum(&self) -> TfsResult<u32> {
+        let block = encode(self)?;
+        Ok(crc32(&block[..block.len() - mem::size_of::<u32>()]))
+    }
 }
 
 impl From<SuperBlock> for SuperBlockData {
     fn from(
         SuperBlock {
-            allocated_blocks, ..
+            clean,
+            uuid,
+            label,
+            backup_block,
+            checksum_start,
+            checksum_blocks,
+            journal_start,
+            journal_blocks,
+            refcount_start,
+            refcount_blocks,
+            total_blocks,
+            allocated_blocks,
+            ..
         }: SuperBlock,
     ) -> Self {
+        let mut label = CString::new(label).unwrap().into_bytes();
+        label.resize(LABEL_LEN, 0);
+        let features = [
+            (checksum_blocks, FEATURE_CHECKSUMS),
+            (journal_blocks, FEATURE_JOURNAL),
+            (refcount_blocks, FEATURE_REFLINKS),
+        ]
+        .into_iter()
+        .filter(|&(blocks, _)| blocks != 0)
+        .fold(0, |features, (_, feature)| features | feature);
         Self {
-            magic_number: 0x5A,
+            magic_number: MAGIC_NUMBER,
+            version: FORMAT_VERSION,
+            features,
+            state: if clean { STATE_CLEAN } else { STATE_DIRTY },
+            uuid,
+            label: label.try_into().unwrap(),
             root_inode: 1,
+            backup_block,
+            checksum_start,
+            checksum_blocks,
+            journal_start,
+            journal_blocks,
+            refcount_start,
+            refcount_blocks,
+            total_blocks,
             allocated_blocks,
+            checksum: 0,
+        }
+    }
+}
+
+pub const MAX_FILENAME_LEN: usize = 64;
+
+// a name in a directory and the inode block it's for, an inode of 0 is an empty slot
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntryData {
+    #[serde(with = "BigArray")]
+    pub filename: [u8; MAX_FILENAME_LEN],
+    pub inode: u16,
+}
+
+impl EntryData {
+    pub fn new() -> Self {
+        Self {
+            filename: [0; MAX_FILENAME_LEN],
+            inode: 0,
         }
     }
 }
 
-const ROOT_INODES: usize = BLOCK_SIZE / mem::size_of::<u16>();
+impl From<&DirEntry> for EntryData {
+    fn from(DirEntry { filename, inode }: &DirEntry) -> Self {
+        let mut filename = CString::new(filename.as_str()).unwrap().into_bytes();
+        filename.resize(MAX_FILENAME_LEN, 0);
+        Self {
+            filename: filename.try_into().unwrap(),
+            inode: *inode,
+        }
+    }
+}
+
+const ENTRY_LEN: usize = mem::size_of::<[u8; MAX_FILENAME_LEN]>() + mem::size_of::<u16>();
+// every directory block is one of these, the root's included. only the root chains its blocks
+// together, the last two bytes point at the next block of entries if there is one
+pub const ROOT_ENTRIES: usize = (BLOCK_SIZE - mem::size_of::<u16>()) / ENTRY_LEN;
+const ROOT_PADDING: usize = BLOCK_SIZE - mem::size_of::<u16>() - ROOT_ENTRIES * ENTRY_LEN;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RootData {
+    pub entries: [EntryData; ROOT_ENTRIES],
     #[serde(with = "BigArray")]
-    pub inodes: [u16; ROOT_INODES],
+    pub padding: [u8; ROOT_PADDING],
+    pub next: u16,
 }
 
 impl RootData {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            inodes: [0; ROOT_INODES],
+            entries: std::array::from_fn(|_| EntryData::new()),
+            padding: [0; ROOT_PADDING],
+            next: 0,
         }
     }
-}
 
-impl TryFrom<Root> for RootData {
-    type Error = TfsError;
-
-    fn try_from(Root { inodes, .. }: Root) -> Result<Self, Self::Error> {
-        let mut inodes: Vec<u16> = inodes.into_iter().map(|inode| inode.block).collect();
-        if inodes.len() > ROOT_INODES {
-            return Err(TfsError::SizeError { size: inodes.len() });
+    pub fn with_entries(entries: &[DirEntry], next: u16) -> TfsResult<Self> {
+        if entries.len() > ROOT_ENTRIES {
+            return Err(TfsError::SizeError {
+                size: entries.len(),
+            });
         }
-        inodes.resize(ROOT_INODES, 0);
         Ok(Self {
-            inodes: inodes.try_into().unwrap(),
+            entries: std::array::from_fn(|i| {
+                entries.get(i).map_or_else(EntryData::new, Into::into)
+            }),
+            padding: [0; ROOT_PADDING],
+            next,
         })
     }
 }
 
-const MAX_FILENAME_LEN: usize = 8;
+// bits of INodeData::flags
+pub const FLAG_IMMUTABLE: u8 = 1;
+pub const FLAG_APPEND_ONLY: u8 = 2;
+// set by the filesystem rather than through FileFlags, when there are blocks reserved past the end
+// of the file, see TfsFs::preallocate
+pub const FLAG_PREALLOCATED: u8 = 0x80;
+// each indirect slot points at a block full of further block pointers
+pub const INDIRECT_SLOTS: usize = 4;
+pub const INDIRECT_BLOCK_LEN: usize = BLOCK_SIZE / mem::size_of::<u16>();
 // can't use struct size for Statdata due to padding
-const INODE_BLOCKS: usize = (BLOCK_SIZE
-    - mem::size_of::<[u8; MAX_FILENAME_LEN]>()
-    - mem::size_of::<u16>()
-    - mem::size_of::<u32>() * 3)
-    / mem::size_of::<u16>();
+const INODE_HEADER_LEN: usize = mem::size_of::<u8>() * 2
+    + mem::size_of::<u32>()
+    + mem::size_of::<u64>() * 4
+    + mem::size_of::<u16>() * 4
+    + mem::size_of::<u32>()
+    + mem::size_of::<[u16; INDIRECT_SLOTS]>();
+pub const INODE_BLOCKS: usize = (BLOCK_SIZE - INODE_HEADER_LEN) / mem::size_of::<u16>();
+pub const MAX_FILE_BLOCKS: usize = INODE_BLOCKS + INDIRECT_SLOTS * INDIRECT_BLOCK_LEN;
+// directories only ever use the blocks in the inode itself
+pub const MAX_DIR_ENTRIES: usize = INODE_BLOCKS * ROOT_ENTRIES;
+// leftover bytes so that an inode always fills exactly one block
+const INODE_PADDING: usize = BLOCK_SIZE - INODE_HEADER_LEN - INODE_BLOCKS * mem::size_of::<u16>();
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatData {
-    pub size: u16,
-    pub ctime: u32,
-    pub mtime: u32,
-    pub atime: u32,
+    pub size: u32,
+    // nanoseconds since the epoch
+    pub ctime: u64,
+    pub mtime: u64,
+    pub atime: u64,
+    pub btime: u64,
+    pub nlink: u16,
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
 }
 
 impl StatData {
@@ -105,10 +304,21 @@ impl StatData {
             ctime: 0,
             mtime: 0,
             atime: 0,
+            btime: 0,
+            nlink: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
         }
     }
 }
 
+// nanoseconds since the epoch, if the time is after it and before the u64 runs out in 2554
+pub fn disk_time(time: SystemTime) -> Option<u64> {
+    let nanos = time.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    nanos.try_into().ok()
+}
+
 impl From<Stat> for StatData {
     fn from(
         Stat {
@@ -116,32 +326,53 @@ impl From<Stat> for StatData {
             ctime,
             mtime,
             atime,
+            btime,
+            nlink,
+            mode,
+            uid,
+            gid,
+            ..
         }: Stat,
     ) -> Self {
         Self {
-            size,
-            ctime: ctime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
-            mtime: mtime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
-            atime: atime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+            size: size as u32,
+            // set_times refuses anything out of range, so this only catches a clock set before 1970
+            ctime: disk_time(ctime).unwrap_or_default(),
+            mtime: disk_time(mtime).unwrap_or_default(),
+            atime: disk_time(atime).unwrap_or_default(),
+            btime: disk_time(btime).unwrap_or_default(),
+            nlink,
+            mode,
+            uid,
+            gid,
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct INodeData {
-    pub filename: [u8; MAX_FILENAME_LEN],
+    pub kind: u8,
+    pub flags: u8,
     pub stat: StatData,
+    // bumped every time an inode is created, whatever block it's in
+    pub generation: u32,
     #[serde(with = "BigArray")]
     pub blocks: [u16; INODE_BLOCKS],
+    pub indirect: [u16; INDIRECT_SLOTS],
+    pub padding: [u8; INODE_PADDING],
 }
 
 impl INodeData {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            filename: [0; MAX_FILENAME_LEN],
+            kind: FileKind::Regular as u8,
+            flags: 0,
             stat: StatData::new(),
+            generation: 0,
             blocks: [0; INODE_BLOCKS],
+            indirect: [0; INDIRECT_SLOTS],
+            padding: [0; INODE_PADDING],
         }
     }
 }
@@ -149,24 +380,99 @@ impl INodeData {
 impl From<INode> for INodeData {
     fn from(
         INode {
-            filename,
+            generation,
             stat,
+            preallocated,
             mut blocks,
+            mut indirect,
             ..
         }: INode,
     ) -> Self {
-        let filename = CString::new(filename).unwrap();
-        let mut filename = filename.into_bytes();
-        filename.resize(MAX_FILENAME_LEN, 0);
+        let mut flags = stat.flags.into();
+        if preallocated && blocks.len() > (stat.size as usize).div_ceil(BLOCK_SIZE) {
+            flags |= FLAG_PREALLOCATED;
+        }
+        // anything past the inline blocks lives in the indirect blocks
         blocks.resize(INODE_BLOCKS, 0);
+        indirect.resize(INDIRECT_SLOTS, 0);
         Self {
-            filename: filename.try_into().unwrap(),
+            kind: stat.kind as u8,
+            flags,
             stat: stat.into(),
+            generation,
             blocks: blocks.try_into().unwrap(),
+            indirect: indirect.try_into().unwrap(),
+            padding: [0; INODE_PADDING],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndirectData {
+    #[serde(with = "BigArray")]
+    pub blocks: [u16; INDIRECT_BLOCK_LEN],
+}
+
+impl IndirectData {
+    pub fn new() -> Self {
+        Self {
+            blocks: [0; INDIRECT_BLOCK_LEN],
         }
     }
 }
 
+// the original layout from before the superblock had a version, only kept around for upgrading
+// old images. the root inode pointer sits where the version is now, so these read as version 1
+pub mod v1 {
+    use std::mem;
+
+    use serde::{Deserialize, Serialize};
+    use serde_big_array::BigArray;
+
+    use crate::BLOCK_SIZE;
+
+    pub const VERSION: u16 = 1;
+    pub const ALLOCATION_TABLE_LEN: usize =
+        BLOCK_SIZE - mem::size_of::<u8>() - mem::size_of::<u16>();
+    pub const ROOT_INODES: usize = BLOCK_SIZE / mem::size_of::<u16>();
+    pub const MAX_FILENAME_LEN: usize = 8;
+    pub const INODE_BLOCKS: usize = (BLOCK_SIZE
+        - mem::size_of::<[u8; MAX_FILENAME_LEN]>()
+        - mem::size_of::<u16>()
+        - mem::size_of::<u32>() * 3)
+        / mem::size_of::<u16>();
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct SuperBlockData {
+        pub magic_number: u8,
+        pub root_inode: u16,
+        #[serde(with = "BigArray")]
+        pub allocated_blocks: [u8; ALLOCATION_TABLE_LEN],
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RootData {
+        #[serde(with = "BigArray")]
+        pub inodes: [u16; ROOT_INODES],
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct StatData {
+        pub size: u16,
+        pub ctime: u32,
+        pub mtime: u32,
+        pub atime: u32,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct INodeData {
+        pub filename: [u8; MAX_FILENAME_LEN],
+        pub stat: StatData,
+        #[serde(with = "BigArray")]
+        pub blocks: [u16; INODE_BLOCKS],
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,21 +480,171 @@ mod tests {
     #[test]
     fn superblock_correct_size() {
         let super_block = SuperBlockData::new(1).unwrap();
-        let encoded = bincode::serialize(&super_block).unwrap();
+        let encoded = encode(&super_block).unwrap();
         assert_eq!(encoded.len(), BLOCK_SIZE);
     }
 
+    #[test]
+    fn superblock_size_limit_works() {
+        let super_block = SuperBlockData::new_with_size(1, MAX_BLOCKS * BLOCK_SIZE).unwrap();
+        assert_eq!(super_block.total_blocks as usize, MAX_BLOCKS);
+        assert!(matches!(
+            SuperBlockData::new_with_size(1, (MAX_BLOCKS + 1) * BLOCK_SIZE),
+            Err(TfsError::SizeError { .. })
+        ));
+    }
+
+    // every field set to something different, so a field moving or changing width shows up
+    fn golden_superblock() -> SuperBlockData {
+        let mut super_block = SuperBlockData::new_with_size(1, DEFAULT_DISK_SIZE).unwrap();
+        super_block.features = FEATURE_CHECKSUMS | FEATURE_JOURNAL;
+        super_block.state = STATE_DIRTY;
+        super_block.uuid = std::array::from_fn(|i| i as u8 + 1);
+        super_block.label[..6].copy_from_slice(b"golden");
+        super_block.backup_block = 0x0027;
+        super_block.checksum_start = 0x0002;
+        super_block.checksum_blocks = 0x0001;
+        super_block.journal_start = 0x0003;
+        super_block.journal_blocks = 0x0004;
+        super_block.refcount_start = 0x0107;
+        super_block.refcount_blocks = 0x0208;
+        super_block.allocated_blocks[0] = 0xFF;
+        super_block.allocated_blocks[ALLOCATION_TABLE_LEN - 1] = 0x80;
+        super_block.checksum = 0x1234_5678;
+        super_block
+    }
+
+    fn golden_inode() -> INodeData {
+        let mut inode = INodeData::new();
+        inode.kind = FileKind::Directory as u8;
+        inode.flags = FLAG_APPEND_ONLY | FLAG_PREALLOCATED;
+        inode.stat = StatData {
+            size: 0x0102_0304,
+            ctime: 0x1112_1314_1516_1718,
+            mtime: 0x2122_2324_2526_2728,
+            atime: 0x3132_3334_3536_3738,
+            btime: 0x4142_4344_4546_4748,
+            nlink: 0x0506,
+            mode: 0o755,
+            uid: 0x0708,
+            gid: 0x090A,
+        };
+        inode.generation = 0x5152_5354;
+        inode.blocks[0] = 0x0B0C;
+        inode.blocks[INODE_BLOCKS - 1] = 0x0D0E;
+        inode.indirect = [0x1001, 0x1002, 0x1003, 0x1004];
+        inode
+    }
+
+    // anything that changes these bytes changes the format, so it needs a new FORMAT_VERSION and
+    // new fixtures
+    #[test]
+    fn superblock_encoding_is_fixed() {
+        let encoded = encode(&golden_superblock()).unwrap();
+        assert_eq!(encoded, include_bytes!("../fixtures/superblock.golden"));
+        // little endian, straight after the one byte magic number
+        assert_eq!(encoded[1..3], FORMAT_VERSION.to_le_bytes());
+        let decoded: SuperBlockData = decode(&encoded).unwrap();
+        assert_eq!(decoded.refcount_blocks, 0x0208);
+        assert_eq!(decoded.checksum, 0x1234_5678);
+    }
+
+    #[test]
+    fn root_encoding_is_fixed() {
+        let entries = [
+            ("golden", 0x0003),
+            ("", 0x0104),
+            (&"x".repeat(MAX_FILENAME_LEN), 0x0205),
+        ]
+        .map(|(filename, inode)| DirEntry {
+            filename: filename.to_string(),
+            inode,
+        });
+        let root = RootData::with_entries(&entries, 0x0306).unwrap();
+        let encoded = encode(&root).unwrap();
+        assert_eq!(encoded, include_bytes!("../fixtures/root.golden"));
+        // a name fills its field, nul terminated if it's short, then the inode block
+        assert_eq!(encoded[..7], *b"golden\0");
+        assert_eq!(encoded[MAX_FILENAME_LEN..ENTRY_LEN], [0x03, 0x00]);
+        assert_eq!(encoded[BLOCK_SIZE - 2..], [0x06, 0x03]);
+        let decoded: RootData = decode(&encoded).unwrap();
+        let inodes = decoded.entries.map(|entry| entry.inode);
+        assert_eq!(inodes, [0x0003, 0x0104, 0x0205]);
+    }
+
+    #[test]
+    fn inode_encoding_is_fixed() {
+        let encoded = encode(&golden_inode()).unwrap();
+        assert_eq!(encoded, include_bytes!("../fixtures/inode.golden"));
+        // the size comes straight after the kind and flags
+        assert_eq!(encoded[2..6], [0x04, 0x03, 0x02, 0x01]);
+        let decoded: INodeData = decode(&encoded).unwrap();
+        assert_eq!(decoded.stat.btime, 0x4142_4344_4546_4748);
+        assert_eq!(decoded.generation, 0x5152_5354);
+        assert_eq!(decoded.indirect, [0x1001, 0x1002, 0x1003, 0x1004]);
+    }
+
+    #[test]
+    fn crc32_works() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
     #[test]
     fn inode_correct_size() {
         let inode = INodeData::new();
-        let encoded = bincode::serialize(&inode).unwrap();
+        let encoded = encode(&inode).unwrap();
         assert_eq!(encoded.len(), BLOCK_SIZE);
     }
 
+    #[test]
+    fn inode_kind_round_trips() {
+        for kind in [FileKind::Regular, FileKind::Directory, FileKind::Symlink] {
+            let mut inode = INodeData::new();
+            inode.kind = kind as u8;
+            let encoded = encode(&inode).unwrap();
+            assert_eq!(encoded.len(), BLOCK_SIZE);
+            let decoded: INodeData = decode(&encoded).unwrap();
+            assert_eq!(FileKind::try_from(decoded.kind).unwrap(), kind);
+        }
+    }
+
     #[test]
     fn root_correct_size() {
         let inode = RootData::new();
-        let encoded = bincode::serialize(&inode).unwrap();
+        let encoded = encode(&inode).unwrap();
         assert_eq!(encoded.len(), BLOCK_SIZE);
     }
+
+    #[test]
+    fn indirect_correct_size() {
+        let indirect = IndirectData::new();
+        let encoded = encode(&indirect).unwrap();
+        assert_eq!(encoded.len(), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn v1_correct_size() {
+        let super_block = v1::SuperBlockData {
+            magic_number: MAGIC_NUMBER,
+            root_inode: 1,
+            allocated_blocks: [0; v1::ALLOCATION_TABLE_LEN],
+        };
+        assert_eq!(encode(&super_block).unwrap().len(), BLOCK_SIZE);
+        let root = v1::RootData {
+            inodes: [0; v1::ROOT_INODES],
+        };
+        assert_eq!(encode(&root).unwrap().len(), BLOCK_SIZE);
+        let inode = v1::INodeData {
+            filename: [0; v1::MAX_FILENAME_LEN],
+            stat: v1::StatData {
+                size: 0,
+                ctime: 0,
+                mtime: 0,
+                atime: 0,
+            },
+            blocks: [0; v1::INODE_BLOCKS],
+        };
+        assert_eq!(encode(&inode).unwrap().len(), BLOCK_SIZE);
+    }
 }
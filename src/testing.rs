@@ -0,0 +1,508 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{BlockDevice, DiskError, DiskResult, MemDisk, BLOCK_SIZE};
+
+// wraps another device and goes wrong on purpose, for seeing how the filesystem copes. the faults
+// are set through a Faults handle so they can be changed while it's mounted
+#[derive(Debug)]
+pub struct FaultyDisk<D> {
+    inner: D,
+    faults: Faults,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    // counted down on each read or write, failing the one that finds it at zero
+    fail_read: Option<usize>,
+    fail_write: Option<usize>,
+    bad_blocks: HashSet<usize>,
+    corrupt_blocks: HashSet<usize>,
+}
+
+impl<D> FaultyDisk<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl Faults {
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // the nth read from now fails, 0 being the very next one
+    pub fn fail_read(&self, n: usize) {
+        self.state().fail_read = Some(n);
+    }
+
+    pub fn fail_write(&self, n: usize) {
+        self.state().fail_write = Some(n);
+    }
+
+    // every write to block fails until cleared
+    pub fn fail_writes_to(&self, block: usize) {
+        self.state().bad_blocks.insert(block);
+    }
+
+    // reads of block come back with every bit flipped, but don't error
+    pub fn corrupt_reads_of(&self, block: usize) {
+        self.state().corrupt_blocks.insert(block);
+    }
+
+    pub fn clear(&self) {
+        *self.state() = FaultState::default();
+    }
+}
+
+// counts down, true if this is the one that should fail
+fn trip(countdown: &mut Option<usize>) -> bool {
+    match countdown {
+        Some(0) => {
+            *countdown = None;
+            true
+        }
+        Some(n) => {
+            *n -= 1;
+            false
+        }
+        None => false,
+    }
+}
+
+fn fault(what: &str, num: usize) -> DiskError {
+    io::Error::other(format!("injected fault {what} block {num}")).into()
+}
+
+impl<const BLOCK_SIZE: usize, D: BlockDevice<BLOCK_SIZE>> BlockDevice<BLOCK_SIZE>
+    for FaultyDisk<D>
+{
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let mut state = self.faults.state();
+        if trip(&mut state.fail_read) {
+            return Err(fault("reading", num));
+        }
+        self.inner.read_block(num, buf)?;
+        if state.corrupt_blocks.contains(&num) {
+            buf.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        let mut state = self.faults.state();
+        if trip(&mut state.fail_write) || state.bad_blocks.contains(&num) {
+            return Err(fault("writing", num));
+        }
+        drop(state);
+        self.inner.write_block(num, data)
+    }
+
+    fn block_count(&self) -> usize {
+        self.inner.block_count()
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        self.inner.sync()
+    }
+}
+
+// writes that seem to succeed but stop reaching the device after a cutoff, like a machine losing
+// power with a write cache full. every write is kept, so the image can be rebuilt as it would be
+// after a crash at any point
+#[derive(Debug)]
+pub struct CrashDisk<D> {
+    inner: D,
+    // what the device held before the first write
+    base: MemDisk,
+    writes: Vec<(usize, [u8; BLOCK_SIZE])>,
+    // only this many writes reach the device, the rest only live here
+    crash_after: Option<usize>,
+    lost: HashMap<usize, [u8; BLOCK_SIZE]>,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> CrashDisk<D> {
+    pub fn new(inner: D) -> DiskResult<Self> {
+        let mut base = MemDisk::new();
+        let mut block = [0; BLOCK_SIZE];
+        for num in 0..inner.block_count() {
+            inner.read_block(num, &mut block)?;
+            base.write_block(num, &block)?;
+        }
+        Ok(Self {
+            inner,
+            base,
+            writes: Vec::new(),
+            crash_after: None,
+            lost: HashMap::new(),
+        })
+    }
+
+    // counted from the start, not from now
+    pub fn crash_after(&mut self, writes: usize) {
+        self.crash_after = Some(writes);
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.len()
+    }
+
+    // the image as it would be if the machine died after the first n writes
+    pub fn snapshot_at(&self, n: usize) -> MemDisk {
+        let mut snapshot = self.base.clone();
+        for (num, data) in &self.writes[..n.min(self.writes.len())] {
+            // can't fail, it just grows
+            snapshot.write_block(*num, data).unwrap();
+        }
+        snapshot
+    }
+
+    // whatever actually made it to the device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> BlockDevice<BLOCK_SIZE> for CrashDisk<D> {
+    fn read_block(&self, num: usize, buf: &mut [u8; BLOCK_SIZE]) -> DiskResult<()> {
+        match self.lost.get(&num) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => self.inner.read_block(num, buf),
+        }
+    }
+
+    fn write_block(&mut self, num: usize, data: &[u8; BLOCK_SIZE]) -> DiskResult<()> {
+        self.writes.push((num, *data));
+        if self
+            .crash_after
+            .is_some_and(|crash_after| self.writes.len() > crash_after)
+        {
+            self.lost.insert(num, *data);
+            return Ok(());
+        }
+        self.inner.write_block(num, data)
+    }
+
+    fn block_count(&self) -> usize {
+        let lost = self.lost.keys().map(|num| num + 1).max().unwrap_or(0);
+        self.inner.block_count().max(lost)
+    }
+
+    fn sync(&mut self) -> DiskResult<()> {
+        self.inner.sync()
+    }
+}
+
+// tests keep their images at fixed paths, so one left behind by an earlier run that failed
+// partway mustn't stop the next from starting over
+#[cfg(test)]
+pub(crate) fn mkfs_fresh(path: &str, size: usize) {
+    crate::MkfsOptions::new()
+        .size(size)
+        .force(true)
+        .mkfs(path)
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::{disk::Disk, FsckFinding, MkfsOptions, MountOptions, Tfs, TfsError, TfsFs};
+
+    #[test]
+    fn faulty_disk_works() {
+        let disk = FaultyDisk::new(MemDisk::new());
+        let faults = disk.faults();
+        let mut disk: Disk<BLOCK_SIZE, _> = Disk::new(disk, false);
+        disk.set_block_count(8);
+        disk.write_block(3, [1; BLOCK_SIZE]).unwrap();
+        faults.fail_write(1);
+        disk.write_block(4, [2; BLOCK_SIZE]).unwrap();
+        assert!(disk.write_block(5, [3; BLOCK_SIZE]).is_err());
+        // only fails the once
+        disk.write_block(5, [3; BLOCK_SIZE]).unwrap();
+
+        faults.fail_writes_to(4);
+        assert!(disk.write_block(4, [4; BLOCK_SIZE]).is_err());
+        assert!(disk.write_block(4, [4; BLOCK_SIZE]).is_err());
+        faults.fail_read(0);
+        assert!(disk.read_block(3).is_err());
+        assert_eq!(disk.read_block(3).unwrap(), [1; BLOCK_SIZE]);
+        faults.corrupt_reads_of(3);
+        assert_eq!(disk.read_block(3).unwrap(), [!1; BLOCK_SIZE]);
+        faults.clear();
+        disk.write_block(4, [4; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.read_block(4).unwrap(), [4; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn failed_data_write_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        let mut file = tfs.open("file").unwrap();
+        faults.fail_write(0);
+        assert!(matches!(
+            tfs.write(&mut file, &[1; BLOCK_SIZE]),
+            Err(TfsError::DiskError(_))
+        ));
+        // and the filesystem carries on once the disk does
+        tfs.write(&mut file, &[1; BLOCK_SIZE]).unwrap();
+        tfs.close(&mut file).unwrap();
+        tfs.unmount().unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("file").unwrap(), [1; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn failed_truncate_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        let mut file = tfs.open("file").unwrap();
+        // all hole but the last block
+        tfs.seek(&mut file, io::SeekFrom::Start(BLOCK_SIZE as u64 * 3))
+            .unwrap();
+        tfs.write(&mut file, &[1; BLOCK_SIZE]).unwrap();
+        // zeroing the block that's to become the last one
+        faults.fail_write(0);
+        assert!(matches!(
+            tfs.truncate(&mut file, BLOCK_SIZE as u64 + 1),
+            Err(TfsError::DiskError(_))
+        ));
+        faults.clear();
+        assert_eq!(tfs.metadata("file").unwrap().size, BLOCK_SIZE as u64 * 4);
+        let mut contents = vec![0; BLOCK_SIZE * 3];
+        contents.extend([1; BLOCK_SIZE]);
+        assert_eq!(tfs.read_file("file").unwrap(), contents);
+        tfs.close(&mut file).unwrap();
+        tfs.unmount().unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("file").unwrap(), contents);
+    }
+
+    #[test]
+    fn failed_sync_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        let mut file = tfs.open("file").unwrap();
+        tfs.write(&mut file, b"contents").unwrap();
+        // the superblock is the first thing a sync writes
+        faults.fail_writes_to(0);
+        assert!(tfs.sync().is_err());
+        assert!(tfs.superblock.dirty);
+        faults.clear();
+        tfs.sync().unwrap();
+        assert!(!tfs.superblock.dirty);
+        tfs.close(&mut file).unwrap();
+        tfs.unmount().unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("file").unwrap(), b"contents");
+    }
+
+    #[test]
+    fn failed_journal_commit_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, MkfsOptions::new().journal_blocks(8)).unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        let mut file = tfs.open("file").unwrap();
+        tfs.write(&mut file, b"contents").unwrap();
+        // partway through writing the transaction into the journal
+        faults.fail_write(1);
+        assert!(tfs.sync().is_err());
+        assert!(tfs.superblock.dirty);
+        faults.clear();
+        tfs.sync().unwrap();
+        tfs.close(&mut file).unwrap();
+        tfs.unmount().unwrap();
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert_eq!(tfs.read("file").unwrap(), b"contents");
+    }
+
+    #[test]
+    fn failed_drop_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, &MkfsOptions::new()).unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let mut tfs = TfsFs::mount_on(device, &MountOptions::new()).unwrap();
+        tfs.begin_batch();
+        tfs.write_file("file", b"contents").unwrap();
+        faults.fail_write(0);
+        // unmounting in drop fails, which mustn't panic
+        drop(tfs);
+
+        let tfs = Tfs::mount_on(&mut disk, &MountOptions::new()).unwrap();
+        assert!(tfs.was_unclean());
+        drop(tfs);
+
+        // and the same for Tfs, whose filesystem unmounts when it goes
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let tfs = Tfs::mount_on(device, &MountOptions::new()).unwrap();
+        tfs.open("other").unwrap().write(&[1; 300]).unwrap();
+        faults.fail_write(0);
+        drop(tfs);
+
+        let tfs = Tfs::mount_mem(disk).unwrap();
+        assert!(tfs.was_unclean());
+        // closing the file had already synced it
+        assert_eq!(tfs.read("other").unwrap(), [1; 300]);
+    }
+
+    #[test]
+    fn corrupt_read_works() {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, MkfsOptions::new().checksums(true)).unwrap();
+        Tfs::mount_on(&mut disk, &MountOptions::new())
+            .unwrap()
+            .write_file("file", b"contents")
+            .unwrap();
+        let device = FaultyDisk::new(&mut disk);
+        let faults = device.faults();
+        let tfs = TfsFs::mount_on(device, MountOptions::new().read_only(true)).unwrap();
+        let inode = tfs.root.find("file").unwrap();
+        faults.corrupt_reads_of(tfs.root.inodes[inode].blocks[0] as usize);
+        assert!(matches!(
+            tfs.read_file("file"),
+            Err(TfsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn crash_disk_works() {
+        let mut disk = MemDisk::new();
+        disk.write_block(0, &[1; BLOCK_SIZE]).unwrap();
+        let mut crash = CrashDisk::new(disk).unwrap();
+        crash.crash_after(1);
+        crash.write_block(0, &[2; BLOCK_SIZE]).unwrap();
+        crash.write_block(1, &[3; BLOCK_SIZE]).unwrap();
+        // still there as far as anyone reading can tell
+        let mut block = [0; BLOCK_SIZE];
+        crash.read_block(1, &mut block).unwrap();
+        assert_eq!(block, [3; BLOCK_SIZE]);
+        assert_eq!(crash.writes(), 2);
+        assert_eq!(crash.snapshot_at(0).into_bytes(), [1; BLOCK_SIZE]);
+        assert_eq!(crash.snapshot_at(2).block_count(), 2);
+        assert_eq!(crash.into_inner().into_bytes(), [2; BLOCK_SIZE]);
+    }
+
+    // writes and syncs a few files, then crashes after every single write along the way
+    fn check_crash_points(options: &MkfsOptions, path: &str) {
+        let mut disk = MemDisk::new();
+        Tfs::mkfs_on(&mut disk, options).unwrap();
+        let mut crash = CrashDisk::new(disk).unwrap();
+        let small = b"small".to_vec();
+        let big: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+        {
+            let tfs = Tfs::mount_on(&mut crash, &MountOptions::new()).unwrap();
+            let mut file = tfs.open("small").unwrap();
+            file.write(&small).unwrap();
+            file.close().unwrap();
+            tfs.mkdir("dir").unwrap();
+            let mut file = tfs.open("dir/big").unwrap();
+            file.write(&big).unwrap();
+            file.close().unwrap();
+            tfs.sync().unwrap();
+            tfs.unmount().unwrap();
+        }
+        for n in 0..=crash.writes() {
+            let snapshot = crash.snapshot_at(n);
+            fs::write(path, snapshot.clone().into_bytes()).unwrap();
+            // a torn mount is fine as long as it fails cleanly
+            let Ok(tfs) = Tfs::mount_mem(snapshot) else {
+                continue;
+            };
+            for (filename, contents) in [("small", &small), ("dir/big", &big)] {
+                match tfs.read(filename) {
+                    Ok(data) => assert!(
+                        data.is_empty() || data == *contents,
+                        "{filename} is torn after {n} writes"
+                    ),
+                    Err(TfsError::FileNotFound(_)) => {}
+                    Err(err) => panic!("reading {filename} after {n} writes: {err}"),
+                }
+            }
+            drop(tfs);
+            // nothing may end up with a block it doesn't own
+            for finding in Tfs::check(path).unwrap().findings {
+                assert!(
+                    !matches!(
+                        finding,
+                        FsckFinding::BadBlock { .. }
+                            | FsckFinding::UnallocatedBlock { .. }
+                            | FsckFinding::CrossLinkedBlock { .. }
+                    ),
+                    "{finding:?} after {n} writes"
+                );
+            }
+        }
+        // with every write in, it's all there
+        let tfs = Tfs::mount_mem(crash.snapshot_at(crash.writes())).unwrap();
+        assert_eq!(tfs.read("small").unwrap(), small);
+        assert_eq!(tfs.read("dir/big").unwrap(), big);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn crash_points_work() {
+        check_crash_points(&MkfsOptions::new(), "crash-points-disk.bin");
+    }
+
+    #[test]
+    fn journaled_crash_points_work() {
+        check_crash_points(
+            MkfsOptions::new().journal_blocks(16),
+            "journaled-crash-points-disk.bin",
+        );
+    }
+
+    #[test]
+    fn checksummed_crash_points_work() {
+        check_crash_points(
+            MkfsOptions::new().checksums(true),
+            "checksummed-crash-points-disk.bin",
+        );
+    }
+}